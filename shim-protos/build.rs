@@ -1,4 +1,4 @@
 fn main() -> Result<(), Box<dyn std::error::Error>> {
-    tonic_build::compile_protos("proto/shim.proto")?;
+    tonic_build::configure().compile(&["proto/shim.proto", "proto/events.proto"], &["proto"])?;
     Ok(())
 }
@@ -1,3 +1,7 @@
 pub mod proto {
     tonic::include_proto!("shim.task");
 }
+
+pub mod events {
+    tonic::include_proto!("shim.events");
+}
@@ -0,0 +1,521 @@
+use std::{
+    collections::BTreeMap,
+    fmt, fs, io,
+    path::{Path, PathBuf},
+    str::FromStr,
+    thread,
+    time::Duration,
+};
+
+use anyhow::{bail, Context, Result};
+use clap::ValueEnum;
+use nix::libc;
+use tracing::{debug, warn};
+
+use crate::spec::{LinuxCpu, LinuxMemory, LinuxPids, LinuxResources};
+
+const CGROUP_ROOT: &str = "/sys/fs/cgroup";
+
+/// How often a transiently failed read of a cgroup file is retried, see [`retry_transient`].
+const READ_RETRIES: u32 = 2;
+
+/// What cgroup v1 reports for an unlimited memory limit (`PAGE_COUNTER_MAX` in bytes) with 4K
+/// pages. Larger pages report a smaller value, so anything above this is unlimited as well.
+const V1_UNLIMITED: i64 = 0x7FFF_FFFF_FFFF_F000;
+
+/// Resource usage read from a cgroup. Usage of controllers that aren't enabled is left unset.
+#[derive(Debug, Default)]
+pub struct Stats {
+    pub memory: Option<MemoryStats>,
+    pub cpu: Option<CpuStats>,
+    pub pids: Option<PidsStats>,
+}
+
+#[derive(Debug, Default)]
+pub struct MemoryStats {
+    /// Memory usage in bytes, including the page cache.
+    pub usage: u64,
+
+    /// Memory limit in bytes, -1 if unlimited.
+    pub limit: i64,
+}
+
+#[derive(Debug, Default)]
+pub struct CpuStats {
+    /// Total, user and system CPU time in microseconds.
+    pub usage_usec: u64,
+    pub user_usec: u64,
+    pub system_usec: u64,
+}
+
+#[derive(Debug, Default)]
+pub struct PidsStats {
+    pub current: u64,
+
+    /// The pids limit, -1 if unlimited.
+    pub limit: i64,
+}
+
+/// What to do when a container's cgroup can't be located.
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CgroupPolicy {
+    /// Log a warning and continue without cgroup based features.
+    Degrade,
+
+    /// Fail the operation.
+    Error,
+}
+
+/// How containers' cgroups are located and read.
+#[derive(Clone, Copy, Debug)]
+pub struct CgroupOptions {
+    pub policy: CgroupPolicy,
+
+    /// The backoff before retrying a transiently failed read, see [`Cgroup::read_file`].
+    pub read_backoff: Duration,
+}
+
+/// A container's cgroup. Reading it blocks, so async code does so on a blocking thread.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Cgroup {
+    hierarchy: Hierarchy,
+
+    read_backoff: Duration,
+}
+
+/// The location of a cgroup.
+#[derive(Clone, Debug, PartialEq, Eq)]
+enum Hierarchy {
+    /// The cgroup directory in the unified (v2) hierarchy.
+    V2(PathBuf),
+
+    /// The cgroup directories in the legacy (v1) hierarchies, keyed by controller.
+    V1(BTreeMap<String, PathBuf>),
+}
+
+impl Cgroup {
+    /// Locates the cgroup of a process from `/proc/<pid>/cgroup`.
+    pub fn for_pid(pid: i32, read_backoff: Duration) -> Result<Self> {
+        Ok(Self {
+            hierarchy: Hierarchy::for_pid(pid)?,
+            read_backoff,
+        })
+    }
+
+    /// Returns the directory of a controller. On v2 all controllers share one directory.
+    pub fn controller_path(&self, controller: &str) -> Option<&Path> {
+        match &self.hierarchy {
+            Hierarchy::V2(path) => Some(path),
+            Hierarchy::V1(controllers) => controllers.get(controller).map(PathBuf::as_path),
+        }
+    }
+
+    /// Reads the effective resource limits from the cgroup's limit files. Limits of controllers
+    /// that aren't enabled are left unset; unlimited values are reported as -1.
+    pub fn resources(&self) -> Result<LinuxResources> {
+        let resources = match &self.hierarchy {
+            Hierarchy::V2(path) => self.resources_v2(path)?,
+            Hierarchy::V1(_) => self.resources_v1()?,
+        };
+        Ok(LinuxResources {
+            memory: resources
+                .memory
+                .filter(|memory| *memory != LinuxMemory::default()),
+            cpu: resources.cpu.filter(|cpu| *cpu != LinuxCpu::default()),
+            pids: resources.pids,
+        })
+    }
+
+    /// Returns the pids of the processes in the cgroup. On v1 all hierarchies hold the same
+    /// processes, so any one of them is read.
+    pub fn procs(&self) -> Result<Vec<i32>> {
+        let dir = match &self.hierarchy {
+            Hierarchy::V2(path) => path,
+            Hierarchy::V1(controllers) => controllers
+                .values()
+                .next()
+                .context("Cgroup has no controllers")?,
+        };
+        let Some(procs) = self.read_file(dir, "cgroup.procs")? else {
+            bail!("Cgroup {:?} has no cgroup.procs", dir);
+        };
+        procs
+            .lines()
+            .map(|pid| {
+                pid.parse()
+                    .with_context(|| format!("Invalid pid {:?} in cgroup.procs", pid))
+            })
+            .collect()
+    }
+
+    /// Returns how many processes in the cgroup the OOM killer has killed, from `memory.events`
+    /// on v2 and `memory.oom_control` on v1. `None` without the memory controller, or on v1
+    /// kernels before 4.13 that don't count OOM kills.
+    pub fn oom_kills(&self) -> Result<Option<u64>> {
+        let (dir, name) = match &self.hierarchy {
+            Hierarchy::V2(path) => (path.as_path(), "memory.events"),
+            Hierarchy::V1(_) => match self.controller_path("memory") {
+                Some(dir) => (dir, "memory.oom_control"),
+                None => return Ok(None),
+            },
+        };
+        let Some(contents) = self.read_file(dir, name)? else {
+            return Ok(None);
+        };
+        contents
+            .lines()
+            .find_map(|line| line.strip_prefix("oom_kill "))
+            .map(|value| {
+                value
+                    .parse()
+                    .with_context(|| format!("Invalid oom_kill {:?} in {}", value, name))
+            })
+            .transpose()
+    }
+
+    /// Reads the cgroup's current resource usage.
+    pub fn stats(&self) -> Result<Stats> {
+        match &self.hierarchy {
+            Hierarchy::V2(path) => self.stats_v2(path),
+            Hierarchy::V1(_) => self.stats_v1(),
+        }
+    }
+
+    fn stats_v1(&self) -> Result<Stats> {
+        let mut stats = Stats::default();
+        if let Some(dir) = self.controller_path("memory") {
+            if let Some(usage) = self.read_value(dir, "memory.usage_in_bytes")? {
+                let limit = self
+                    .read_value::<i64>(dir, "memory.limit_in_bytes")?
+                    .filter(|limit| *limit < V1_UNLIMITED)
+                    .unwrap_or(-1);
+                stats.memory = Some(MemoryStats { usage, limit });
+            }
+        }
+        if let Some(dir) = self.controller_path("cpuacct") {
+            // cpuacct reports nanoseconds.
+            if let Some(usage) = self.read_value::<u64>(dir, "cpuacct.usage")? {
+                stats.cpu = Some(CpuStats {
+                    usage_usec: usage / 1000,
+                    user_usec: self
+                        .read_value::<u64>(dir, "cpuacct.usage_user")?
+                        .unwrap_or(0)
+                        / 1000,
+                    system_usec: self
+                        .read_value::<u64>(dir, "cpuacct.usage_sys")?
+                        .unwrap_or(0)
+                        / 1000,
+                });
+            }
+        }
+        if let Some(dir) = self.controller_path("pids") {
+            stats.pids = self.pids_stats(dir)?;
+        }
+        Ok(stats)
+    }
+
+    fn resources_v1(&self) -> Result<LinuxResources> {
+        let mut resources = LinuxResources::default();
+        if let Some(dir) = self.controller_path("memory") {
+            let limit = |name| {
+                Ok::<_, anyhow::Error>(self.read_value::<i64>(dir, name)?.map(|value| {
+                    if value >= V1_UNLIMITED {
+                        -1
+                    } else {
+                        value
+                    }
+                }))
+            };
+            resources.memory = Some(LinuxMemory {
+                limit: limit("memory.limit_in_bytes")?,
+                reservation: limit("memory.soft_limit_in_bytes")?,
+                swap: limit("memory.memsw.limit_in_bytes")?,
+            });
+        }
+        let mut cpu = LinuxCpu::default();
+        if let Some(dir) = self.controller_path("cpu") {
+            cpu.shares = self.read_value(dir, "cpu.shares")?;
+            cpu.quota = self.read_value(dir, "cpu.cfs_quota_us")?;
+            cpu.period = self.read_value(dir, "cpu.cfs_period_us")?;
+        }
+        if let Some(dir) = self.controller_path("cpuset") {
+            cpu.cpus = self
+                .read_file(dir, "cpuset.cpus")?
+                .filter(|cpus| !cpus.is_empty());
+            cpu.mems = self
+                .read_file(dir, "cpuset.mems")?
+                .filter(|mems| !mems.is_empty());
+        }
+        resources.cpu = Some(cpu);
+        if let Some(dir) = self.controller_path("pids") {
+            resources.pids = self
+                .read_limit(dir, "pids.max")?
+                .map(|limit| LinuxPids { limit });
+        }
+        Ok(resources)
+    }
+
+    fn resources_v2(&self, dir: &Path) -> Result<LinuxResources> {
+        let limit = self.read_limit(dir, "memory.max")?;
+        // OCI limits memory and swap together, v2 limits swap on its own.
+        let swap = self
+            .read_limit(dir, "memory.swap.max")?
+            .map(|swap| match limit {
+                Some(limit) if limit >= 0 && swap >= 0 => limit + swap,
+                _ => -1,
+            });
+        let memory = LinuxMemory {
+            limit,
+            reservation: self.read_limit(dir, "memory.low")?,
+            swap,
+        };
+        let mut cpu = LinuxCpu::default();
+        if let Some(max) = self.read_file(dir, "cpu.max")? {
+            let (quota, period) = max.split_once(' ').unwrap_or((&max, ""));
+            cpu.quota = Some(parse_limit(quota)?);
+            cpu.period = period.parse().ok();
+        }
+        // The inverse of the conversion runc applies when setting shares on v2.
+        cpu.shares = self
+            .read_value::<u64>(dir, "cpu.weight")?
+            .map(|weight| 2 + (weight.saturating_sub(1) * 262142) / 9999);
+        cpu.cpus = self
+            .read_file(dir, "cpuset.cpus")?
+            .filter(|cpus| !cpus.is_empty());
+        cpu.mems = self
+            .read_file(dir, "cpuset.mems")?
+            .filter(|mems| !mems.is_empty());
+        Ok(LinuxResources {
+            memory: Some(memory),
+            cpu: Some(cpu),
+            pids: self
+                .read_limit(dir, "pids.max")?
+                .map(|limit| LinuxPids { limit }),
+        })
+    }
+
+    fn stats_v2(&self, dir: &Path) -> Result<Stats> {
+        let memory = self
+            .read_value(dir, "memory.current")?
+            .map(|usage| {
+                Ok::<_, anyhow::Error>(MemoryStats {
+                    usage,
+                    limit: self.read_limit(dir, "memory.max")?.unwrap_or(-1),
+                })
+            })
+            .transpose()?;
+        // cpu.stat is always there, even without the cpu controller enabled.
+        let cpu = self.read_file(dir, "cpu.stat")?.map(|stat| {
+            let field = |name| {
+                stat.lines()
+                    .find_map(|line| line.strip_prefix(name)?.strip_prefix(' '))
+                    .and_then(|value| value.parse().ok())
+                    .unwrap_or(0)
+            };
+            CpuStats {
+                usage_usec: field("usage_usec"),
+                user_usec: field("user_usec"),
+                system_usec: field("system_usec"),
+            }
+        });
+        Ok(Stats {
+            memory,
+            cpu,
+            pids: self.pids_stats(dir)?,
+        })
+    }
+
+    fn pids_stats(&self, dir: &Path) -> Result<Option<PidsStats>> {
+        self.read_value(dir, "pids.current")?
+            .map(|current| {
+                Ok(PidsStats {
+                    current,
+                    limit: self.read_limit(dir, "pids.max")?.unwrap_or(-1),
+                })
+            })
+            .transpose()
+    }
+
+    /// Reads a cgroup file, returning `None` if it doesn't exist. Reads that fail transiently,
+    /// see [`retry_transient`], are retried.
+    fn read_file(&self, dir: &Path, name: &str) -> Result<Option<String>> {
+        let path = dir.join(name);
+        match retry_transient(self.read_backoff, || fs::read_to_string(&path)) {
+            Ok(contents) => Ok(Some(contents.trim().to_string())),
+            Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(err) => Err(err).with_context(|| format!("Failed to read {:?}", path)),
+        }
+    }
+
+    fn read_value<T>(&self, dir: &Path, name: &str) -> Result<Option<T>>
+    where
+        T: FromStr,
+        T::Err: std::error::Error + Send + Sync + 'static,
+    {
+        self.read_file(dir, name)?
+            .map(|value| {
+                value
+                    .parse()
+                    .with_context(|| format!("Invalid value {:?} in {}", value, name))
+            })
+            .transpose()
+    }
+
+    /// Reads a limit that may be `max`, which is reported as -1.
+    fn read_limit(&self, dir: &Path, name: &str) -> Result<Option<i64>> {
+        self.read_file(dir, name)?
+            .map(|value| parse_limit(&value).with_context(|| format!("Invalid value in {}", name)))
+            .transpose()
+    }
+}
+
+impl Hierarchy {
+    fn for_pid(pid: i32) -> Result<Self> {
+        let path = format!("/proc/{}/cgroup", pid);
+        let contents =
+            fs::read_to_string(&path).with_context(|| format!("Failed to read {}", path))?;
+        let root = Path::new(CGROUP_ROOT);
+        if root.join("cgroup.controllers").exists() {
+            let relative = contents
+                .lines()
+                .find_map(|line| line.strip_prefix("0::"))
+                .with_context(|| format!("No unified hierarchy entry in {}", path))?;
+            return Ok(Hierarchy::V2(join_cgroup(root, relative)?));
+        }
+        let mut controllers = BTreeMap::new();
+        for line in contents.lines() {
+            let mut fields = line.splitn(3, ':');
+            let (Some(_), Some(names), Some(relative)) =
+                (fields.next(), fields.next(), fields.next())
+            else {
+                continue;
+            };
+            if names.is_empty() || names.starts_with("name=") {
+                continue;
+            }
+            let dir = join_cgroup(&root.join(names), relative)?;
+            for name in names.split(',') {
+                controllers.insert(name.to_string(), dir.clone());
+            }
+        }
+        if controllers.is_empty() {
+            bail!("No cgroup controllers found in {}", path);
+        }
+        Ok(Hierarchy::V1(controllers))
+    }
+}
+
+/// Retries a read of a cgroup file that failed transiently, with EINTR or with ENODEV while the
+/// cgroup is being set up or torn down, up to [`READ_RETRIES`] times with a backoff growing
+/// linearly from `backoff`. Other errors are returned right away.
+fn retry_transient<T>(backoff: Duration, mut read: impl FnMut() -> io::Result<T>) -> io::Result<T> {
+    let mut attempt = 0;
+    loop {
+        match read() {
+            Err(err)
+                if attempt < READ_RETRIES
+                    && matches!(err.raw_os_error(), Some(libc::EINTR | libc::ENODEV)) =>
+            {
+                attempt += 1;
+                debug!("Retrying cgroup read after error: {}", err);
+                thread::sleep(backoff * attempt);
+            }
+            result => return result,
+        }
+    }
+}
+
+fn parse_limit(value: &str) -> Result<i64> {
+    if value == "max" {
+        return Ok(-1);
+    }
+    Ok(value.parse()?)
+}
+
+impl fmt::Display for Cgroup {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.hierarchy {
+            Hierarchy::V2(path) => write!(f, "{}", path.display()),
+            Hierarchy::V1(controllers) => {
+                let mut separator = "";
+                for (name, path) in controllers {
+                    write!(f, "{}{}={}", separator, name, path.display())?;
+                    separator = ",";
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+/// Locates the cgroup of a process, applying the policy if that fails. All cgroup based features
+/// go through this so that they degrade consistently.
+pub fn resolve(pid: i32, options: CgroupOptions) -> Result<Option<Cgroup>> {
+    match Cgroup::for_pid(pid, options.read_backoff) {
+        Ok(cgroup) => Ok(Some(cgroup)),
+        Err(err) if options.policy == CgroupPolicy::Degrade => {
+            warn!("Failed to locate cgroup of process {}: {:#}", pid, err);
+            Ok(None)
+        }
+        Err(err) => Err(err),
+    }
+}
+
+fn join_cgroup(mount: &Path, relative: &str) -> Result<PathBuf> {
+    // Cgroups outside of our cgroup namespace show up relative to its root, e.g. `/../foo`.
+    if relative.split('/').any(|component| component == "..") {
+        bail!(
+            "Cgroup {} is outside of the shim's cgroup namespace",
+            relative
+        );
+    }
+    let path = mount.join(relative.trim_start_matches('/'));
+    if !path.is_dir() {
+        bail!("Cgroup directory {:?} does not exist", path);
+    }
+    Ok(path)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::Cell;
+
+    use super::*;
+
+    #[test]
+    fn transient_read_errors_are_retried() {
+        let attempts = Cell::new(0);
+        let result = retry_transient(Duration::ZERO, || {
+            attempts.set(attempts.get() + 1);
+            if attempts.get() == 1 {
+                Err(io::Error::from_raw_os_error(libc::ENODEV))
+            } else {
+                Ok("1")
+            }
+        });
+        assert_eq!(result.unwrap(), "1");
+        assert_eq!(attempts.get(), 2);
+    }
+
+    #[test]
+    fn transient_read_errors_are_retried_a_limited_number_of_times() {
+        let attempts = Cell::new(0);
+        let result: io::Result<()> = retry_transient(Duration::ZERO, || {
+            attempts.set(attempts.get() + 1);
+            Err(io::Error::from_raw_os_error(libc::EINTR))
+        });
+        assert_eq!(result.unwrap_err().raw_os_error(), Some(libc::EINTR));
+        assert_eq!(attempts.get(), READ_RETRIES + 1);
+    }
+
+    #[test]
+    fn other_read_errors_are_not_retried() {
+        let attempts = Cell::new(0);
+        let result: io::Result<()> = retry_transient(Duration::ZERO, || {
+            attempts.set(attempts.get() + 1);
+            Err(io::Error::from_raw_os_error(libc::EACCES))
+        });
+        assert!(result.is_err());
+        assert_eq!(attempts.get(), 1);
+    }
+}
@@ -0,0 +1,351 @@
+use std::{ffi::OsString, net::SocketAddr, path::PathBuf, time::Duration};
+
+use clap::{Args, ValueEnum};
+
+use crate::{
+    cgroup::{CgroupOptions, CgroupPolicy},
+    metrics::Metrics,
+    monitor::{LogCompression, LogFormat, LogOptions, LogRotation},
+    utils::{format_duration, parse_duration, parse_size},
+};
+
+/// Daemon configuration, forwarded from `start` to the daemon process.
+#[derive(Args, Debug, Clone)]
+pub struct Config {
+    /// Path to OCI runtime executable.
+    #[arg(short, long, default_value = "/usr/sbin/runc")]
+    pub runtime: PathBuf,
+
+    /// Root directory of the OCI runtime's state (`--root`), e.g. for rootless runtimes. The
+    /// runtime's own default is used if unset. Containers created with a root in their runc
+    /// options use that instead.
+    #[arg(long)]
+    pub runtime_root: Option<PathBuf>,
+
+    /// Have the OCI runtime manage cgroups through systemd (`--systemd-cgroup`), as containerd
+    /// expects on systemd hosts. Containers' cgroupsPath must then be in `slice:prefix:name` form.
+    /// Containers can also enable it in their runc options.
+    #[arg(long)]
+    pub systemd_cgroup: bool,
+
+    /// Number of consecutive OCI runtime failures of the same kind, failing to spawn, being
+    /// killed by a signal or timing out, after which further invocations fail fast. Set to 0 to
+    /// disable.
+    #[arg(long, default_value_t = 5)]
+    pub runtime_failure_threshold: u32,
+
+    /// How long to fail fast once the OCI runtime appears broken.
+    #[arg(long, default_value = "30s", value_parser = parse_duration)]
+    pub runtime_failure_cooldown: Duration,
+
+    /// How long a single OCI runtime invocation may take before the runtime is killed and the
+    /// request fails with a deadline error, e.g. when it hangs on a mount.
+    #[arg(long, default_value = "60s", value_parser = parse_duration)]
+    pub runtime_timeout: Duration,
+
+    /// Bind the task socket in the abstract namespace rather than as a file, so that it goes
+    /// away with the shim and needs no writable directory. Its address is printed as
+    /// `unix-abstract:<name>`. Abstract sockets have no permissions: any process in the shim's
+    /// network namespace can connect and control the container.
+    #[arg(long)]
+    pub abstract_socket: bool,
+
+    /// How `start` hands the listening socket to the daemon. `path` is a fallback for
+    /// environments that restrict passing extra fds to child processes: the daemon binds the
+    /// socket itself and reports readiness on its stdout.
+    #[arg(long, value_enum, default_value_t = SocketPassing::Fd)]
+    pub socket_passing: SocketPassing,
+
+    /// How often to retry creating a container when the runtime fails with a transient error,
+    /// see `--create-retry-error`. Partial state of the failed attempt is force deleted first.
+    #[arg(long, default_value_t = 0)]
+    pub create_retries: u32,
+
+    /// Part of a runtime log error that marks a failed create as transient, e.g. a device being
+    /// briefly busy. Can be repeated. Only add errors caused by the environment: configuration
+    /// errors such as an exec format error would just fail again.
+    #[arg(
+        long = "create-retry-error",
+        default_values = ["device or resource busy", "resource temporarily unavailable"]
+    )]
+    pub create_retry_errors: Vec<String>,
+
+    /// Log the pid and exit code of reaped processes that don't belong to a container.
+    #[arg(long)]
+    pub log_orphans: bool,
+
+    /// Keep running if the shim can't become a child subreaper, e.g. because a seccomp profile
+    /// blocks prctl. Only processes the shim spawns itself are reaped then: orphans aren't, and
+    /// since a container's init process is orphaned by the runtime, its exit goes unnoticed and
+    /// waits on it don't return.
+    #[arg(long)]
+    pub best_effort_subreaper: bool,
+
+    /// Periodically fsync the container's stdout and stderr files so that output survives a host
+    /// crash. Every sync forces a write to stable storage, so short intervals reduce the
+    /// throughput of chatty containers and add IO load to the host. Disabled by default.
+    #[arg(long, value_parser = parse_duration)]
+    pub log_fsync_interval: Option<Duration>,
+
+    /// Directory the daemon changes into once its socket is set up, before serving requests.
+    /// Relative paths are resolved inside it from then on, so the runtime binary, bundles and
+    /// stdio files should be given as absolute paths.
+    #[arg(long)]
+    pub jail: Option<PathBuf>,
+
+    /// Also chroot into the jail directory (requires CAP_SYS_CHROOT). Every path the daemon uses
+    /// afterwards must exist inside the jail: the runtime binary and its libraries, bundles,
+    /// stdio files and the socket directory, which is otherwise left behind on exit.
+    #[arg(long, requires = "jail")]
+    pub jail_chroot: bool,
+
+    /// How the exit status of a process terminated by a signal is reported.
+    #[arg(long, value_enum, default_value_t = ExitCodeFormat::Shell)]
+    pub exit_code_format: ExitCodeFormat,
+
+    /// What to do when a container's cgroup can't be located, e.g. because it lives in a
+    /// foreign cgroup namespace. With `error`, starting such a container fails.
+    #[arg(long, value_enum, default_value_t = CgroupPolicy::Degrade)]
+    pub cgroup_policy: CgroupPolicy,
+
+    /// The backoff before retrying a cgroup read that failed transiently, e.g. with ENODEV while
+    /// the cgroup is torn down. It grows linearly with each of the two retries.
+    #[arg(long, default_value = "5ms", value_parser = parse_duration)]
+    pub cgroup_read_backoff: Duration,
+
+    /// How long to wait for the OCI runtime to delete a container, e.g. when unmounting hangs,
+    /// before retrying with `--force`. If that times out too the delete fails and is forced on
+    /// the next attempt.
+    #[arg(long, default_value = "10s", value_parser = parse_duration)]
+    pub delete_timeout: Duration,
+
+    /// How long a command run with Nsenter may take before it is killed and the request fails
+    /// with a deadline error.
+    #[arg(long, default_value = "60s", value_parser = parse_duration)]
+    pub nsenter_timeout: Duration,
+
+    /// How long shutdown gives containers to exit after SIGTERM before sending SIGKILL.
+    #[arg(long, default_value = "10s", value_parser = parse_duration)]
+    pub shutdown_grace_period: Duration,
+
+    /// How long the daemon lets requests in flight finish once it is shutting down, e.g. the
+    /// Shutdown call's own response or a Wait on another container, before it exits anyway.
+    /// No new connections are accepted meanwhile.
+    #[arg(long, default_value = "5s", value_parser = parse_duration)]
+    pub rpc_drain_timeout: Duration,
+
+    /// Wait this long between the container being created and starting it. A workaround for
+    /// hooks or init systems that race with start, not a recommended setting: it delays every
+    /// start and doesn't guarantee whatever it waits for has finished.
+    #[arg(long, value_parser = parse_duration)]
+    pub start_delay: Option<Duration>,
+
+    /// Minimum time between two reconciliations, which run `runtime state` for every container.
+    #[arg(long, default_value = "1s", value_parser = parse_duration)]
+    pub reconcile_interval: Duration,
+
+    /// Compress container output written to regular files, e.g. to save disk for chatty,
+    /// long-lived containers. Compression costs CPU in the shim, and output only reaches the
+    /// file in compressed blocks, so live tailing it lags behind. Readers have to decompress.
+    #[arg(long, value_enum, default_value_t = LogCompression::None)]
+    pub log_compression: LogCompression,
+
+    /// The format of container output written to regular files. `json` wraps each line in an
+    /// object with its stream and time, as log collectors parsing Docker's json-file logs expect.
+    #[arg(long, value_enum, default_value_t = LogFormat::Raw)]
+    pub log_format: LogFormat,
+
+    /// Rotate container output written to regular files once it reaches this size, e.g. `10M`:
+    /// the file is renamed to `<path>.1`, older rotations move up by one and a new file is
+    /// started. Files may exceed the size by one read of output. Set to 0 to disable.
+    #[arg(long, default_value = "0", value_parser = parse_size)]
+    pub log_max_size: u64,
+
+    /// Number of rotated files kept per log with `--log-max-size`. The oldest is removed when
+    /// another rotation would exceed it. With 0, a full log is truncated instead.
+    #[arg(long, default_value_t = 5)]
+    pub log_max_files: u32,
+
+    /// Limit the output read from each of a container's streams to this many bytes per second,
+    /// e.g. `1M`, to keep a chatty container from saturating the host's disk. The container
+    /// blocks on writing its output while it is held back. Set to 0 to disable.
+    #[arg(long, default_value = "0", value_parser = parse_size)]
+    pub log_rate_limit: u64,
+
+    /// Stop writing a stream's output once this many bytes have been written in total, e.g.
+    /// `1G`, discarding the rest with a warning. Rotated files count too. Set to 0 to disable.
+    #[arg(long, default_value = "0", value_parser = parse_size)]
+    pub log_output_limit: u64,
+
+    /// How long to wait after a process exited for its stdout and stderr to be closed, before
+    /// its exit is reported with the output copied so far. Only processes it left behind that
+    /// still hold them open make the wait this long, and what they write later is lost.
+    #[arg(long, default_value = "1s", value_parser = parse_duration)]
+    pub exit_drain_timeout: Duration,
+
+    /// containerd's ttrpc socket to publish task events to, usually its `TTRPC_ADDRESS`. Events
+    /// aren't published if unset.
+    #[arg(long)]
+    pub publish_address: Option<PathBuf>,
+
+    /// The containerd namespace the task belongs to, which its events are published in.
+    #[arg(long, default_value = "default")]
+    pub namespace: String,
+
+    /// Serve Prometheus metrics over HTTP at `/metrics` on this address, e.g. `127.0.0.1:9100`:
+    /// RPC and OCI runtime durations, output copied and containers by status. Every shim needs
+    /// its own port. Not served if unset.
+    #[arg(long)]
+    pub metrics_address: Option<SocketAddr>,
+}
+
+fn value_name<V: ValueEnum>(value: V) -> String {
+    value
+        .to_possible_value()
+        .expect("no skipped variants")
+        .get_name()
+        .to_string()
+}
+
+/// Mechanisms for handing the listening socket from `start` to the daemon.
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SocketPassing {
+    /// `start` binds the socket and passes it as an inherited fd.
+    Fd,
+
+    /// The daemon binds the socket from its path.
+    Path,
+}
+
+/// Exit status conventions for processes terminated by a signal. Normal exits always report
+/// the process's exit code.
+#[derive(ValueEnum, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum ExitCodeFormat {
+    /// Report 128 + the signal number, like shells and containerd do.
+    #[default]
+    Shell,
+
+    /// Report the signal number and set the response's `signaled` flag.
+    Raw,
+}
+
+impl Config {
+    /// Returns how container output is written, counting it in `metrics`.
+    pub fn log_options(&self, metrics: &Metrics) -> LogOptions {
+        LogOptions {
+            fsync_interval: self.log_fsync_interval,
+            compression: self.log_compression,
+            format: self.log_format,
+            rotation: (self.log_max_size > 0).then_some(LogRotation {
+                max_size: self.log_max_size,
+                max_files: self.log_max_files,
+            }),
+            rate_limit: (self.log_rate_limit > 0).then_some(self.log_rate_limit),
+            output_limit: (self.log_output_limit > 0).then_some(self.log_output_limit),
+            exit_drain_timeout: self.exit_drain_timeout,
+            metrics: metrics.clone(),
+        }
+    }
+
+    /// Returns how containers' cgroups are located and read.
+    pub fn cgroup_options(&self) -> CgroupOptions {
+        CgroupOptions {
+            policy: self.cgroup_policy,
+            read_backoff: self.cgroup_read_backoff,
+        }
+    }
+
+    /// Returns the command line arguments reproducing this configuration.
+    pub fn to_args(&self) -> Vec<OsString> {
+        let mut args: Vec<OsString> = vec![
+            "--runtime".into(),
+            self.runtime.clone().into(),
+            "--runtime-failure-threshold".into(),
+            self.runtime_failure_threshold.to_string().into(),
+            "--runtime-failure-cooldown".into(),
+            format_duration(self.runtime_failure_cooldown).into(),
+            "--runtime-timeout".into(),
+            format_duration(self.runtime_timeout).into(),
+        ];
+        if let Some(root) = &self.runtime_root {
+            args.push("--runtime-root".into());
+            args.push(root.into());
+        }
+        if self.systemd_cgroup {
+            args.push("--systemd-cgroup".into());
+        }
+        args.push("--create-retries".into());
+        args.push(self.create_retries.to_string().into());
+        for error in &self.create_retry_errors {
+            args.push("--create-retry-error".into());
+            args.push(error.into());
+        }
+        if self.abstract_socket {
+            args.push("--abstract-socket".into());
+        }
+        args.push("--socket-passing".into());
+        args.push(value_name(self.socket_passing).into());
+        if self.log_orphans {
+            args.push("--log-orphans".into());
+        }
+        if self.best_effort_subreaper {
+            args.push("--best-effort-subreaper".into());
+        }
+        if let Some(interval) = self.log_fsync_interval {
+            args.push("--log-fsync-interval".into());
+            args.push(format_duration(interval).into());
+        }
+        if let Some(jail) = &self.jail {
+            args.push("--jail".into());
+            args.push(jail.into());
+        }
+        if self.jail_chroot {
+            args.push("--jail-chroot".into());
+        }
+        args.push("--exit-code-format".into());
+        args.push(value_name(self.exit_code_format).into());
+        args.push("--cgroup-policy".into());
+        args.push(value_name(self.cgroup_policy).into());
+        args.push("--cgroup-read-backoff".into());
+        args.push(format_duration(self.cgroup_read_backoff).into());
+        args.push("--delete-timeout".into());
+        args.push(format_duration(self.delete_timeout).into());
+        args.push("--nsenter-timeout".into());
+        args.push(format_duration(self.nsenter_timeout).into());
+        args.push("--shutdown-grace-period".into());
+        args.push(format_duration(self.shutdown_grace_period).into());
+        args.push("--rpc-drain-timeout".into());
+        args.push(format_duration(self.rpc_drain_timeout).into());
+        args.push("--log-compression".into());
+        args.push(value_name(self.log_compression).into());
+        args.push("--log-format".into());
+        args.push(value_name(self.log_format).into());
+        args.push("--log-max-size".into());
+        args.push(self.log_max_size.to_string().into());
+        args.push("--log-max-files".into());
+        args.push(self.log_max_files.to_string().into());
+        args.push("--log-rate-limit".into());
+        args.push(self.log_rate_limit.to_string().into());
+        args.push("--log-output-limit".into());
+        args.push(self.log_output_limit.to_string().into());
+        args.push("--exit-drain-timeout".into());
+        args.push(format_duration(self.exit_drain_timeout).into());
+        args.push("--reconcile-interval".into());
+        args.push(format_duration(self.reconcile_interval).into());
+        if let Some(delay) = self.start_delay {
+            args.push("--start-delay".into());
+            args.push(format_duration(delay).into());
+        }
+        if let Some(address) = &self.publish_address {
+            args.push("--publish-address".into());
+            args.push(address.into());
+        }
+        args.push("--namespace".into());
+        args.push(self.namespace.clone().into());
+        if let Some(address) = self.metrics_address {
+            args.push("--metrics-address".into());
+            args.push(address.to_string().into());
+        }
+        args
+    }
+}
@@ -1,22 +1,141 @@
 use std::{
+    collections::HashMap,
+    env, fmt,
     fs::{self, File, OpenOptions},
+    io::{self, Read},
+    os::{
+        fd::{AsFd, AsRawFd, OwnedFd},
+        unix::{
+            fs::{FileTypeExt, OpenOptionsExt},
+            net::UnixListener,
+        },
+    },
     path::{Path, PathBuf},
     process::Stdio,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
 };
 
-use anyhow::{bail, Context, Result};
-use nix::{sys::signal::Signal, unistd::Pid};
+use anyhow::{anyhow, bail, Context, Result};
+use nix::{
+    fcntl::{fcntl, FcntlArg, OFlag},
+    sys::{
+        signal::{kill, Signal},
+        stat::Mode,
+    },
+    unistd::{mkdtemp, mkfifo, pipe2, Pid},
+};
 use prost_types::Timestamp;
+use serde::{Deserialize, Serialize};
 use time::OffsetDateTime;
 use tokio::{
-    process::Command,
-    sync::{mpsc, RwLock},
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::unix::pipe,
+    sync::{watch, Mutex, MutexGuard, RwLock},
+    task,
+    time::{sleep, timeout},
 };
+use tracing::{debug, error, info, warn};
 
-use crate::signal::forward_signal;
+use crate::{
+    cgroup::{self, CgroupOptions, Stats},
+    events::Publisher,
+    monitor::{rotated_path, LogOptions, Monitor, Stream},
+    mount::{self, Mount},
+    oom::OomWatcher,
+    process::{Exec, PidRegistry, Process},
+    runtime::{self, Runtime, RuntimeOptions},
+    seccomp::SeccompNotifyHandler,
+    signal::ExitStatus,
+    spec::{LinuxResources, Rlimit, Spec, SpecPatch},
+    utils::{recv_fd, write_atomic},
+};
 
 const PID_FILE: &str = "container.pid";
+const RUNTIME_LOG: &str = "log.json";
+const EXIT_FILE: &str = "exit.json";
+const STATE_FILE: &str = "state.json";
+
+/// File in the bundle holding the resources for `runtime update`.
+const UPDATE_RESOURCES: &str = "update.json";
+
+/// The log CRIU writes to the work directory when checkpointing, and how much of it to include
+/// in the error when checkpointing fails.
+const CRIU_DUMP_LOG: &str = "dump.log";
+const CRIU_RESTORE_LOG: &str = "restore.log";
+const CRIU_LOG_LINES: usize = 10;
+
+/// Name of the socket in a [`ConsoleSocket`]'s directory.
+const CONSOLE_SOCKET: &str = "console.sock";
+
+/// Prefix of the files in the bundle holding an exec'd process' spec and pid.
+const EXEC_PREFIX: &str = "exec-";
+/// The backoff before retrying a transiently failed create, growing linearly with each attempt.
+const CREATE_RETRY_BACKOFF: Duration = Duration::from_millis(100);
+
+/// How long to wait for the runtime's pid file to be written, and how often to check for it.
+const PID_FILE_TIMEOUT: Duration = Duration::from_secs(2);
+const PID_FILE_POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+/// How long to wait for a container to exit after SIGKILL, see [`Container::stop`].
+const KILL_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// How long the raw runtime state is cached, see [`Container::runtime_state`].
+const RUNTIME_STATE_TTL: Duration = Duration::from_secs(1);
+
+/// Directory in the bundle holding the patched spec the runtime is given, if any.
+const PATCHED_BUNDLE: &str = "patched";
+
+/// The directory in the bundle the rootfs mounts go to.
+const ROOTFS: &str = "rootfs";
+
+/// Exit status reported when the real exit status of a process could not be determined.
+const UNKNOWN_EXIT_STATUS: ExitStatus = ExitStatus::Exited(255);
+
+/// How much of the stdin path is copied into the container's stdin at a time.
+const STDIN_BUFFER_SIZE: usize = 32 * 1024;
+
+/// Failures of container operations that callers tell apart from other errors, e.g. to report
+/// them with a specific gRPC status. Other failures are plain errors.
+#[derive(Debug)]
+pub enum ContainerError {
+    /// A process with the ID already exists.
+    AlreadyExists(String),
+
+    /// The process doesn't exist.
+    NotFound(String),
+
+    /// The container or process is not in a state that allows the operation.
+    InvalidState(String),
+
+    /// The OCI runtime failed the operation.
+    RuntimeFailed(String),
+}
+
+impl fmt::Display for ContainerError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ContainerError::AlreadyExists(message)
+            | ContainerError::NotFound(message)
+            | ContainerError::InvalidState(message)
+            | ContainerError::RuntimeFailed(message) => f.write_str(message),
+        }
+    }
+}
+
+impl std::error::Error for ContainerError {}
 
+/// A container managed through the OCI runtime.
+///
+/// Operations that invoke the runtime on the container or signal its processes (create, start,
+/// pause, resume, update, checkpoint, delete, recover, reconcile, kill, kill_all, start_exec and
+/// delete_exec) hold the `operation` lock for their whole duration, so at most one of them runs
+/// at a time and each sees the state the previous one left behind. Reading the
+/// container's state and registering wait channels only take the short-lived locks on the
+/// individual fields, so they never queue behind a slow runtime call.
 pub struct Container {
     /// The container ID.
     pub id: String,
@@ -30,159 +149,2101 @@ pub struct Container {
     /// The container's stderr path.
     pub stderr: PathBuf,
 
-    /// The container status.
-    status: RwLock<Status>,
+    /// Options the container was created with.
+    pub options: CreateOptions,
+
+    /// The container's init process.
+    init: Arc<Process>,
+
+    /// Processes started in the container with exec, by exec ID.
+    execs: RwLock<HashMap<String, Arc<Exec>>>,
+
+    /// Where the pids of the container's processes are registered for the reaper.
+    pids: PidRegistry,
+
+    /// Where the container's task events are published.
+    events: Publisher,
+
+    /// Watches the container's cgroup for OOM kills while it runs.
+    oom_watcher: Mutex<Option<OomWatcher>>,
+
+    /// The container's create timestamp.
+    created_at: RwLock<Option<OffsetDateTime>>,
+
+    /// The container's start timestamp.
+    started_at: RwLock<Option<OffsetDateTime>>,
+
+    /// Serializes runtime operations, e.g. so that a kill can't race a delete into signalling a
+    /// pid that has since been reused. See the locking model above.
+    operation: Mutex<()>,
+
+    /// Whether the container has been deleted from the runtime.
+    deleted: AtomicBool,
 
-    /// The container process ID.
-    pid: RwLock<i32>,
+    /// Whether a delete timed out, leaving the container to be force deleted later.
+    cleanup_pending: AtomicBool,
 
-    /// The container's exit code.
-    exit_code: RwLock<i32>,
+    /// The seccomp notify listener fd sent by the runtime, if the spec requests one.
+    seccomp_listener: RwLock<Option<OwnedFd>>,
 
-    /// The container's exit timestamp.
-    exited_at: RwLock<Option<OffsetDateTime>>,
+    /// The resource limits from the spec's `process.rlimits`, read at create.
+    rlimits: RwLock<Vec<Rlimit>>,
 
-    /// The container's wait channels.
-    wait_channels: RwLock<Vec<mpsc::UnboundedSender<()>>>,
+    /// Serializes saving the container's state, see [`Container::save`].
+    persist: Mutex<()>,
+
+    /// Whether the container was loaded from a previous daemon, whose processes we can't reap.
+    adopted: AtomicBool,
+
+    /// The last raw runtime state and when it was fetched.
+    runtime_state: std::sync::Mutex<Option<(Instant, Vec<u8>)>>,
+
+    /// The write end of the container's stdin pipe, until it is closed. Shared with the task
+    /// copying the stdin path into it.
+    stdin: Arc<Mutex<Option<pipe::Sender>>>,
+
+    /// Set once stdin is closed on request, which interrupts writers waiting for the container
+    /// to read while holding [`Container::stdin`].
+    stdin_closed: watch::Sender<bool>,
+
+    /// Copies the container's stdout into its sink.
+    stdout_monitor: RwLock<Option<Monitor>>,
+
+    /// Copies the container's stderr into its sink, unless it is merged into stdout.
+    stderr_monitor: RwLock<Option<Monitor>>,
 }
 
-#[derive(Clone, Copy, PartialEq, Eq)]
+/// Options for creating a container, taken from the create request.
+#[derive(Default)]
+pub struct CreateOptions {
+    /// Create stdout and stderr as named pipes if they don't exist. Until a reader connects,
+    /// output is buffered in the pipe.
+    pub create_fifos: bool,
+
+    /// Give the container's stderr the same pipe as its stdout, like `2>&1`. The container's
+    /// writes reach the stdout sink in the order it made them. This is unlike merging the streams
+    /// after the fact, which can only interleave whole writes read from two separate pipes.
+    pub merge_stderr: bool,
+
+    /// The `oom_score_adj` of the init process, overriding the spec's `process.oomScoreAdj`.
+    pub oom_score_adj: Option<i32>,
+
+    /// Give the container a stdin pipe, see [`Container::stdin`]. With a terminal, stdin is
+    /// written to the pty instead.
+    pub stdin: bool,
+
+    /// A file or named pipe copied into the container's stdin, or empty for none. Implies
+    /// `stdin`.
+    pub stdin_path: PathBuf,
+
+    /// A checkpoint image directory to restore the container from with CRIU, rather than
+    /// creating it afresh. The runtime restores it running, so it skips `start`.
+    pub checkpoint: Option<PathBuf>,
+
+    /// Give the container a pty, which needs `process.terminal` set in the spec. Its output is
+    /// copied to stdout, and it can be resized through the init process.
+    pub terminal: bool,
+
+    /// Changes to the spec, e.g. security labels. The runtime is then given a patched copy of
+    /// the spec in a subdirectory of the bundle, which is what hooks see as the bundle.
+    pub spec_patch: SpecPatch,
+
+    /// How the runtime is invoked for the container.
+    pub runtime: RuntimeOptions,
+
+    /// Mounted onto the bundle's rootfs directory before create, and unmounted on delete.
+    pub rootfs: Vec<Mount>,
+}
+
+/// How to checkpoint a container with CRIU, from containerd's runc checkpoint options.
+#[derive(Clone, Debug, Default)]
+pub struct CheckpointOptions {
+    /// Directory CRIU writes the images to.
+    pub image_path: PathBuf,
+
+    /// Directory for CRIU's log and temporary files, the image directory if unset.
+    pub work_path: Option<PathBuf>,
+
+    /// Keep the container running after checkpointing it (`--leave-running`).
+    pub leave_running: bool,
+
+    /// Checkpoint established TCP connections (`--tcp-established`).
+    pub tcp_established: bool,
+
+    /// Allow unix sockets connected to peers outside the container (`--ext-unix-sk`).
+    pub external_unix_sockets: bool,
+
+    /// Allow a process attached to a terminal (`--shell-job`).
+    pub shell_job: bool,
+
+    /// Checkpoint file locks (`--file-locks`).
+    pub file_locks: bool,
+
+    /// Namespaces to be created empty rather than restored, e.g. `network` (`--empty-ns`).
+    pub empty_namespaces: Vec<String>,
+
+    /// How CRIU handles cgroups, `soft`, `full` or `strict` (`--manage-cgroups-mode`).
+    pub cgroups_mode: Option<String>,
+}
+
+/// The exit of a container's init process, recorded in the bundle so that it isn't lost if no
+/// one is waiting for it.
+#[derive(Serialize)]
+struct ExitRecord {
+    exit_status: ExitStatus,
+
+    /// Nanoseconds since the Unix epoch.
+    exited_at: i128,
+}
+
+/// The container's state, persisted in its bundle on every transition so that a restarted
+/// daemon can load it, see [`Container::load`].
+#[derive(Serialize, Deserialize)]
+struct PersistedState {
+    id: String,
+    bundle: PathBuf,
+    stdout: PathBuf,
+    stderr: PathBuf,
+    pid: i32,
+    status: Status,
+    exit_status: ExitStatus,
+
+    /// Nanoseconds since the Unix epoch, once the container has exited.
+    exited_at: Option<i128>,
+
+    /// Needed to find the container in the runtime again, e.g. its root.
+    #[serde(default)]
+    runtime: RuntimeOptions,
+
+    /// Needed to unmount the rootfs on delete.
+    #[serde(default)]
+    rootfs: Vec<Mount>,
+}
+
+/// What changed when a container was reconciled with the runtime.
+pub struct Reconciled {
+    pub previous_status: Status,
+    pub status: Status,
+    pub pid: i32,
+}
+
+/// Size information about a log file and its rotations.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct LogStats {
+    pub size: u64,
+    pub rotations: u32,
+    pub total_size: u64,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[allow(clippy::upper_case_acronyms)]
 pub enum Status {
     UNKNOWN,
     CREATED,
     RUNNING,
+    PAUSED,
     STOPPED,
 }
 
 impl Container {
-    pub fn new(id: &str, bundle: &PathBuf, stdout: &PathBuf, stderr: &PathBuf) -> Self {
+    pub fn new(
+        id: &str,
+        bundle: &PathBuf,
+        stdout: &PathBuf,
+        stderr: &PathBuf,
+        options: CreateOptions,
+        pids: PidRegistry,
+        events: Publisher,
+    ) -> Self {
         Self {
             id: id.to_string(),
             bundle: bundle.to_owned(),
             stdout: stdout.to_owned(),
             stderr: stderr.to_owned(),
-            status: RwLock::new(Status::UNKNOWN),
-            pid: RwLock::new(0),
-            exit_code: RwLock::new(0),
-            exited_at: RwLock::new(None),
-            wait_channels: RwLock::new(Vec::new()),
+            options,
+            init: Arc::new(Process::new(Status::UNKNOWN)),
+            execs: RwLock::new(HashMap::new()),
+            pids,
+            events,
+            oom_watcher: Mutex::new(None),
+            created_at: RwLock::new(None),
+            started_at: RwLock::new(None),
+            operation: Mutex::new(()),
+            deleted: AtomicBool::new(false),
+            cleanup_pending: AtomicBool::new(false),
+            seccomp_listener: RwLock::new(None),
+            rlimits: RwLock::new(Vec::new()),
+            persist: Mutex::new(()),
+            adopted: AtomicBool::new(false),
+            runtime_state: std::sync::Mutex::new(None),
+            stdin: Arc::new(Mutex::new(None)),
+            stdin_closed: watch::Sender::new(false),
+            stdout_monitor: RwLock::new(None),
+            stderr_monitor: RwLock::new(None),
         }
     }
 
-    pub async fn create(&self, runtime: &PathBuf) -> Result<()> {
-        let mut cmd = Command::new(runtime);
-        cmd.arg("create")
-            .arg("--bundle")
-            .arg(&self.bundle)
-            .arg("--pid-file")
-            .arg(self.bundle.join(PID_FILE))
-            .arg(&self.id);
-        cmd.stdin(Stdio::null())
-            .stdout(stdio_file(&self.stdout)?)
-            .stderr(stdio_file(&self.stderr)?);
-        let mut child = cmd.spawn().context("Failed to spawn OCI runtime")?;
-        match child.wait().await {
-            Ok(status) if status.success() => {}
-            Ok(status) => bail!("OCI runtime exited with status {}", status),
-            Err(err) => bail!("Failed to wait for OCI runtime: {}", err),
-        };
-        let pid = read_pid(self.bundle.join(PID_FILE))?;
-        let mut pid_guard = self.pid.write().await;
-        let mut status_guard = self.status.write().await;
-        *pid_guard = pid;
-        *status_guard = Status::CREATED;
+    /// Creates the container, or restores it from its checkpoint, in which case it is running
+    /// once this returns.
+    pub async fn create(
+        &self,
+        runtime: &Runtime,
+        log_options: LogOptions,
+        cgroup_options: CgroupOptions,
+        seccomp_handler: &dyn SeccompNotifyHandler,
+    ) -> Result<()> {
+        let _operation = self.operation.lock().await;
+        if self.status().await != Status::UNKNOWN {
+            bail!(ContainerError::InvalidState(format!(
+                "Container {} has already been created",
+                self.id
+            )));
+        }
+        if !self.options.rootfs.is_empty() {
+            mount::mount_all(&self.options.rootfs, &self.bundle.join(ROOTFS))
+                .context("Failed to mount rootfs")?;
+        }
+        let mut runtime_created = false;
+        let result = self
+            .create_container(
+                runtime,
+                log_options,
+                cgroup_options,
+                seccomp_handler,
+                &mut runtime_created,
+            )
+            .await;
+        if result.is_err() {
+            // The runtime's container still uses the rootfs, so it goes first.
+            if runtime_created {
+                if let Err(err) = self.force_delete(runtime).await {
+                    warn!(
+                        "Failed to delete container {} after failing to create it: {:#}",
+                        self.id, err
+                    );
+                }
+            }
+            if !self.options.rootfs.is_empty() {
+                mount::unmount_all(&self.bundle.join(ROOTFS));
+            }
+        }
+        result
+    }
+
+    /// Creates the container in the runtime and sets up its process. `runtime_created` is set
+    /// once the runtime has created the container, which then has to be deleted if a later step
+    /// fails.
+    async fn create_container(
+        &self,
+        runtime: &Runtime,
+        log_options: LogOptions,
+        cgroup_options: CgroupOptions,
+        seccomp_handler: &dyn SeccompNotifyHandler,
+        runtime_created: &mut bool,
+    ) -> Result<()> {
+        let spec = Spec::load(&self.bundle)?;
+        if runtime.systemd_cgroup(&self.options.runtime) {
+            spec.validate_systemd_cgroups_path()?;
+        }
+        // The runtime validates the rlimits itself, so bad ones aren't our concern here.
+        match spec.rlimits() {
+            Ok(rlimits) => {
+                debug!("Container {} has rlimits {:?}", self.id, rlimits);
+                *self.rlimits.write().await = rlimits;
+            }
+            Err(err) => warn!("Ignoring rlimits of container {}: {:#}", self.id, err),
+        }
+        // With a terminal the container's stdio is the pty, whose output is monitored once the
+        // runtime has handed over its master.
+        let (stdout, stderr) = if self.options.terminal {
+            (None, None)
+        } else {
+            let stdout = self
+                .monitor_stdio(
+                    &self.stdout,
+                    &self.stdout_monitor,
+                    Stream::Stdout,
+                    &log_options,
+                )
+                .await?;
+            let stderr = if self.options.merge_stderr {
+                stdout.as_ref().map(OwnedFd::try_clone).transpose()?
+            } else {
+                self.monitor_stdio(
+                    &self.stderr,
+                    &self.stderr_monitor,
+                    Stream::Stderr,
+                    &log_options,
+                )
+                .await?
+            };
+            (stdout, stderr)
+        };
+        let runtime_bundle = if self.options.spec_patch.is_empty() {
+            self.bundle.clone()
+        } else {
+            let dir = self.bundle.join(PATCHED_BUNDLE);
+            self.options
+                .spec_patch
+                .write(&self.bundle, &dir)
+                .context("Failed to patch spec")?;
+            dir
+        };
+        let stdin = if self.has_stdin() && !self.options.terminal {
+            let (reader, writer) = pipe2(OFlag::O_CLOEXEC).context("Failed to create pipe")?;
+            *self.stdin.lock().await = Some(pipe::Sender::from_owned_fd(writer)?);
+            Some(reader)
+        } else {
+            None
+        };
+        let log = self.bundle.join(RUNTIME_LOG);
+        let mut attempt = 0;
+        let mut console = None;
+        let mut seccomp_listener = None;
+        loop {
+            let _ = fs::remove_file(&log);
+            let mut cmd = runtime.command(&self.options.runtime);
+            cmd.arg("--log").arg(&log).arg("--log-format").arg("json");
+            match &self.options.checkpoint {
+                Some(checkpoint) => {
+                    cmd.arg("restore")
+                        .arg("--detach")
+                        .arg("--image-path")
+                        .arg(checkpoint);
+                }
+                None => {
+                    cmd.arg("create");
+                }
+            }
+            cmd.arg("--bundle")
+                .arg(&runtime_bundle)
+                .arg("--pid-file")
+                .arg(self.bundle.join(PID_FILE));
+            if self.options.runtime.no_pivot_root {
+                cmd.arg("--no-pivot");
+            }
+            // Restore has no such option, the restored processes keep their keyring.
+            if self.options.runtime.no_new_keyring && self.options.checkpoint.is_none() {
+                cmd.arg("--no-new-keyring");
+            }
+            if self.options.terminal {
+                // A fresh socket per attempt, so a failed attempt's pty can't be picked up.
+                let socket = ConsoleSocket::bind()?;
+                cmd.arg("--console-socket").arg(socket.path());
+                console = Some(socket);
+            }
+            // The runtime connects to the listener path during create to hand over the fd, so
+            // the socket has to exist before it is invoked. A fresh one per attempt, so a failed
+            // attempt's connection can't be picked up.
+            drop(seccomp_listener.take());
+            seccomp_listener = spec
+                .seccomp_listener_path()
+                .map(SeccompListener::bind)
+                .transpose()
+                .context("Failed to bind seccomp notify listener")?;
+            cmd.arg(&self.id);
+            cmd.stdout(stdio(&stdout)?).stderr(stdio(&stderr)?);
+            if let Some(stdin) = &stdin {
+                cmd.stdin(stdin.try_clone()?);
+            }
+            let Err(err) = runtime.run(cmd).await else {
+                *runtime_created = true;
+                break;
+            };
+            let err = match &self.options.checkpoint {
+                Some(checkpoint) => with_criu_log(err, &checkpoint.join(CRIU_RESTORE_LOG)),
+                None => err,
+            };
+            let errors = runtime::log_errors(&log);
+            if attempt < runtime.create_retries && runtime.is_transient(&errors) {
+                attempt += 1;
+                warn!(
+                    "Creating container {} failed transiently, retrying ({}/{}): {}",
+                    self.id,
+                    attempt,
+                    runtime.create_retries,
+                    errors.join("; ")
+                );
+                // Remove whatever the failed attempt left behind.
+                let _ = self.force_delete(runtime).await;
+                sleep(CREATE_RETRY_BACKOFF * attempt).await;
+                continue;
+            }
+            // Hooks run by the runtime only show up in its log, so surface their errors.
+            let hook_errors: Vec<_> = errors
+                .iter()
+                .filter(|msg| msg.contains("hook"))
+                .map(String::as_str)
+                .collect();
+            if !hook_errors.is_empty() {
+                return Err(err.context(format!("OCI hook failed: {}", hook_errors.join("; "))));
+            }
+            // The runtime's stderr is the container's, so its messages are only in the log.
+            if !errors.is_empty() {
+                return Err(err.context(errors.join("; ")));
+            }
+            return Err(err);
+        }
+        // Keeping our copies of the write ends would keep the monitors from seeing EOF.
+        drop((stdout, stderr));
+        if let Some(console) = console {
+            self.attach_console(console.receive()?, log_options)
+                .await
+                .context("Failed to attach to container terminal")?;
+        }
+        if !self.options.stdin_path.as_os_str().is_empty() {
+            tokio::spawn(copy_stdin(
+                format!("container {}", self.id),
+                self.options.stdin_path.clone(),
+                self.stdin.clone(),
+                self.stdin_closed.subscribe(),
+            ));
+        }
+        let pid = read_pid(self.bundle.join(PID_FILE)).await?;
+        let now = OffsetDateTime::now_utc();
+        *self.created_at.write().await = Some(now);
+        if self.options.checkpoint.is_some() {
+            *self.started_at.write().await = Some(now);
+            self.init.set_pid_status(pid, Status::RUNNING).await;
+        } else {
+            self.init.set_pid_status(pid, Status::CREATED).await;
+        }
+        // Registered only once the pid is set, as the reaper drops exits of other pids.
+        let registered = self.pids.register(pid, &self.id, None);
+        self.save().await;
+        // The init process may crash before `create` returns, in which case it has already been
+        // reaped and its exit status is lost.
+        if !registered {
+            match runtime.state(&self.id, &self.options.runtime).await {
+                Ok(state) => warn!(
+                    "Container {} init process {} exited during create, runtime reports {}",
+                    self.id, pid, state.status
+                ),
+                Err(err) => warn!(
+                    "Container {} init process {} exited during create: {}",
+                    self.id, pid, err
+                ),
+            }
+            self.set_exited(UNKNOWN_EXIT_STATUS).await;
+        } else if self.options.checkpoint.is_some() {
+            // What start does before the entrypoint runs, but the restored processes are already
+            // running, so failures can't stop them anymore.
+            if let Err(err) = self.watch_oom(cgroup_options).await {
+                warn!("Failed to watch restored container {}: {:#}", self.id, err);
+            }
+            if let Some(oom_score_adj) = self.options.oom_score_adj {
+                let path = format!("/proc/{}/oom_score_adj", pid);
+                if let Err(err) = fs::write(&path, oom_score_adj.to_string()) {
+                    warn!("Failed to write {}: {}", path, err);
+                }
+            }
+        }
+        if let Some(listener) = seccomp_listener {
+            self.receive_seccomp_listener(&listener.listener, seccomp_handler)
+                .await;
+        }
+        Ok(())
+    }
+
+    /// Copies the output of the container's pty to stdout and makes it the container's stdin.
+    ///
+    /// The pty is always the controlling terminal of the container's init process: runc sets it
+    /// up that way in the container before handing us the master. Closing stdin does not close
+    /// the master, as the output monitor and the process still hold it, but once every copy is
+    /// closed, for instance when the shim exits, the terminal hangs up and the kernel sends
+    /// SIGHUP to the container's foreground process group.
+    async fn attach_console(&self, console: OwnedFd, log_options: LogOptions) -> Result<()> {
+        let flags = OFlag::from_bits_truncate(fcntl(console.as_raw_fd(), FcntlArg::F_GETFL)?);
+        fcntl(
+            console.as_raw_fd(),
+            FcntlArg::F_SETFL(flags | OFlag::O_NONBLOCK),
+        )?;
+        // The pty has to be read even if its output is discarded, or the container blocks.
+        let sink = if self.stdout.as_os_str().is_empty() {
+            File::options().write(true).open("/dev/null")?
+        } else {
+            self.open_stdio(&self.stdout)?
+        };
+        let monitor =
+            Monitor::spawn_console(console.try_clone()?, sink, &self.stdout, log_options)?;
+        *self.stdout_monitor.write().await = Some(monitor);
+        if self.has_stdin() {
+            *self.stdin.lock().await =
+                Some(pipe::Sender::from_owned_fd_unchecked(console.try_clone()?)?);
+        }
+        self.init.set_console(console).await;
         Ok(())
     }
 
-    pub async fn start(&self, runtime: &PathBuf) -> Result<()> {
-        let mut cmd = Command::new(runtime);
+    /// Opens a stdout or stderr path, as a named pipe if it already is one or `create_fifos` is
+    /// set. Truncating and blocking until a reader connects are both wrong for named pipes.
+    fn open_stdio(&self, path: &Path) -> Result<File> {
+        let is_fifo =
+            fs::symlink_metadata(path).is_ok_and(|metadata| metadata.file_type().is_fifo());
+        if self.options.create_fifos || is_fifo {
+            open_fifo(path).with_context(|| format!("Failed to open fifo {:?}", path))
+        } else {
+            stdio_file(path)
+        }
+    }
+
+    /// Redirects the container's stdout and/or stderr to new paths, opened like at create. Both
+    /// are opened, and their monitors checked, before either is swapped, so a failure leaves the
+    /// output unchanged. Only the container closing its output in between can still fail the
+    /// second swap after the first.
+    pub async fn update_stdio(&self, stdout: Option<&Path>, stderr: Option<&Path>) -> Result<()> {
+        let stdout = stdout
+            .map(|path| self.open_stdio(path).map(|file| (file, path)))
+            .transpose()?;
+        let stderr = stderr
+            .map(|path| self.open_stdio(path).map(|file| (file, path)))
+            .transpose()?;
+        let stdout_monitor = self.stdout_monitor.read().await;
+        let stderr_monitor = self.stderr_monitor.read().await;
+        let mut swaps = Vec::new();
+        for (monitor, sink, stream) in [
+            (stdout_monitor.as_ref(), stdout, Stream::Stdout),
+            (stderr_monitor.as_ref(), stderr, Stream::Stderr),
+        ] {
+            let Some((file, path)) = sink else {
+                continue;
+            };
+            match monitor {
+                Some(monitor) if !monitor.is_closed() => swaps.push((monitor, file, path)),
+                _ => bail!(ContainerError::InvalidState(format!(
+                    "The {} of container {} is not being monitored",
+                    stream.name(),
+                    self.id
+                ))),
+            }
+        }
+        for (monitor, file, path) in swaps {
+            monitor.swap(file, path).await?;
+        }
+        Ok(())
+    }
+
+    async fn receive_seccomp_listener(
+        &self,
+        listener: &UnixListener,
+        handler: &dyn SeccompNotifyHandler,
+    ) {
+        let fd = match listener.accept() {
+            Ok((stream, _)) => recv_fd(stream.as_fd()),
+            Err(err) => Err(err.into()),
+        };
+        match fd {
+            Ok(fd) => {
+                let mut guard = self.seccomp_listener.write().await;
+                let fd = guard.insert(fd);
+                handler.handle(&self.id, fd.as_fd());
+            }
+            Err(err) => warn!(
+                "Failed to receive seccomp notify fd for container {}: {}",
+                self.id, err
+            ),
+        }
+    }
+
+    pub async fn start(&self, runtime: &Runtime, cgroup_options: CgroupOptions) -> Result<()> {
+        let _operation = self.operation.lock().await;
+        // A concurrent start may have won the race for the lock.
+        if self.status().await != Status::CREATED {
+            bail!(ContainerError::InvalidState(format!(
+                "Container {} is not created",
+                self.id
+            )));
+        }
+        // Check up front that cgroup based features will work for this container.
+        self.watch_oom(cgroup_options).await?;
+        // Set before the init process execs the container's entrypoint, so that there is no
+        // window in which it runs with the spec's value.
+        if let Some(oom_score_adj) = self.options.oom_score_adj {
+            let path = format!("/proc/{}/oom_score_adj", self.pid().await);
+            fs::write(&path, oom_score_adj.to_string())
+                .with_context(|| format!("Failed to write {}", path))?;
+        }
+        let mut cmd = runtime.command(&self.options.runtime);
         cmd.arg("start").arg(&self.id);
-        cmd.stdin(Stdio::null())
-            .stdout(Stdio::null())
-            .stderr(Stdio::null());
-        let mut child = cmd.spawn().context("Failed to spawn OCI runtime")?;
-        match child.wait().await {
-            Ok(status) if status.success() => {}
-            Ok(status) => bail!("OCI runtime exited with status {}", status),
-            Err(err) => bail!("Failed to wait for OCI runtime: {}", err),
-        };
-        *self.status.write().await = Status::RUNNING;
+        cmd.stdout(Stdio::null()).stderr(Stdio::piped());
+        runtime.run(cmd).await?;
+        self.init.set_status(Status::RUNNING).await;
+        *self.started_at.write().await = Some(OffsetDateTime::now_utc());
+        self.save().await;
+        Ok(())
+    }
+
+    /// Watches the container's cgroup for OOM kills, if it can be located.
+    async fn watch_oom(&self, cgroup_options: CgroupOptions) -> Result<()> {
+        if let Some(cgroup) = cgroup::resolve(self.pid().await, cgroup_options)? {
+            debug!("Container {} is in cgroup {}", self.id, cgroup);
+            let watcher = OomWatcher::spawn(&self.id, cgroup, self.events.clone());
+            *self.oom_watcher.lock().await = Some(watcher);
+        }
+        Ok(())
+    }
+
+    fn has_stdin(&self) -> bool {
+        self.options.stdin || !self.options.stdin_path.as_os_str().is_empty()
+    }
+
+    /// Returns the write end of the container's stdin pipe, locked so that concurrent writers
+    /// don't interleave. Setting it to `None` closes the container's stdin. It is `None` if the
+    /// container was created without stdin.
+    pub async fn stdin(&self) -> MutexGuard<'_, Option<pipe::Sender>> {
+        self.stdin.lock().await
+    }
+
+    /// Returns a receiver that sees `true` once [`Container::close_stdin`] is called. Writers
+    /// holding [`Container::stdin`] must stop and close it then.
+    pub fn stdin_closed(&self) -> watch::Receiver<bool> {
+        self.stdin_closed.subscribe()
+    }
+
+    /// Closes the container's stdin, so that it reads EOF once it has read what was written.
+    /// Writers waiting for the container to read are interrupted.
+    pub async fn close_stdin(&self) {
+        self.stdin_closed.send_replace(true);
+        *self.stdin.lock().await = None;
+    }
+
+    /// Gives the output monitors up to `timeout` to write out the container's remaining output,
+    /// see [`Monitor::drain`].
+    pub async fn drain_output(&self, timeout: Duration) {
+        let execs: Vec<_> = self.execs.read().await.values().cloned().collect();
+        let monitors = execs
+            .iter()
+            .flat_map(|exec| [&exec.stdout_monitor, &exec.stderr_monitor]);
+        for monitor in [&self.stdout_monitor, &self.stderr_monitor]
+            .into_iter()
+            .chain(monitors)
+        {
+            if let Some(monitor) = monitor.read().await.as_ref() {
+                monitor.drain(timeout).await;
+            }
+        }
+    }
+
+    /// Freezes all processes in the container.
+    pub async fn pause(&self, runtime: &Runtime) -> Result<()> {
+        let _operation = self.operation.lock().await;
+        if self.status().await != Status::RUNNING {
+            bail!(ContainerError::InvalidState(format!(
+                "Container {} is not running",
+                self.id
+            )));
+        }
+        let mut cmd = runtime.command(&self.options.runtime);
+        cmd.arg("pause").arg(&self.id);
+        cmd.stdout(Stdio::null()).stderr(Stdio::piped());
+        runtime.run(cmd).await?;
+        self.init.set_status(Status::PAUSED).await;
+        self.save().await;
+        Ok(())
+    }
+
+    /// Thaws a paused container.
+    pub async fn resume(&self, runtime: &Runtime) -> Result<()> {
+        let _operation = self.operation.lock().await;
+        if self.status().await != Status::PAUSED {
+            bail!(ContainerError::InvalidState(format!(
+                "Container {} is not paused",
+                self.id
+            )));
+        }
+        let mut cmd = runtime.command(&self.options.runtime);
+        cmd.arg("resume").arg(&self.id);
+        cmd.stdout(Stdio::null()).stderr(Stdio::piped());
+        runtime.run(cmd).await?;
+        self.init.set_status(Status::RUNNING).await;
+        self.save().await;
+        Ok(())
+    }
+
+    /// Returns the raw JSON state of the container as reported by the runtime. It is cached for a
+    /// second so that clients polling it don't hammer the runtime.
+    pub async fn runtime_state(&self, runtime: &Runtime) -> Result<Vec<u8>> {
+        if let Some((fetched_at, state)) = &*self.runtime_state.lock().unwrap() {
+            if fetched_at.elapsed() < RUNTIME_STATE_TTL {
+                return Ok(state.clone());
+            }
+        }
+        let state = runtime.state_json(&self.id, &self.options.runtime).await?;
+        *self.runtime_state.lock().unwrap() = Some((Instant::now(), state.clone()));
+        Ok(state)
+    }
+
+    /// Reads the container's effective resource limits from its cgroup.
+    pub async fn resources(&self, cgroup_options: CgroupOptions) -> Result<LinuxResources> {
+        let cgroup = cgroup::resolve(self.pid().await, cgroup_options)?
+            .context("Container cgroup could not be located")?;
+        task::spawn_blocking(move || cgroup.resources()).await?
+    }
+
+    /// Changes the container's resource limits. `resources` is a JSON encoded OCI
+    /// `LinuxResources`, passed to the runtime as is.
+    pub async fn update(&self, runtime: &Runtime, resources: &[u8]) -> Result<()> {
+        let _operation = self.operation.lock().await;
+        // A forced delete leaves the status as it was, as the exit is no longer routed to us.
+        if self.deleted.load(Ordering::SeqCst) || self.status().await != Status::RUNNING {
+            bail!(ContainerError::InvalidState(format!(
+                "Container {} is not running",
+                self.id
+            )));
+        }
+        let path = self.bundle.join(UPDATE_RESOURCES);
+        fs::write(&path, resources).with_context(|| format!("Failed to write {:?}", path))?;
+        let mut cmd = runtime.command(&self.options.runtime);
+        cmd.arg("update")
+            .arg("--resources")
+            .arg(&path)
+            .arg(&self.id);
+        cmd.stdout(Stdio::null()).stderr(Stdio::piped());
+        let result = runtime.run(cmd).await;
+        let _ = fs::remove_file(&path);
+        result
+    }
+
+    /// Checkpoints the container with CRIU. Unless it is left running, the container is stopped
+    /// afterwards and its exit is reported like any other. If the runtime fails, the end of
+    /// CRIU's log is added to the error, as runc only points to it.
+    pub async fn checkpoint(&self, runtime: &Runtime, options: &CheckpointOptions) -> Result<()> {
+        let _operation = self.operation.lock().await;
+        if !matches!(self.status().await, Status::RUNNING | Status::PAUSED) {
+            bail!(ContainerError::InvalidState(format!(
+                "Container {} is not running",
+                self.id
+            )));
+        }
+        let mut cmd = runtime.command(&self.options.runtime);
+        cmd.arg("checkpoint")
+            .arg("--image-path")
+            .arg(&options.image_path);
+        if let Some(work_path) = &options.work_path {
+            cmd.arg("--work-path").arg(work_path);
+        }
+        for (set, flag) in [
+            (options.leave_running, "--leave-running"),
+            (options.tcp_established, "--tcp-established"),
+            (options.external_unix_sockets, "--ext-unix-sk"),
+            (options.shell_job, "--shell-job"),
+            (options.file_locks, "--file-locks"),
+        ] {
+            if set {
+                cmd.arg(flag);
+            }
+        }
+        for namespace in &options.empty_namespaces {
+            cmd.arg("--empty-ns").arg(namespace);
+        }
+        if let Some(mode) = &options.cgroups_mode {
+            cmd.arg("--manage-cgroups-mode").arg(mode);
+        }
+        cmd.arg(&self.id);
+        cmd.stdout(Stdio::null()).stderr(Stdio::piped());
+        let Err(err) = runtime.run(cmd).await else {
+            return Ok(());
+        };
+        let log = options
+            .work_path
+            .as_ref()
+            .unwrap_or(&options.image_path)
+            .join(CRIU_DUMP_LOG);
+        Err(with_criu_log(err, &log))
+    }
+
+    /// Reads the container's current resource usage from its cgroup.
+    pub async fn stats(&self, cgroup_options: CgroupOptions) -> Result<Stats> {
+        let cgroup = cgroup::resolve(self.pid().await, cgroup_options)?
+            .context("Container cgroup could not be located")?;
+        task::spawn_blocking(move || cgroup.stats()).await?
+    }
+
+    /// Deletes the container from the runtime. If the runtime doesn't finish within
+    /// `delete_timeout` it is killed and the delete is retried with `--force`. If that times out
+    /// as well, an `Elapsed` error is returned and the next delete goes straight to `--force`.
+    /// With `force`, a container that is still running is killed and deleted as well; without it
+    /// a running or paused container is refused.
+    pub async fn delete(
+        &self,
+        runtime: &Runtime,
+        delete_timeout: Duration,
+        force: bool,
+    ) -> Result<()> {
+        let _operation = self.operation.lock().await;
+        let mut force = force || self.cleanup_pending.load(Ordering::SeqCst);
+        // A container that was created but never started has nothing running yet and can be
+        // deleted like a stopped one, e.g. after a failed start.
+        if !force && matches!(self.status().await, Status::RUNNING | Status::PAUSED) {
+            bail!(ContainerError::InvalidState(format!(
+                "Container {} is still running",
+                self.id
+            )));
+        }
+        loop {
+            let mut cmd = runtime.command(&self.options.runtime);
+            cmd.arg("delete");
+            if force {
+                cmd.arg("--force");
+            }
+            cmd.arg(&self.id);
+            cmd.stdout(Stdio::null()).stderr(Stdio::piped());
+            match timeout(delete_timeout, runtime.run(cmd)).await {
+                Ok(result) => {
+                    result?;
+                    break;
+                }
+                Err(_) if !force => {
+                    warn!(
+                        "Deleting container {} timed out after {:?}, retrying with --force",
+                        self.id, delete_timeout
+                    );
+                    force = true;
+                }
+                Err(elapsed) => {
+                    self.cleanup_pending.store(true, Ordering::SeqCst);
+                    error!(
+                        "Force deleting container {} timed out after {:?}, the runtime may be stuck on its mounts",
+                        self.id, delete_timeout
+                    );
+                    return Err(elapsed).context("OCI runtime is stuck deleting the container");
+                }
+            }
+        }
+        self.cleanup_pending.store(false, Ordering::SeqCst);
+        self.deleted.store(true, Ordering::SeqCst);
+        // The pids may be reused by now, so they must no longer be routed to the container.
+        self.pids.unregister(self.pid().await);
+        for exec in self.execs.read().await.values() {
+            self.pids.unregister(exec.process.pid().await);
+        }
+        let _ = fs::remove_file(self.bundle.join(EXIT_FILE));
+        let _ = fs::remove_file(self.bundle.join(STATE_FILE));
+        if !self.options.rootfs.is_empty() {
+            mount::unmount_all(&self.bundle.join(ROOTFS));
+        }
+        let _ = fs::remove_dir_all(self.bundle.join(PATCHED_BUNDLE));
+        Ok(())
+    }
+
+    /// Loads a container persisted by a previous daemon from its bundle. It is brought up to
+    /// date with the runtime by [`Container::recover`].
+    pub async fn load(bundle: &Path, pids: PidRegistry, events: Publisher) -> Result<Self> {
+        let path = bundle.join(STATE_FILE);
+        let contents = fs::read(&path).with_context(|| format!("Failed to read {:?}", path))?;
+        let state: PersistedState = serde_json::from_slice(&contents)
+            .with_context(|| format!("Invalid container state in {:?}", path))?;
+        let container = Self::new(
+            &state.id,
+            &state.bundle,
+            &state.stdout,
+            &state.stderr,
+            CreateOptions {
+                runtime: state.runtime,
+                rootfs: state.rootfs,
+                ..Default::default()
+            },
+            pids,
+            events,
+        );
+        container.adopted.store(true, Ordering::SeqCst);
+        container.init.set_pid_status(state.pid, state.status).await;
+        if let Some(exited_at) = state.exited_at {
+            let exited_at = OffsetDateTime::from_unix_timestamp_nanos(exited_at)?;
+            container
+                .init
+                .restore_exited(state.exit_status, exited_at)
+                .await;
+        }
+        Ok(container)
+    }
+
+    /// Brings a container loaded by [`Container::load`] up to date with `runtime state`. Its
+    /// output was copied by the previous daemon and can't be attached to again. Its processes
+    /// are no longer our children either, so their exits are only noticed by reconciling.
+    pub async fn recover(&self, runtime: &Runtime) -> Result<()> {
+        let _operation = self.operation.lock().await;
+        if self.status().await == Status::STOPPED {
+            return Ok(());
+        }
+        let state = runtime.state(&self.id, &self.options.runtime).await?;
+        let status = match state.status.as_str() {
+            "created" => Status::CREATED,
+            "running" => Status::RUNNING,
+            "paused" => Status::PAUSED,
+            "stopped" => {
+                warn!(
+                    "Container {} exited while no daemon was around to reap it",
+                    self.id
+                );
+                self.set_exited(UNKNOWN_EXIT_STATUS).await;
+                return Ok(());
+            }
+            status => bail!("Container {} is {} in the runtime", self.id, status),
+        };
+        let registered = self.pids.register(state.pid, &self.id, None);
+        self.init.set_pid_status(state.pid, status).await;
+        if !registered {
+            warn!(
+                "Container {} init process {} exited during recovery",
+                self.id, state.pid
+            );
+            self.set_exited(UNKNOWN_EXIT_STATUS).await;
+        }
+        self.save().await;
         Ok(())
     }
 
-    pub async fn delete(&self, runtime: &PathBuf) -> Result<()> {
-        let mut cmd = Command::new(runtime);
-        cmd.arg("delete").arg(&self.id);
-        cmd.stdin(Stdio::null())
-            .stdout(Stdio::null())
-            .stderr(Stdio::null());
-        let mut child = cmd.spawn().context("Failed to spawn OCI runtime")?;
-        match child.wait().await {
-            Ok(status) if status.success() => {}
-            Ok(status) => bail!("OCI runtime exited with status {}", status),
-            Err(err) => bail!("Failed to wait for OCI runtime: {}", err),
+    /// Updates the container's status and pid from `runtime state`, e.g. after a missed exit or
+    /// the container having been started by someone else. Exited containers are reaped. Returns
+    /// `None` if nothing changed.
+    pub async fn reconcile(&self, runtime: &Runtime) -> Result<Option<Reconciled>> {
+        let _operation = self.operation.lock().await;
+        let previous_status = self.status().await;
+        if !matches!(
+            previous_status,
+            Status::CREATED | Status::RUNNING | Status::PAUSED
+        ) {
+            return Ok(None);
+        }
+        let state = runtime.state(&self.id, &self.options.runtime).await?;
+        let previous_pid = self.pid().await;
+        if state.pid != 0 && state.pid != previous_pid {
+            warn!(
+                "Container {} pid changed from {} to {}",
+                self.id, previous_pid, state.pid
+            );
+            self.pids.unregister(previous_pid);
+            self.pids.register(state.pid, &self.id, None);
+            self.init.set_pid(state.pid).await;
+        }
+        match state.status.as_str() {
+            "running" | "paused" => {
+                if previous_status == Status::CREATED {
+                    *self.started_at.write().await = Some(OffsetDateTime::now_utc());
+                }
+                let status = if state.status == "paused" {
+                    Status::PAUSED
+                } else {
+                    Status::RUNNING
+                };
+                self.init.set_status(status).await;
+            }
+            "stopped" => {
+                // If the reaper got to the exit first, it reports the exit status itself.
+                if let Some(exit_status) = self.pids.reap_registered(previous_pid, &self.id, None) {
+                    warn!("Container {} exit was missed, reaped it now", self.id);
+                    self.set_exited(exit_status).await;
+                } else if self.adopted.load(Ordering::SeqCst) {
+                    // The reaper never sees the exits of a previous daemon's containers.
+                    warn!("Container {} exited with an unknown status", self.id);
+                    self.set_exited(UNKNOWN_EXIT_STATUS).await;
+                }
+            }
+            _ => {}
+        }
+        let status = if state.status == "stopped" {
+            Status::STOPPED
+        } else {
+            self.status().await
         };
+        let pid = self.pid().await;
+        if status == previous_status && pid == previous_pid {
+            return Ok(None);
+        }
+        self.save().await;
+        Ok(Some(Reconciled {
+            previous_status,
+            status,
+            pid,
+        }))
+    }
+
+    /// Signals the init process, or the exec'd process `exec_id` if it isn't empty.
+    pub async fn kill(&self, exec_id: &str, signal: Signal) -> Result<()> {
+        let _operation = self.operation.lock().await;
+        if self.deleted.load(Ordering::SeqCst) {
+            warn!(
+                "Container {} has been deleted, ignoring signal {}",
+                self.id, signal
+            );
+            return Ok(());
+        }
+        let process = self
+            .process(exec_id)
+            .await
+            .with_context(|| format!("Container {} has no exec {}", self.id, exec_id))?;
+        let pid = process.pid().await;
+        // kill(0) would signal our own process group.
+        if pid == 0 {
+            bail!(ContainerError::InvalidState(format!(
+                "Container {} has no pid yet",
+                self.id
+            )));
+        }
+        self.pids.signal(pid, signal)
+    }
+
+    /// Signals every process in the container's cgroup, including ones forked by the init
+    /// process that would outlive it. If the cgroup can't be located under the degrade policy,
+    /// only the init process is signalled.
+    pub async fn kill_all(&self, signal: Signal, cgroup_options: CgroupOptions) -> Result<()> {
+        let _operation = self.operation.lock().await;
+        if self.deleted.load(Ordering::SeqCst) {
+            warn!(
+                "Container {} has been deleted, ignoring signal {}",
+                self.id, signal
+            );
+            return Ok(());
+        }
+        let pid = self.pid().await;
+        if pid == 0 {
+            bail!(ContainerError::InvalidState(format!(
+                "Container {} has no pid yet",
+                self.id
+            )));
+        }
+        let Some(cgroup) = cgroup::resolve(pid, cgroup_options)? else {
+            return self.pids.signal(pid, signal);
+        };
+        for pid in task::spawn_blocking(move || cgroup.procs()).await?? {
+            // Processes in another pid namespace show up as 0, and kill(0) would signal our own
+            // process group.
+            if pid == 0 {
+                continue;
+            }
+            match kill(Pid::from_raw(pid), signal) {
+                // The process exited after the cgroup was read.
+                Ok(()) | Err(nix::Error::ESRCH) => {}
+                Err(err) => bail!("Failed to signal process {}: {}", pid, err),
+            }
+        }
+        Ok(())
+    }
+
+    /// Stops the init process with SIGTERM, escalating to SIGKILL if it hasn't exited within
+    /// `grace_period`. Does nothing if it has already stopped.
+    pub async fn stop(&self, grace_period: Duration) -> Result<()> {
+        if matches!(self.status().await, Status::UNKNOWN | Status::STOPPED) {
+            return Ok(());
+        }
+        self.kill("", Signal::SIGTERM).await?;
+        if timeout(grace_period, self.init.wait()).await.is_ok() {
+            return Ok(());
+        }
+        warn!(
+            "Container {} did not exit within {:?} of SIGTERM, sending SIGKILL",
+            self.id, grace_period
+        );
+        self.kill("", Signal::SIGKILL).await?;
+        // SIGKILL can't be ignored, but the exit still has to be reaped.
+        let _ = timeout(KILL_TIMEOUT, self.init.wait()).await;
+        Ok(())
+    }
+
+    /// Returns the init process if `exec_id` is empty, otherwise the exec'd process with that ID.
+    pub async fn process(&self, exec_id: &str) -> Option<Arc<Process>> {
+        if exec_id.is_empty() {
+            return Some(self.init.clone());
+        }
+        self.exec(exec_id).await.map(|exec| exec.process.clone())
+    }
+
+    pub async fn exec(&self, exec_id: &str) -> Option<Arc<Exec>> {
+        self.execs.read().await.get(exec_id).cloned()
+    }
+
+    /// Registers a process to be started in the running container with [`Container::start_exec`].
+    /// `spec` is the JSON encoded OCI process spec. `stdin` is copied into the process' stdin
+    /// like the container's stdin path, and empty for none. Empty output paths discard the
+    /// output.
+    pub async fn add_exec(
+        &self,
+        exec_id: &str,
+        spec: &[u8],
+        stdin: &Path,
+        stdout: &Path,
+        stderr: &Path,
+    ) -> Result<()> {
+        if self.status().await != Status::RUNNING {
+            bail!(ContainerError::InvalidState(format!(
+                "Container {} is not running",
+                self.id
+            )));
+        }
+        let mut execs = self.execs.write().await;
+        if execs.contains_key(exec_id) {
+            bail!(ContainerError::AlreadyExists(format!(
+                "Container {} already has exec {}",
+                self.id, exec_id
+            )));
+        }
+        let path = self.bundle.join(format!("{}{}.json", EXEC_PREFIX, exec_id));
+        fs::write(&path, spec).with_context(|| format!("Failed to write {:?}", path))?;
+        let exec = Exec::new(
+            exec_id,
+            stdin.to_owned(),
+            stdout.to_owned(),
+            stderr.to_owned(),
+            path,
+        );
+        execs.insert(exec_id.to_string(), Arc::new(exec));
         Ok(())
     }
 
-    pub async fn kill(&self, signal: Signal) -> Result<()> {
-        let pid = Pid::from_raw(*self.pid.read().await);
-        forward_signal(pid, signal)?;
+    /// Starts a registered exec'd process with `runtime exec`.
+    pub async fn start_exec(
+        &self,
+        runtime: &Runtime,
+        exec_id: &str,
+        log_options: LogOptions,
+    ) -> Result<()> {
+        let _operation = self.operation.lock().await;
+        let exec = self
+            .exec(exec_id)
+            .await
+            .with_context(|| format!("Container {} has no exec {}", self.id, exec_id))?;
+        // A concurrent start may have won the race for the lock.
+        if exec.process.status().await != Status::CREATED {
+            bail!(ContainerError::InvalidState(format!(
+                "Exec {} of container {} is not created",
+                exec_id, self.id
+            )));
+        }
+        if self.status().await != Status::RUNNING {
+            bail!(ContainerError::InvalidState(format!(
+                "Container {} is not running",
+                self.id
+            )));
+        }
+        let stdout = self
+            .monitor_stdio(
+                &exec.stdout,
+                &exec.stdout_monitor,
+                Stream::Stdout,
+                &log_options,
+            )
+            .await?;
+        let stderr = self
+            .monitor_stdio(
+                &exec.stderr,
+                &exec.stderr_monitor,
+                Stream::Stderr,
+                &log_options,
+            )
+            .await?;
+        let stdin = if exec.stdin.as_os_str().is_empty() {
+            None
+        } else {
+            let (reader, writer) = pipe2(OFlag::O_CLOEXEC).context("Failed to create pipe")?;
+            *exec.stdin_pipe.lock().await = Some(pipe::Sender::from_owned_fd(writer)?);
+            Some(reader)
+        };
+        let mut cmd = runtime.command(&self.options.runtime);
+        cmd.stdout(stdio(&stdout)?).stderr(stdio(&stderr)?);
+        if let Some(stdin) = stdin {
+            cmd.stdin(stdin);
+        }
+        let log = self.bundle.join(RUNTIME_LOG);
+        let pid_file = self.bundle.join(format!("{}{}.pid", EXEC_PREFIX, exec_id));
+        let _ = fs::remove_file(&log);
+        cmd.arg("--log")
+            .arg(&log)
+            .arg("--log-format")
+            .arg("json")
+            .arg("exec")
+            .arg("--detach")
+            .arg("--process")
+            .arg(&exec.spec)
+            .arg("--pid-file")
+            .arg(&pid_file)
+            .arg(&self.id);
+        if let Err(err) = runtime.run(cmd).await {
+            *exec.stdin_pipe.lock().await = None;
+            let errors = runtime::log_errors(&log);
+            if !errors.is_empty() {
+                return Err(err.context(errors.join("; ")));
+            }
+            return Err(err);
+        }
+        if !exec.stdin.as_os_str().is_empty() {
+            tokio::spawn(copy_stdin(
+                format!("exec {} of container {}", exec_id, self.id),
+                exec.stdin.clone(),
+                exec.stdin_pipe.clone(),
+                exec.stdin_closed.subscribe(),
+            ));
+        }
+        let pid = read_pid(&pid_file).await?;
+        let _ = fs::remove_file(&pid_file);
+        exec.process.set_pid_status(pid, Status::RUNNING).await;
+        let registered = self.pids.register(pid, &self.id, Some(exec_id));
+        // Like the init process at create, the process may have exited and been reaped before
+        // its pid was known.
+        if !registered {
+            warn!(
+                "Exec {} of container {} exited before its pid {} was read",
+                exec_id, self.id, pid
+            );
+            exec.process
+                .set_exited(UNKNOWN_EXIT_STATUS, |_, _| {})
+                .await;
+            self.publish_exit(&exec.id, &exec.process).await;
+        }
         Ok(())
     }
 
-    pub async fn wait_channel(&self) -> mpsc::UnboundedReceiver<()> {
-        let (tx, rx) = mpsc::unbounded_channel();
-        // keep this guard so that the status is not changed while adding the channel
-        let status_guard = self.status.read().await;
-        if *status_guard != Status::STOPPED {
-            self.wait_channels.write().await.push(tx);
+    /// Deletes the container from the runtime whatever its state, killing its processes.
+    async fn force_delete(&self, runtime: &Runtime) -> Result<()> {
+        let mut cmd = runtime.command(&self.options.runtime);
+        cmd.arg("delete").arg("--force").arg(&self.id);
+        cmd.stdout(Stdio::null()).stderr(Stdio::piped());
+        runtime.run(cmd).await
+    }
+
+    /// Starts monitoring a stdout or stderr path, returning the write end of the pipe to give the
+    /// process. Output to an empty path is discarded, so there is nothing to monitor.
+    async fn monitor_stdio(
+        &self,
+        path: &Path,
+        monitor: &RwLock<Option<Monitor>>,
+        stream: Stream,
+        log_options: &LogOptions,
+    ) -> Result<Option<OwnedFd>> {
+        if path.as_os_str().is_empty() {
+            return Ok(None);
         }
-        rx
+        let (stdio_monitor, stdio) =
+            Monitor::spawn(self.open_stdio(path)?, path, stream, log_options.clone())?;
+        *monitor.write().await = Some(stdio_monitor);
+        Ok(Some(stdio))
     }
 
-    pub async fn set_exited(&self, exit_code: i32) {
-        let mut status_guard = self.status.write().await;
-        let mut exit_code_guard = self.exit_code.write().await;
-        let mut exited_at_guard = self.exited_at.write().await;
-        *status_guard = Status::STOPPED;
-        *exit_code_guard = exit_code;
-        *exited_at_guard = Some(OffsetDateTime::now_utc());
-        for tx in self.wait_channels.write().await.drain(..) {
-            let _ = tx.send(());
+    /// Removes an exec'd process that isn't running, writing out its remaining output first.
+    pub async fn delete_exec(&self, exec_id: &str, drain_timeout: Duration) -> Result<Arc<Exec>> {
+        let _operation = self.operation.lock().await;
+        let exec = {
+            let mut execs = self.execs.write().await;
+            match execs.get(exec_id) {
+                Some(exec) if exec.process.status().await == Status::RUNNING => {
+                    bail!(ContainerError::InvalidState(format!(
+                        "Exec {} of container {} is running",
+                        exec_id, self.id
+                    )))
+                }
+                Some(_) => execs.remove(exec_id).expect("exec was just found"),
+                None => bail!(ContainerError::NotFound(format!(
+                    "Container {} has no exec {}",
+                    self.id, exec_id
+                ))),
+            }
+        };
+        for monitor in [&exec.stdout_monitor, &exec.stderr_monitor] {
+            if let Some(monitor) = monitor.read().await.as_ref() {
+                monitor.drain(drain_timeout).await;
+            }
+        }
+        let _ = fs::remove_file(&exec.spec);
+        self.pids.unregister(exec.process.pid().await);
+        Ok(exec)
+    }
+
+    /// Lists the processes in the container, with the exec ID of those started with exec. A
+    /// stopped container has no processes.
+    pub async fn pids(&self, runtime: &Runtime) -> Result<Vec<(i32, Option<String>)>> {
+        if self.status().await == Status::STOPPED {
+            return Ok(Vec::new());
+        }
+        let pids = runtime.ps(&self.id, &self.options.runtime).await?;
+        let mut exec_ids = HashMap::new();
+        for exec in self.execs.read().await.values() {
+            exec_ids.insert(exec.process.pid().await, exec.id.clone());
+        }
+        Ok(pids
+            .into_iter()
+            .map(|pid| (pid, exec_ids.get(&pid).cloned()))
+            .collect())
+    }
+
+    /// Records the exit of `pid`, the init process or the exec'd process `exec_id` as looked up
+    /// in the [`PidRegistry`], once its output has been written. Exits of pids the process
+    /// doesn't have are ignored.
+    pub async fn reap(&self, exec_id: Option<&str>, pid: i32, exit_status: ExitStatus) {
+        let Some(exec_id) = exec_id else {
+            let current = self.pid().await;
+            if current != pid {
+                warn!(
+                    "Ignoring exit of pid {} of container {}, whose pid is {}",
+                    pid, self.id, current
+                );
+                return;
+            }
+            self.set_exited(exit_status).await;
+            return;
+        };
+        let Some(exec) = self.exec(exec_id).await else {
+            return;
+        };
+        let current = exec.process.pid().await;
+        if current != pid {
+            warn!(
+                "Ignoring exit of pid {} of exec {} of container {}, whose pid is {}",
+                pid, exec_id, self.id, current
+            );
+            return;
+        }
+        let process = format!("exec {} of container {}", exec.id, self.id);
+        for monitor in [&exec.stdout_monitor, &exec.stderr_monitor] {
+            if let Some(monitor) = monitor.read().await.as_ref() {
+                monitor.drain_exited(&process).await;
+            }
+        }
+        // Stops copying into the stdin of a process that can no longer read it.
+        exec.close_stdin().await;
+        exec.process.set_exited(exit_status, |_, _| {}).await;
+        self.publish_exit(&exec.id, &exec.process).await;
+        info!(
+            "Exec {} of container {} exited with {:?}",
+            exec.id, self.id, exit_status
+        );
+    }
+
+    pub async fn set_exited(&self, exit_status: ExitStatus) {
+        // Waiters expect the output to be complete once they hear about the exit.
+        let process = format!("container {}", self.id);
+        for monitor in [&self.stdout_monitor, &self.stderr_monitor] {
+            if let Some(monitor) = monitor.read().await.as_ref() {
+                monitor.drain_exited(&process).await;
+            }
+        }
+        // Stopped first, so that an OOM kill is reported before the exit it caused.
+        if let Some(watcher) = self.oom_watcher.lock().await.take() {
+            watcher.stop().await;
+        }
+        self.init
+            .set_exited(exit_status, |exit_status, exited_at| {
+                let record = ExitRecord {
+                    exit_status,
+                    exited_at: exited_at.unix_timestamp_nanos(),
+                };
+                if let Err(err) = write_exit_record(&self.bundle, &record) {
+                    warn!("Failed to record exit of container {}: {:#}", self.id, err);
+                }
+            })
+            .await;
+        self.publish_exit(&self.id, &self.init).await;
+        info!(
+            "Container {} exited with {:?}, time to running: {:?}, time to exit: {:?}",
+            self.id,
+            exit_status,
+            self.time_to_running().await,
+            self.time_to_exit().await
+        );
+        self.save().await;
+    }
+
+    /// Publishes the exit of the init process or of an exec'd process, `id` being its exec ID.
+    async fn publish_exit(&self, id: &str, process: &Process) {
+        self.events.task_exit(
+            &self.id,
+            id,
+            process.pid().await,
+            process.exit_status().await,
+            process.exited_at().await,
+        );
+    }
+
+    /// Persists the container's state in its bundle, see [`Container::load`].
+    async fn save(&self) {
+        // Held while reading the state too, so that an older state can't overwrite a newer one.
+        let _persist = self.persist.lock().await;
+        let state = PersistedState {
+            id: self.id.clone(),
+            bundle: self.bundle.clone(),
+            stdout: self.stdout.clone(),
+            stderr: self.stderr.clone(),
+            pid: self.pid().await,
+            status: self.status().await,
+            exit_status: self.exit_status().await,
+            exited_at: self
+                .init
+                .exit_time()
+                .await
+                .map(OffsetDateTime::unix_timestamp_nanos),
+            runtime: self.options.runtime.clone(),
+            rootfs: self.options.rootfs.clone(),
+        };
+        let result = serde_json::to_vec(&state)
+            .map_err(Into::into)
+            .and_then(|contents| write_atomic(&self.bundle.join(STATE_FILE), &contents));
+        if let Err(err) = result {
+            warn!("Failed to save state of container {}: {:#}", self.id, err);
         }
     }
 
     pub async fn exited_at(&self) -> Option<Timestamp> {
-        self.exited_at.read().await.map(|exited_at| Timestamp {
-            seconds: exited_at.unix_timestamp(),
-            nanos: exited_at.nanosecond() as i32,
-        })
+        self.init.exited_at().await
+    }
+
+    /// Returns the resource limits of the init process, from the spec at create.
+    pub async fn rlimits(&self) -> Vec<Rlimit> {
+        self.rlimits.read().await.clone()
+    }
+
+    /// Returns how long the container took from being created to running.
+    pub async fn time_to_running(&self) -> Option<Duration> {
+        let created_at = (*self.created_at.read().await)?;
+        let started_at = (*self.started_at.read().await)?;
+        (started_at - created_at).try_into().ok()
+    }
+
+    /// Returns how long the container ran before exiting.
+    pub async fn time_to_exit(&self) -> Option<Duration> {
+        let started_at = (*self.started_at.read().await)?;
+        let exited_at = self.init.exit_time().await?;
+        (exited_at - started_at).try_into().ok()
+    }
+
+    /// Returns size information about the current stdout and stderr log files.
+    pub async fn log_stats(&self) -> (LogStats, LogStats) {
+        let stdout = self.stdout_monitor.read().await.as_ref().map(Monitor::path);
+        let stderr = self.stderr_monitor.read().await.as_ref().map(Monitor::path);
+        (
+            log_stats(stdout.as_deref().unwrap_or(&self.stdout)),
+            log_stats(stderr.as_deref().unwrap_or(&self.stderr)),
+        )
     }
 
     pub async fn pid(&self) -> i32 {
-        *self.pid.read().await
+        self.init.pid().await
     }
 
     pub async fn status(&self) -> Status {
-        *self.status.read().await
+        self.init.status().await
+    }
+
+    pub async fn exit_status(&self) -> ExitStatus {
+        self.init.exit_status().await
+    }
+}
+
+fn write_exit_record(bundle: &Path, record: &ExitRecord) -> Result<()> {
+    write_atomic(&bundle.join(EXIT_FILE), &serde_json::to_vec(record)?)
+}
+
+/// Reads a pid file written by the runtime. It may not have been written yet when the runtime
+/// returns on a loaded host, so it is polled for up to [`PID_FILE_TIMEOUT`].
+async fn read_pid<P: AsRef<Path>>(path: P) -> Result<i32> {
+    let path = path.as_ref();
+    let deadline = Instant::now() + PID_FILE_TIMEOUT;
+    loop {
+        let contents = match read_pid_file(path) {
+            Ok(contents) => contents,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => String::new(),
+            Err(err) => {
+                return Err(refuse_symlink(err, path))
+                    .with_context(|| format!("Failed to read {:?}", path))
+            }
+        };
+        let contents = contents.trim();
+        if let Ok(pid) = contents.parse() {
+            return Ok(pid);
+        }
+        if Instant::now() >= deadline {
+            if contents.is_empty() {
+                bail!(
+                    "Pid file {:?} was not written within {:?}",
+                    path,
+                    PID_FILE_TIMEOUT
+                );
+            }
+            bail!("Invalid pid {:?} in {:?}", contents, path);
+        }
+        sleep(PID_FILE_POLL_INTERVAL).await;
     }
+}
 
-    pub async fn exit_code(&self) -> i32 {
-        *self.exit_code.read().await
+/// Stats a log file and its rotations, `<path>.1`, `<path>.2` and so on. Only regular files count.
+fn log_stats(path: &Path) -> LogStats {
+    let size = |path: &Path| {
+        fs::metadata(path)
+            .ok()
+            .filter(|metadata| metadata.is_file())
+            .map(|metadata| metadata.len())
+    };
+    let mut stats = LogStats {
+        size: size(path).unwrap_or(0),
+        ..Default::default()
+    };
+    stats.total_size = stats.size;
+    while let Some(size) = size(&rotated_path(path, stats.rotations + 1)) {
+        stats.rotations += 1;
+        stats.total_size += size;
     }
+    stats
 }
 
-fn read_pid<P: AsRef<Path>>(path: P) -> Result<i32> {
-    let contents = fs::read_to_string(path)?;
-    Ok(contents.parse()?)
+/// Adds the end of CRIU's log to the error of a failed runtime invocation, as runc only points to
+/// the log.
+fn with_criu_log(err: anyhow::Error, log: &Path) -> anyhow::Error {
+    match (err.downcast_ref::<ContainerError>(), log_tail(log)) {
+        (Some(ContainerError::RuntimeFailed(message)), Some(tail)) => {
+            anyhow!(ContainerError::RuntimeFailed(format!(
+                "{}\nCRIU log {:?} ends with:\n{}",
+                message, log, tail
+            )))
+        }
+        _ => err,
+    }
 }
 
-fn stdio_file<P: AsRef<Path>>(path: P) -> Result<File> {
-    let file = OpenOptions::new()
+/// Returns the last [`CRIU_LOG_LINES`] lines of a log, if it exists and isn't empty.
+fn log_tail(path: &Path) -> Option<String> {
+    let contents = fs::read_to_string(path).ok()?;
+    let lines: Vec<_> = contents.lines().collect();
+    let tail = lines[lines.len().saturating_sub(CRIU_LOG_LINES)..].join("\n");
+    (!tail.is_empty()).then_some(tail)
+}
+
+/// Copies the stdin path into the stdin of `process`, the container or one of its exec'd
+/// processes, until the path reaches EOF, then closes stdin. Stops early once stdin has been
+/// closed, e.g. with CloseIO, even while waiting for the path or the process, or once the
+/// process closed its end of the pipe.
+async fn copy_stdin(
+    process: String,
+    path: PathBuf,
+    stdin: Arc<Mutex<Option<pipe::Sender>>>,
+    mut closed: watch::Receiver<bool>,
+) {
+    // Opening a named pipe waits for its writer.
+    let mut file = match tokio::fs::File::open(&path).await {
+        Ok(file) => file,
+        Err(err) => {
+            warn!("Failed to open stdin {:?} of {}: {}", path, process, err);
+            *stdin.lock().await = None;
+            return;
+        }
+    };
+    let mut buffer = vec![0; STDIN_BUFFER_SIZE];
+    loop {
+        let read = tokio::select! {
+            read = file.read(&mut buffer) => read,
+            _ = closed.wait_for(|closed| *closed) => return,
+        };
+        let read = match read {
+            Ok(0) => break,
+            Ok(read) => read,
+            Err(err) => {
+                warn!("Failed to read stdin {:?} of {}: {}", path, process, err);
+                break;
+            }
+        };
+        let mut stdin = stdin.lock().await;
+        let Some(pipe) = stdin.as_mut() else {
+            return;
+        };
+        // Waits for the container to read while the pipe is full.
+        let written = tokio::select! {
+            written = pipe.write_all(&buffer[..read]) => Some(written),
+            _ = closed.wait_for(|closed| *closed) => None,
+        };
+        match written {
+            Some(Ok(())) => {}
+            Some(Err(err)) if err.kind() == io::ErrorKind::BrokenPipe => {
+                debug!("The stdin of {} was closed by the process", process);
+                *stdin = None;
+                return;
+            }
+            Some(Err(err)) => {
+                warn!("Failed to write stdin of {}: {}", process, err);
+                *stdin = None;
+                return;
+            }
+            None => {
+                *stdin = None;
+                return;
+            }
+        }
+    }
+    *stdin.lock().await = None;
+}
+
+/// Returns a copy of a pipe end for a runtime invocation, or null to discard output.
+fn stdio(fd: &Option<OwnedFd>) -> Result<Stdio> {
+    Ok(match fd {
+        Some(fd) => fd.try_clone()?.into(),
+        None => Stdio::null(),
+    })
+}
+
+/// The socket the runtime sends the master end of a container's pty to. It lives in its own
+/// temporary directory, removed on drop, as bundle paths can exceed the socket path limit.
+struct ConsoleSocket {
+    listener: UnixListener,
+    dir: PathBuf,
+}
+
+impl ConsoleSocket {
+    fn bind() -> Result<Self> {
+        let dir = mkdtemp(&env::temp_dir().join("shim-console-XXXXXX"))
+            .context("Failed to create console socket directory")?;
+        match UnixListener::bind(dir.join(CONSOLE_SOCKET)) {
+            Ok(listener) => Ok(Self { listener, dir }),
+            Err(err) => {
+                let _ = fs::remove_dir_all(&dir);
+                Err(err).context("Failed to bind console socket")
+            }
+        }
+    }
+
+    fn path(&self) -> PathBuf {
+        self.dir.join(CONSOLE_SOCKET)
+    }
+
+    /// Receives the pty master, which the runtime has sent by the time create returns.
+    fn receive(&self) -> Result<OwnedFd> {
+        self.listener
+            .set_nonblocking(true)
+            .context("Failed to set console socket non-blocking")?;
+        let (stream, _) = self
+            .listener
+            .accept()
+            .context("OCI runtime did not send a terminal")?;
+        recv_fd(stream.as_fd())
+    }
+}
+
+impl Drop for ConsoleSocket {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.dir);
+    }
+}
+
+/// The socket the runtime hands a container's seccomp notify fd over, at the spec's listener
+/// path. It is removed on drop.
+struct SeccompListener {
+    listener: UnixListener,
+    path: PathBuf,
+}
+
+impl SeccompListener {
+    /// Binds the socket. A socket already at `path`, e.g. left behind by a crashed shim, is
+    /// replaced, but anything else is refused rather than removed, as the path comes from the
+    /// bundle and the shim usually runs as root.
+    fn bind(path: &str) -> Result<Self> {
+        let path = PathBuf::from(path);
+        match fs::symlink_metadata(&path) {
+            Ok(metadata) if metadata.file_type().is_socket() => fs::remove_file(&path)?,
+            Ok(_) => bail!("{:?} exists and is not a socket", path),
+            Err(err) if err.kind() == io::ErrorKind::NotFound => {}
+            Err(err) => return Err(err.into()),
+        }
+        let listener = UnixListener::bind(&path)?;
+        let listener = Self { listener, path };
+        listener.listener.set_nonblocking(true)?;
+        Ok(listener)
+    }
+}
+
+impl Drop for SeccompListener {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.path);
+    }
+}
+
+/// Reads a pid file without following a symlink, which a container could plant in its bundle.
+fn read_pid_file(path: &Path) -> io::Result<String> {
+    let mut contents = String::new();
+    OpenOptions::new()
+        .read(true)
+        .custom_flags(OFlag::O_NOFOLLOW.bits())
+        .open(path)?
+        .read_to_string(&mut contents)?;
+    Ok(contents)
+}
+
+/// Creates or truncates a stdio file. A symlink is refused rather than followed, as the shim
+/// usually runs as root and would otherwise overwrite whatever file it points to.
+fn stdio_file(path: &Path) -> Result<File> {
+    OpenOptions::new()
         .create(true)
         .truncate(true)
         .write(true)
-        .open(path)?;
+        .custom_flags(OFlag::O_NOFOLLOW.bits())
+        .open(path)
+        .map_err(|err| refuse_symlink(err, path))
+}
+
+/// Explains the ELOOP an O_NOFOLLOW open fails with on a symlink.
+fn refuse_symlink(err: io::Error, path: &Path) -> anyhow::Error {
+    if err.raw_os_error() == Some(nix::libc::ELOOP) {
+        anyhow!("{:?} is a symlink, refusing to follow it", path)
+    } else {
+        err.into()
+    }
+}
+
+/// Opens a named pipe for writing, creating it if needed. If no reader is connected yet the pipe
+/// is opened read-write, which doesn't block, so that output is buffered until one connects. It
+/// stays non-blocking, the monitor writing it never waits on the reader for long.
+fn open_fifo(path: &Path) -> Result<File> {
+    match mkfifo(path, Mode::from_bits_truncate(0o600)) {
+        Ok(()) | Err(nix::Error::EEXIST) => {}
+        Err(err) => return Err(err.into()),
+    }
+    let flags = OFlag::O_NONBLOCK | OFlag::O_NOFOLLOW;
+    let file = match OpenOptions::new()
+        .write(true)
+        .custom_flags(flags.bits())
+        .open(path)
+    {
+        Ok(file) => file,
+        Err(err) if err.raw_os_error() == Some(nix::libc::ENXIO) => OpenOptions::new()
+            .read(true)
+            .write(true)
+            .custom_flags(flags.bits())
+            .open(path)
+            .map_err(|err| refuse_symlink(err, path))?,
+        Err(err) => return Err(refuse_symlink(err, path)),
+    };
     Ok(file)
 }
+
+#[cfg(test)]
+mod tests {
+    use std::{io::Write, sync::atomic::AtomicUsize};
+
+    use tokio::signal::unix::{signal, SignalKind};
+
+    use super::*;
+    use crate::testing::{exec_spec, wait_until, Fixture, TIMEOUT};
+
+    #[tokio::test]
+    async fn transient_create_failure_is_retried() {
+        let fixture = Fixture::with_args(&["--create-retries", "2"]);
+        let container = fixture.container("retry", "sleep 30", CreateOptions::default());
+        fixture.fail_create("open /dev/fuse: device or resource busy");
+        fixture.create(&container).await.unwrap();
+        assert_eq!(fixture.invocations("create", &container), 2);
+        assert_eq!(container.status().await, Status::CREATED);
+    }
+
+    #[tokio::test]
+    async fn transient_create_failure_is_retried_a_limited_number_of_times() {
+        let fixture = Fixture::with_args(&["--create-retries", "2"]);
+        let container = fixture.container("retry", "sleep 30", CreateOptions::default());
+        for _ in 0..3 {
+            fixture.fail_create("open /dev/fuse: device or resource busy");
+        }
+        let err = fixture.create(&container).await.unwrap_err();
+        assert!(format!("{:#}", err).contains("device or resource busy"));
+        assert_eq!(fixture.invocations("create", &container), 3);
+        assert_eq!(container.status().await, Status::UNKNOWN);
+    }
+
+    #[tokio::test]
+    async fn permanent_create_failure_is_not_retried() {
+        let fixture = Fixture::with_args(&["--create-retries", "2"]);
+        let container = fixture.container("retry", "sleep 30", CreateOptions::default());
+        fixture.fail_create("exec /bin/app: exec format error");
+        let err = fixture.create(&container).await.unwrap_err();
+        assert!(format!("{:#}", err).contains("exec format error"));
+        assert_eq!(fixture.invocations("create", &container), 1);
+        assert_eq!(container.status().await, Status::UNKNOWN);
+    }
+
+    #[test]
+    fn seccomp_listener_only_replaces_a_socket() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("seccomp.sock");
+        // The stale socket of a crashed shim is replaced.
+        drop(UnixListener::bind(&path).unwrap());
+        let listener = SeccompListener::bind(path.to_str().unwrap()).unwrap();
+        drop(listener);
+        assert!(!path.exists());
+
+        let file = dir.path().join("file");
+        fs::write(&file, "keep").unwrap();
+        assert!(SeccompListener::bind(file.to_str().unwrap()).is_err());
+        assert_eq!(fs::read_to_string(&file).unwrap(), "keep");
+    }
+
+    #[tokio::test]
+    async fn seccomp_listener_is_removed_when_create_fails() {
+        let fixture = Fixture::with_args(&["--create-retries", "1"]);
+        let container = fixture.container("seccomp", "sleep 30", CreateOptions::default());
+        let path = container.bundle.join("seccomp.sock");
+        let spec = format!(r#"{{"linux":{{"seccomp":{{"listenerPath":{:?}}}}}}}"#, path);
+        fs::write(container.bundle.join("config.json"), spec).unwrap();
+        fixture.fail_create("open /dev/fuse: device or resource busy");
+        fixture.fail_create("exec /bin/app: exec format error");
+        fixture.create(&container).await.unwrap_err();
+        assert_eq!(fixture.invocations("create", &container), 2);
+        assert!(!path.exists());
+    }
+
+    #[tokio::test]
+    async fn kill_before_create_never_signals_pid_0() {
+        let fixture = Fixture::new();
+        let container = fixture.container("kill", "sleep 30", CreateOptions::default());
+        // kill(0) would deliver it to the test's own process group.
+        let mut signals = signal(SignalKind::user_defined2()).unwrap();
+        let err = container.kill("", Signal::SIGUSR2).await.unwrap_err();
+        assert!(matches!(
+            err.downcast_ref(),
+            Some(ContainerError::InvalidState(_))
+        ));
+        let err = container
+            .kill_all(Signal::SIGUSR2, fixture.config.cgroup_options())
+            .await
+            .unwrap_err();
+        assert!(matches!(
+            err.downcast_ref(),
+            Some(ContainerError::InvalidState(_))
+        ));
+        assert!(timeout(Duration::from_millis(200), signals.recv())
+            .await
+            .is_err());
+    }
+
+    #[tokio::test]
+    async fn forced_delete_of_running_container_succeeds() {
+        let fixture = Fixture::new();
+        let container = fixture.container("delete", "sleep 30", CreateOptions::default());
+        fixture.run(&container).await.unwrap();
+        assert_eq!(container.status().await, Status::RUNNING);
+        let pid = Pid::from_raw(container.pid().await);
+        let err = container
+            .delete(&fixture.runtime, Duration::from_secs(10), false)
+            .await
+            .unwrap_err();
+        assert!(matches!(
+            err.downcast_ref(),
+            Some(ContainerError::InvalidState(_))
+        ));
+        container
+            .delete(&fixture.runtime, Duration::from_secs(10), true)
+            .await
+            .unwrap();
+        assert!(container.deleted.load(Ordering::SeqCst));
+        assert!(fixture
+            .runtime
+            .state(&container.id, &container.options.runtime)
+            .await
+            .is_err());
+        assert!(wait_until(|| kill(pid, None) == Err(nix::Error::ESRCH)).await);
+    }
+
+    #[tokio::test]
+    async fn checkpointed_container_is_restored_running() {
+        let fixture = Fixture::new();
+        let container = fixture.container("checkpoint", "exec sleep 30", CreateOptions::default());
+        fixture.run(&container).await.unwrap();
+        let image_path = fixture.dir.path().join("image");
+        let options = CheckpointOptions {
+            image_path: image_path.clone(),
+            ..Default::default()
+        };
+        container
+            .checkpoint(&fixture.runtime, &options)
+            .await
+            .unwrap();
+        // Without --leave-running the runtime stops the container once it is checkpointed.
+        let exit = timeout(TIMEOUT, container.init.wait()).await.unwrap();
+        assert_eq!(
+            exit.exit_status,
+            ExitStatus::Signaled(Signal::SIGKILL as i32)
+        );
+        container
+            .delete(&fixture.runtime, Duration::from_secs(10), false)
+            .await
+            .unwrap();
+
+        let restored = fixture.container(
+            "restore",
+            "echo restored; exec sleep 30",
+            CreateOptions {
+                checkpoint: Some(image_path),
+                ..Default::default()
+            },
+        );
+        fixture.create(&restored).await.unwrap();
+        assert_eq!(fixture.invocations("restore", &restored), 1);
+        assert_eq!(restored.status().await, Status::RUNNING);
+        assert!(restored.time_to_running().await.is_some());
+        let pid = Pid::from_raw(restored.pid().await);
+        assert!(kill(pid, None).is_ok());
+        assert!(
+            wait_until(
+                || fs::read_to_string(&restored.stdout).is_ok_and(|stdout| stdout == "restored\n")
+            )
+            .await
+        );
+        // It is already running, so it can't be started again.
+        assert!(restored
+            .start(&fixture.runtime, fixture.config.cgroup_options())
+            .await
+            .is_err());
+    }
+
+    #[tokio::test]
+    async fn close_stdin_mid_stream_gives_the_container_eof() {
+        let fixture = Fixture::new();
+        let stdin_path = fixture.dir.path().join("stdin");
+        mkfifo(&stdin_path, Mode::from_bits_truncate(0o600)).unwrap();
+        let container = fixture.container(
+            "stdin",
+            "wc -c",
+            CreateOptions {
+                stdin_path: stdin_path.clone(),
+                ..Default::default()
+            },
+        );
+        fixture.run(&container).await.unwrap();
+        // Writes until the copy stops reading, far more than the pipes hold.
+        let written = Arc::new(AtomicUsize::new(0));
+        let writer = {
+            let written = written.clone();
+            std::thread::spawn(move || {
+                let mut fifo = OpenOptions::new().write(true).open(stdin_path).unwrap();
+                let chunk = vec![b'x'; 64 * 1024];
+                while fifo.write_all(&chunk).is_ok() {
+                    written.fetch_add(chunk.len(), Ordering::SeqCst);
+                }
+            })
+        };
+        assert!(wait_until(|| written.load(Ordering::SeqCst) > 1024 * 1024).await);
+        container.close_stdin().await;
+        let exit = timeout(TIMEOUT, container.init.wait())
+            .await
+            .expect("the container did not see EOF");
+        assert_eq!(exit.exit_status, ExitStatus::Exited(0));
+        // The copy stopped, so the writer sees its reader gone.
+        writer.join().unwrap();
+        let read: usize = fs::read_to_string(&container.stdout)
+            .unwrap()
+            .trim()
+            .parse()
+            .unwrap();
+        assert!(read > 0);
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn concurrent_kill_and_delete_are_serialized() {
+        let fixture = Fixture::new();
+        for _ in 0..10 {
+            let container = fixture.container("race", "exec sleep 30", CreateOptions::default());
+            fixture.run(&container).await.unwrap();
+            let pid = container.pid().await;
+            let killer = container.clone();
+            let killed = tokio::spawn(async move { killer.kill("", Signal::SIGTERM).await });
+            let deleted = container.delete(&fixture.runtime, Duration::from_secs(10), true);
+            let (killed, deleted) = tokio::join!(killed, deleted);
+            killed.unwrap().unwrap();
+            deleted.unwrap();
+            assert!(container.deleted.load(Ordering::SeqCst));
+            let pid = Pid::from_raw(pid);
+            assert!(wait_until(|| kill(pid, None) == Err(nix::Error::ESRCH)).await);
+            // The pid may be reused by now, so a kill after the delete must not signal it.
+            container.kill("", Signal::SIGKILL).await.unwrap();
+        }
+    }
+
+    #[tokio::test]
+    async fn concurrent_update_and_delete_are_serialized() {
+        let fixture = Arc::new(Fixture::new());
+        for i in 0..10 {
+            let container = fixture.container("race", "exec sleep 30", CreateOptions::default());
+            fixture.run(&container).await.unwrap();
+            let updated = {
+                let fixture = fixture.clone();
+                let container = container.clone();
+                tokio::spawn(async move { container.update(&fixture.runtime, b"{}").await })
+            };
+            // Lets the update take the lock first every other time.
+            if i % 2 == 0 {
+                task::yield_now().await;
+            }
+            let deleted = container.delete(&fixture.runtime, Duration::from_secs(10), true);
+            let (updated, deleted) = tokio::join!(updated, deleted);
+            deleted.unwrap();
+            assert!(container.deleted.load(Ordering::SeqCst));
+            let invocations = fs::read_to_string(fixture.root().join("invocations")).unwrap();
+            let position = |command: &str| {
+                let line = format!("{} {}", command, container.id);
+                invocations
+                    .lines()
+                    .position(|invocation| invocation == line)
+            };
+            match updated.unwrap() {
+                Ok(()) => assert!(position("update") < position("delete")),
+                Err(err) => {
+                    assert!(matches!(
+                        err.downcast_ref(),
+                        Some(ContainerError::InvalidState(_))
+                    ));
+                    assert_eq!(position("update"), None);
+                }
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn exec_and_init_exiting_together_are_told_apart() {
+        let fixture = Fixture::new();
+        for _ in 0..5 {
+            let go = fixture.dir.path().join("go");
+            let _ = fs::remove_file(&go);
+            let wait_for_go = format!("until [ -e {} ]; do sleep 0.01; done", go.display());
+            let container = fixture.container(
+                "exits",
+                &format!("{}; exit 3", wait_for_go),
+                CreateOptions::default(),
+            );
+            fixture.run(&container).await.unwrap();
+            let spec = exec_spec(&format!("{}; exit 5", wait_for_go));
+            container
+                .add_exec("exec", &spec, Path::new(""), Path::new(""), Path::new(""))
+                .await
+                .unwrap();
+            container
+                .start_exec(
+                    &fixture.runtime,
+                    "exec",
+                    fixture.config.log_options(&fixture.metrics),
+                )
+                .await
+                .unwrap();
+            let exec = container.exec("exec").await.unwrap();
+            // Both see the file within the same poll and exit in the same SIGCHLD batch.
+            fs::write(&go, "").unwrap();
+            let (init, exec) = timeout(TIMEOUT, async {
+                tokio::join!(container.init.wait(), exec.process.wait())
+            })
+            .await
+            .unwrap();
+            assert_eq!(init.exit_status, ExitStatus::Exited(3));
+            assert_eq!(exec.exit_status, ExitStatus::Exited(5));
+        }
+    }
+
+    #[tokio::test]
+    async fn merged_stderr_keeps_the_order_of_writes() {
+        let fixture = Fixture::new();
+        let container = fixture.container(
+            "merge",
+            "for i in 1 2 3 4 5; do echo out $i; echo err $i >&2; done",
+            CreateOptions {
+                merge_stderr: true,
+                ..Default::default()
+            },
+        );
+        fixture.run(&container).await.unwrap();
+        let exit = timeout(TIMEOUT, container.init.wait()).await.unwrap();
+        assert_eq!(exit.exit_status, ExitStatus::Exited(0));
+        // The exit is only reported once the output has been written.
+        let expected: String = (1..=5).map(|i| format!("out {}\nerr {}\n", i, i)).collect();
+        assert_eq!(fs::read_to_string(&container.stdout).unwrap(), expected);
+        assert!(!container.stderr.exists());
+    }
+}
@@ -0,0 +1,226 @@
+use std::{path::PathBuf, time::Duration};
+
+use anyhow::{bail, ensure, Context, Result};
+use prost::Message;
+use prost_types::{Any, Timestamp};
+use shim_protos::events::{
+    Envelope, ForwardRequest, TaskCreate, TaskDelete, TaskExit, TaskOom, TaskStart, TtrpcRequest,
+    TtrpcResponse,
+};
+use time::OffsetDateTime;
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::UnixStream,
+    sync::mpsc,
+    time::timeout,
+};
+use tracing::{debug, warn};
+
+use crate::{
+    config::{Config, ExitCodeFormat},
+    signal::ExitStatus,
+    utils::timestamp,
+};
+
+/// containerd's ttrpc service that shims forward their events to.
+const EVENTS_SERVICE: &str = "containerd.services.events.ttrpc.v1.Events";
+
+/// How long containerd gets to accept an event before it is sent again on a new connection.
+const FORWARD_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// How often an event is sent before it is dropped.
+const FORWARD_ATTEMPTS: u32 = 2;
+
+/// ttrpc's message header: the payload length, the stream ID, the message type and flags.
+const TTRPC_HEADER_LEN: usize = 10;
+const TTRPC_MESSAGE_REQUEST: u8 = 1;
+const TTRPC_MESSAGE_RESPONSE: u8 = 2;
+
+/// The largest message ttrpc accepts.
+const TTRPC_MESSAGE_MAX: usize = 4 << 20;
+
+/// Publishes the containers' task events to containerd, like its own shims do, so that clients
+/// watching the event stream hear about them. Events are queued and forwarded in order by a
+/// background task, so publishing never blocks. Without a publish address events are dropped.
+#[derive(Clone, Default)]
+pub struct Publisher {
+    sender: Option<mpsc::UnboundedSender<Envelope>>,
+    namespace: String,
+    exit_code_format: ExitCodeFormat,
+}
+
+impl Publisher {
+    /// Starts forwarding events to `--publish-address`, if set.
+    pub fn new(config: &Config) -> Self {
+        let sender = config.publish_address.clone().map(|address| {
+            let (sender, receiver) = mpsc::unbounded_channel();
+            tokio::spawn(forward_events(address, receiver));
+            sender
+        });
+        Self {
+            sender,
+            namespace: config.namespace.clone(),
+            exit_code_format: config.exit_code_format,
+        }
+    }
+
+    pub fn task_create(&self, container_id: &str, bundle: &str, pid: i32) {
+        let event = TaskCreate {
+            container_id: container_id.to_string(),
+            bundle: bundle.to_string(),
+            pid: pid as u32,
+        };
+        self.publish("/tasks/create", "TaskCreate", event);
+    }
+
+    pub fn task_start(&self, container_id: &str, pid: i32) {
+        let event = TaskStart {
+            container_id: container_id.to_string(),
+            pid: pid as u32,
+        };
+        self.publish("/tasks/start", "TaskStart", event);
+    }
+
+    /// Publishes the exit of a container's process, `id` being the exec ID or the container ID
+    /// for the init process.
+    pub fn task_exit(
+        &self,
+        container_id: &str,
+        id: &str,
+        pid: i32,
+        exit_status: ExitStatus,
+        exited_at: Option<Timestamp>,
+    ) {
+        let event = TaskExit {
+            container_id: container_id.to_string(),
+            id: id.to_string(),
+            pid: pid as u32,
+            exit_status: exit_status.code(self.exit_code_format),
+            exited_at,
+        };
+        self.publish("/tasks/exit", "TaskExit", event);
+    }
+
+    pub fn task_delete(
+        &self,
+        container_id: &str,
+        pid: i32,
+        exit_status: ExitStatus,
+        exited_at: Option<Timestamp>,
+    ) {
+        let event = TaskDelete {
+            container_id: container_id.to_string(),
+            pid: pid as u32,
+            exit_status: exit_status.code(self.exit_code_format),
+            exited_at,
+            id: container_id.to_string(),
+        };
+        self.publish("/tasks/delete", "TaskDelete", event);
+    }
+
+    pub fn task_oom(&self, container_id: &str) {
+        let event = TaskOom {
+            container_id: container_id.to_string(),
+        };
+        self.publish("/tasks/oom", "TaskOOM", event);
+    }
+
+    fn publish(&self, topic: &str, name: &str, event: impl Message) {
+        let Some(sender) = &self.sender else {
+            return;
+        };
+        let envelope = Envelope {
+            timestamp: Some(timestamp(OffsetDateTime::now_utc())),
+            namespace: self.namespace.clone(),
+            topic: topic.to_string(),
+            event: Some(Any {
+                type_url: format!("containerd.events.{}", name),
+                value: event.encode_to_vec(),
+            }),
+        };
+        // Only fails once the daemon is shutting down.
+        let _ = sender.send(envelope);
+    }
+}
+
+/// Forwards queued events to containerd over a single ttrpc connection, which is reopened when
+/// it fails. An event that can't be forwarded is dropped rather than holding up later ones.
+async fn forward_events(address: PathBuf, mut receiver: mpsc::UnboundedReceiver<Envelope>) {
+    let mut connection: Option<UnixStream> = None;
+    // ttrpc clients use odd stream IDs.
+    let mut stream_id: u32 = 1;
+    while let Some(envelope) = receiver.recv().await {
+        let payload = ForwardRequest {
+            envelope: Some(envelope.clone()),
+        }
+        .encode_to_vec();
+        let mut result = Ok(());
+        for _ in 0..FORWARD_ATTEMPTS {
+            let stream = match connection.as_mut() {
+                Some(stream) => stream,
+                None => match UnixStream::connect(&address).await {
+                    Ok(stream) => connection.insert(stream),
+                    Err(err) => {
+                        result =
+                            Err(err).with_context(|| format!("Failed to connect to {:?}", address));
+                        continue;
+                    }
+                },
+            };
+            result = match timeout(FORWARD_TIMEOUT, forward(stream, stream_id, &payload)).await {
+                Ok(result) => result,
+                Err(err) => Err(err.into()),
+            };
+            stream_id = stream_id.wrapping_add(2);
+            if result.is_ok() {
+                break;
+            }
+            // The connection may be out of step after a failure, so a retry starts afresh.
+            connection = None;
+            stream_id = 1;
+        }
+        match result {
+            Ok(()) => debug!("Published {} event", envelope.topic),
+            Err(err) => warn!("Failed to publish {} event: {:#}", envelope.topic, err),
+        }
+    }
+}
+
+/// Sends a ttrpc `Events.Forward` request and waits for its response.
+async fn forward(stream: &mut UnixStream, stream_id: u32, payload: &[u8]) -> Result<()> {
+    let request = TtrpcRequest {
+        service: EVENTS_SERVICE.to_string(),
+        method: "Forward".to_string(),
+        payload: payload.to_vec(),
+        timeout_nano: FORWARD_TIMEOUT.as_nanos() as i64,
+    }
+    .encode_to_vec();
+    let mut header = [0; TTRPC_HEADER_LEN];
+    header[..4].copy_from_slice(&(request.len() as u32).to_be_bytes());
+    header[4..8].copy_from_slice(&stream_id.to_be_bytes());
+    header[8] = TTRPC_MESSAGE_REQUEST;
+    stream.write_all(&header).await?;
+    stream.write_all(&request).await?;
+
+    stream.read_exact(&mut header).await?;
+    let length = u32::from_be_bytes(header[..4].try_into()?) as usize;
+    let response_id = u32::from_be_bytes(header[4..8].try_into()?);
+    ensure!(
+        length <= TTRPC_MESSAGE_MAX,
+        "Response of {} bytes is too large",
+        length
+    );
+    let mut response = vec![0; length];
+    stream.read_exact(&mut response).await?;
+    ensure!(
+        header[8] == TTRPC_MESSAGE_RESPONSE && response_id == stream_id,
+        "Unexpected message of type {} for stream {}",
+        header[8],
+        response_id
+    );
+    let response = TtrpcResponse::decode(response.as_slice()).context("Invalid response")?;
+    if let Some(status) = response.status.filter(|status| status.code != 0) {
+        bail!("containerd rejected the event: {}", status.message);
+    }
+    Ok(())
+}
@@ -1,49 +1,118 @@
 use std::{
     env,
-    hash::{DefaultHasher, Hash, Hasher},
-    io::{stdout, Write},
+    ffi::CString,
+    fs::File,
+    io::{self, stdout, IsTerminal, Read, Write},
     os::{
-        fd::{FromRawFd, RawFd},
-        unix::net::UnixListener,
+        fd::{AsFd, AsRawFd, FromRawFd, RawFd},
+        linux::net::SocketAddrExt,
+        unix::{
+            ffi::OsStrExt,
+            fs::{DirBuilderExt, PermissionsExt},
+            net::{SocketAddr, UnixListener, UnixStream},
+        },
     },
-    path::PathBuf,
+    path::{Path, PathBuf},
     process::{ExitCode, Stdio},
-    sync::Arc,
+    sync::{atomic::Ordering, Arc},
 };
 
-use anyhow::{Context, Result};
+use anyhow::{bail, ensure, Context, Result};
 use clap::{Parser, Subcommand};
 use command_fds::{CommandFdExt, FdMapping};
-use nix::{sys::prctl::set_child_subreaper, unistd::setsid};
-use service::TaskService;
-use shim_protos::proto::task_server::TaskServer;
-use signal::handle_signals;
-use tokio::{fs, sync::mpsc};
+use config::{Config, SocketPassing};
+use container::{Container, CreateOptions, Status as ContainerStatus};
+use events::Publisher;
+use metrics::Metrics;
+use nix::{
+    fcntl::OFlag,
+    libc::STDOUT_FILENO,
+    sys::prctl::{set_child_subreaper, set_name},
+    sys::signal::Signal,
+    sys::stat::{umask, Mode},
+    unistd::{chdir, chroot, dup2, pipe2, setsid},
+};
+use process::{PidRegistry, Reaped};
+use prost::Message;
+use prost_types::Any;
+use runtime::Runtime;
+use service::{runtime_options, TaskService, SHUTDOWN_DRAIN_TIMEOUT};
+use shim_protos::proto::{task_server::TaskServer, DeleteResponse, RuntimeInfo, RuntimeVersion};
+use signal::{handle_signals, sigchld, wait_for_termination, ExitStatus};
+use time::OffsetDateTime;
+use tokio::{fs, sync::mpsc, time::sleep};
 use tokio_stream::wrappers::UnixListenerStream;
 use tonic::transport::Server;
-use tracing::{error, warn};
-use utils::ExitSignal;
+use tracing::{debug, error, warn};
+use utils::{timestamp, ExitSignal};
 
+mod cgroup;
+mod config;
 mod container;
+mod events;
+mod metrics;
+mod monitor;
+mod mount;
+mod nsenter;
+mod oom;
+mod process;
+mod runtime;
+mod seccomp;
 mod service;
 mod signal;
+mod spec;
+#[cfg(test)]
+mod testing;
 mod utils;
 
 const SOCKET_ROOT: &str = "/run/shim";
+
+/// The longest path a unix socket can be bound to, excluding the terminating nul.
+const SOCKET_PATH_MAX: usize = 107;
 const SOCKET_FD: RawFd = 3;
+const READY_FD: RawFd = 4;
+
+/// Written by the daemon to the ready pipe once it is about to serve requests. Anything else is
+/// an error message.
+const READY: &str = "ready";
+
+/// The version of containerd's shim protocol that the `start` and `delete` commands follow.
+const PROTOCOL_VERSION: u32 = 2;
+
+/// The task RPCs beyond creating, starting, killing, waiting for and deleting containers.
+const CAPABILITIES: [&str; 12] = [
+    "Exec",
+    "Pause",
+    "Resume",
+    "Checkpoint",
+    "Update",
+    "Stats",
+    "Pids",
+    "ResizePty",
+    "CloseIO",
+    "WriteStdin",
+    "UpdateStdio",
+    "Nsenter",
+];
+
+/// The type URL containerd expects the OCI runtime's features as.
+const FEATURES_TYPE_URL: &str =
+    "types.containerd.io/opencontainers/runtime-spec/1/features/Features";
+
+/// The longest process name the kernel keeps, excluding the terminating nul.
+const PROCESS_NAME_MAX: usize = 15;
 
 /// Shim process for running containers.
 #[derive(Parser, Debug)]
 #[command(version, about, long_about = None)]
 struct Args {
-    /// Path to OCI runtime executable.
-    #[arg(short, long, default_value = "/usr/sbin/runc")]
-    runtime: PathBuf,
-
     /// ID of the task.
     #[arg(short, long)]
     id: String,
 
+    #[command(flatten)]
+    config: Config,
+
     /// Command to run.
     #[command(subcommand)]
     command: Command,
@@ -59,6 +128,22 @@ enum Command {
         /// Path to the socket file.
         socket_path: PathBuf,
     },
+
+    /// Clean up after a task whose daemon is gone.
+    ///
+    /// Force deletes its container, removes the daemon's socket and prints a protobuf
+    /// `DeleteResponse` to stdout.
+    Delete {
+        /// Path to the bundle, defaults to the current directory.
+        #[arg(long)]
+        bundle: Option<PathBuf>,
+    },
+
+    /// Print what the shim and its OCI runtime support.
+    ///
+    /// Reads containerd's runc options from stdin unless it is a terminal, and prints a protobuf
+    /// `RuntimeInfo` to stdout.
+    Info,
 }
 
 fn main() -> ExitCode {
@@ -73,6 +158,11 @@ fn main() -> ExitCode {
             let socket_path = socket_path.clone();
             start_daemon(args, socket_path)
         }
+        Command::Delete { ref bundle } => {
+            let bundle = bundle.clone();
+            delete(args, bundle)
+        }
+        Command::Info => info(args),
     };
 
     match result {
@@ -84,18 +174,103 @@ fn main() -> ExitCode {
     }
 }
 
+/// Returns the path of a task's socket, which is named after its ID, or its name with
+/// `--abstract-socket`. IDs that aren't a plain file name, or are too long for a socket path, are
+/// rejected.
+fn socket_path(id: &str) -> Result<PathBuf> {
+    ensure!(
+        !id.is_empty() && id != "." && id != "..",
+        "Invalid task ID {:?}",
+        id
+    );
+    ensure!(
+        !id.contains(|c: char| c == '/' || c.is_control()),
+        "Task ID {:?} contains a slash or control characters",
+        id
+    );
+    let path = PathBuf::from(SOCKET_ROOT).join(format!("{}.sock", id));
+    ensure!(
+        path.as_os_str().len() <= SOCKET_PATH_MAX,
+        "Task ID {:?} is too long, its socket path {:?} exceeds {} bytes",
+        id,
+        path,
+        SOCKET_PATH_MAX
+    );
+    Ok(path)
+}
+
+/// Returns the address clients connect to a task's socket with.
+fn socket_address(path: &Path, abstract_socket: bool) -> String {
+    if abstract_socket {
+        format!("unix-abstract:{}", path.display())
+    } else {
+        format!("unix://{}", path.display())
+    }
+}
+
+/// Binds a task's socket, which only its owner may connect to: anyone connected controls the
+/// container. A socket left behind by a shim that crashed is replaced, one that a shim still
+/// listens on is not. Abstract sockets can't be left behind, or restricted.
+fn bind_socket(path: &Path, abstract_socket: bool) -> Result<UnixListener> {
+    if abstract_socket {
+        let address = SocketAddr::from_abstract_name(path.as_os_str().as_bytes())?;
+        return match UnixListener::bind_addr(&address) {
+            Err(err) if err.kind() == io::ErrorKind::AddrInUse => {
+                bail!("A shim is already running for abstract socket {:?}", path)
+            }
+            result => result.context("Failed to bind abstract socket"),
+        };
+    }
+    let listener = match bind_private(path) {
+        Err(err) if err.kind() == io::ErrorKind::AddrInUse => match UnixStream::connect(path) {
+            Ok(_) => bail!("A shim is already running for socket {:?}", path),
+            Err(connect_err) if connect_err.kind() == io::ErrorKind::ConnectionRefused => {
+                warn!("Removing stale socket {:?}", path);
+                std::fs::remove_file(path).context("Failed to remove stale socket")?;
+                bind_private(path)
+            }
+            Err(_) => Err(err),
+        },
+        result => result,
+    }
+    .context("Failed to bind socket")?;
+    std::fs::set_permissions(path, std::fs::Permissions::from_mode(0o600))
+        .context("Failed to restrict socket permissions")?;
+    Ok(listener)
+}
+
+/// Binds a socket under a umask that leaves it to its owner, so that no one can connect in the
+/// window before its permissions are set. The umask is process wide, so files other threads
+/// create meanwhile end up restricted as well, which is harmless.
+fn bind_private(path: &Path) -> io::Result<UnixListener> {
+    let previous = umask(Mode::from_bits_truncate(0o077));
+    let result = UnixListener::bind(path);
+    umask(previous);
+    result
+}
+
+/// Creates the directory holding the task sockets, accessible to its owner only. One that
+/// already exists is restricted as well, in case something else created it.
+fn create_socket_root(path: &Path) -> Result<()> {
+    std::fs::DirBuilder::new()
+        .recursive(true)
+        .mode(0o700)
+        .create(path)
+        .context("Failed to create socket root")?;
+    std::fs::set_permissions(path, std::fs::Permissions::from_mode(0o700))
+        .context("Failed to restrict socket root permissions")
+}
+
 fn start(args: Args) -> Result<()> {
-    let hash = {
-        let mut hasher = DefaultHasher::new();
-        args.id.hash(&mut hasher);
-        hasher.finish()
-    };
-    let socket_path = PathBuf::from(SOCKET_ROOT).join(format!("{}.sock", hash));
-    std::fs::create_dir_all(SOCKET_ROOT).context("Failed to create socket root")?;
-    let uds = UnixListener::bind(&socket_path).context("Failed to bind socket")?;
-    let socket_addr = format!("unix://{}", socket_path.display());
-    stdout().write_all(socket_addr.as_bytes())?;
-    stdout().flush()?;
+    let socket_path = socket_path(&args.id)?;
+    // Paths are resolved inside the jail, so the daemon checks for itself once it is in there.
+    if args.config.jail.is_none() {
+        runtime::resolve_executable(&args.config.runtime)?;
+    }
+    create_socket_root(Path::new(SOCKET_ROOT))?;
+    let socket_addr = socket_address(&socket_path, args.config.abstract_socket);
+    let (ready_reader, ready_writer) =
+        pipe2(OFlag::O_CLOEXEC).context("Failed to create ready pipe")?;
     let cmd = env::current_exe().context("Failed to get current executable")?;
     let cwd = env::current_dir().context("Failed to get current directory")?;
     let mut command = std::process::Command::new(cmd);
@@ -105,64 +280,445 @@ fn start(args: Args) -> Result<()> {
         .stdout(Stdio::null())
         .stderr(Stdio::null());
     command
-        .arg("--runtime")
-        .arg(args.runtime)
+        .args(args.config.to_args())
         .arg("--id")
         .arg(args.id)
         .arg("daemon")
-        .arg(socket_path);
-    command
-        .fd_mappings(vec![FdMapping {
-            parent_fd: uds.into(),
-            child_fd: SOCKET_FD,
-        }])
-        .context("Failed to set fd mapping")?;
+        .arg(&socket_path);
+    match args.config.socket_passing {
+        SocketPassing::Fd => {
+            let uds = bind_socket(&socket_path, args.config.abstract_socket)?;
+            command
+                .fd_mappings(vec![
+                    FdMapping {
+                        parent_fd: uds.into(),
+                        child_fd: SOCKET_FD,
+                    },
+                    FdMapping {
+                        parent_fd: ready_writer,
+                        child_fd: READY_FD,
+                    },
+                ])
+                .context("Failed to set fd mapping")?;
+        }
+        SocketPassing::Path => {
+            command.stdout(ready_writer);
+        }
+    }
     let _child = command.spawn().context("Failed to spawn shim")?;
+    // Close our copy of the write end so that the read below sees EOF if the daemon dies.
+    drop(command);
+
+    let mut message = String::new();
+    File::from(ready_reader)
+        .read_to_string(&mut message)
+        .context("Failed to wait for daemon")?;
+    match message.as_str() {
+        READY => {}
+        "" => bail!("Daemon exited before it was ready"),
+        err => bail!("Daemon failed to start: {}", err),
+    }
+
+    stdout().write_all(socket_addr.as_bytes())?;
+    stdout().flush()?;
     Ok(())
 }
 
 #[tokio::main]
 async fn start_daemon(args: Args, socket_path: PathBuf) -> Result<()> {
+    let started_at = OffsetDateTime::now_utc();
+    let mut ready = ready_channel(args.config.socket_passing)?;
+    let shutdown_signal = Arc::new(ExitSignal::default());
+    let (task_service, uds_stream) =
+        match setup_daemon(&args, &socket_path, shutdown_signal.clone(), started_at) {
+            Ok(setup) => setup,
+            Err(err) => {
+                // `start` reports this to its caller.
+                let _ = write!(ready, "{:#}", err);
+                return Err(err);
+            }
+        };
+    task_service.recover().await;
+    ready
+        .write_all(READY.as_bytes())
+        .context("Failed to signal readiness")?;
+    drop(ready);
+
+    let containers = task_service.containers.clone();
+    // Once signalled, the server stops accepting connections and waits for the requests in
+    // flight to finish, but a request such as a Wait may not finish on its own.
+    let server = Server::builder()
+        .add_service(TaskServer::new(task_service))
+        .serve_with_incoming_shutdown(uds_stream, shutdown_signal.wait());
+    let drain_timeout = async {
+        shutdown_signal.wait().await;
+        sleep(args.config.rpc_drain_timeout).await;
+    };
+    tokio::select! {
+        result = server => result?,
+        _ = drain_timeout => warn!(
+            "Requests still in flight after {:?}, shutting down anyway",
+            args.config.rpc_drain_timeout
+        ),
+    }
+
+    // Containers still registered are left running, e.g. when the daemon was asked to terminate.
+    // Write out their output so far before the monitors go away with us.
+    let containers: Vec<_> = containers
+        .iter()
+        .map(|container| container.clone())
+        .collect();
+    for container in containers {
+        container.drain_output(SHUTDOWN_DRAIN_TIMEOUT).await;
+    }
+
+    if !args.config.abstract_socket {
+        fs::remove_file(socket_path)
+            .await
+            .context("Failed to remove socket")?;
+    }
+
+    Ok(())
+}
+
+#[tokio::main]
+async fn delete(args: Args, bundle: Option<PathBuf>) -> Result<()> {
+    let socket_path = socket_path(&args.id)?;
+    let bundle = match bundle {
+        Some(bundle) => bundle,
+        None => env::current_dir().context("Failed to get current directory")?,
+    };
+    // The runtime is waited for through the reaper. Nothing else is reaped, as we are not a
+    // subreaper here, so the exits it forwards are dropped.
+    let pids = PidRegistry::default();
+    let (tx, _exits) = mpsc::unbounded_channel();
+    tokio::spawn(handle_signals(
+        sigchld().context("Failed to listen for SIGCHLD")?,
+        tx,
+        pids.clone(),
+    ));
+    let runtime = Runtime::new(&args.config, pids.clone(), Metrics::default());
+    // The container may not have got as far as persisting its state.
+    let container = match Container::load(&bundle, pids.clone(), Publisher::default()).await {
+        Ok(container) => container,
+        Err(err) => {
+            debug!("Deleting container without its state: {:#}", err);
+            Container::new(
+                &args.id,
+                &bundle,
+                &PathBuf::new(),
+                &PathBuf::new(),
+                CreateOptions::default(),
+                pids,
+                Publisher::default(),
+            )
+        }
+    };
+    if let Err(err) = container
+        .delete(&runtime, args.config.delete_timeout, true)
+        .await
+    {
+        // Not every runtime treats force deleting an unknown container as success.
+        if runtime
+            .state(&args.id, &container.options.runtime)
+            .await
+            .is_ok()
+        {
+            return Err(err).context("Failed to delete container");
+        }
+        debug!("Container {} is already gone: {:#}", args.id, err);
+    }
+    for path in [socket_path.clone(), socket_path.with_extension("json")] {
+        match fs::remove_file(&path).await {
+            Err(err) if err.kind() != io::ErrorKind::NotFound => {
+                warn!("Failed to remove {:?}: {}", path, err)
+            }
+            _ => {}
+        }
+    }
+
+    // A container that was still running has just been killed.
+    let exit_status = match container.status().await {
+        ContainerStatus::STOPPED => container.exit_status().await,
+        _ => ExitStatus::Signaled(Signal::SIGKILL as i32),
+    };
+    let exited_at = container
+        .exited_at()
+        .await
+        .unwrap_or_else(|| timestamp(OffsetDateTime::now_utc()));
+    let response = DeleteResponse {
+        pid: container.pid().await as u32,
+        exit_status: exit_status.code(args.config.exit_code_format),
+        exited_at: Some(exited_at),
+        signaled: exit_status.signaled(),
+    };
+    stdout().write_all(&response.encode_to_vec())?;
+    stdout().flush()?;
+    Ok(())
+}
+
+#[tokio::main]
+async fn info(args: Args) -> Result<()> {
+    let options = if io::stdin().is_terminal() {
+        None
+    } else {
+        let mut input = Vec::new();
+        io::stdin()
+            .read_to_end(&mut input)
+            .context("Failed to read options")?;
+        (!input.is_empty())
+            .then(|| Any::decode(input.as_slice()))
+            .transpose()
+            .context("Failed to decode options")?
+    };
+    let runtime_options = options
+        .as_ref()
+        .map(runtime_options)
+        .transpose()
+        .context("Invalid runtime options")?
+        .unwrap_or_default();
+
+    // The runtime is waited for through the reaper, like in `delete`.
+    let pids = PidRegistry::default();
+    let (tx, _exits) = mpsc::unbounded_channel();
+    tokio::spawn(handle_signals(
+        sigchld().context("Failed to listen for SIGCHLD")?,
+        tx,
+        pids.clone(),
+    ));
+    let runtime = Runtime::new(&args.config, pids, Metrics::default());
+    let features = match runtime.features(&runtime_options).await {
+        Ok(features) => Some(Any {
+            type_url: FEATURES_TYPE_URL.to_string(),
+            value: features,
+        }),
+        Err(err) => {
+            debug!("Failed to get OCI runtime features: {:#}", err);
+            None
+        }
+    };
+    let runtime_version = match runtime.version(&runtime_options).await {
+        Ok(version) => Some(version),
+        Err(err) => {
+            debug!("Failed to get OCI runtime version: {:#}", err);
+            None
+        }
+    };
+
+    let response = RuntimeInfo {
+        name: env!("CARGO_PKG_NAME").to_string(),
+        version: Some(RuntimeVersion {
+            version: env!("CARGO_PKG_VERSION").to_string(),
+            revision: String::new(),
+        }),
+        options,
+        features,
+        annotations: Default::default(),
+        capabilities: CAPABILITIES.iter().map(|name| name.to_string()).collect(),
+        protocol_version: PROTOCOL_VERSION,
+        runtime_name: runtime_version
+            .as_ref()
+            .map(|version| version.name.clone())
+            .unwrap_or_default(),
+        runtime_version: runtime_version.map(|version| RuntimeVersion {
+            version: version.version,
+            revision: version.commit,
+        }),
+    };
+    stdout().write_all(&response.encode_to_vec())?;
+    stdout().flush()?;
+    Ok(())
+}
+
+/// Opens the channel the daemon reports readiness to `start` on.
+fn ready_channel(socket_passing: SocketPassing) -> Result<File> {
+    match socket_passing {
+        SocketPassing::Fd => Ok(unsafe { File::from_raw_fd(READY_FD) }),
+        SocketPassing::Path => {
+            // `start` waits for the pipe on our stdout to close, so move it out of the way
+            // rather than closing fd 1, which would then be reused by the next file we open.
+            let ready = stdout().as_fd().try_clone_to_owned()?;
+            let null = File::options().write(true).open("/dev/null")?;
+            dup2(null.as_raw_fd(), STDOUT_FILENO).context("Failed to redirect stdout")?;
+            Ok(File::from(ready))
+        }
+    }
+}
+
+fn setup_daemon(
+    args: &Args,
+    socket_path: &Path,
+    shutdown_signal: Arc<ExitSignal>,
+    started_at: OffsetDateTime,
+) -> Result<(TaskService, UnixListenerStream)> {
     setsid().context("Failed to setsid")?;
-    set_child_subreaper(true).context("Failed to set subreaper")?;
+    match set_child_subreaper(true) {
+        Ok(()) => {}
+        Err(err) if args.config.best_effort_subreaper => {
+            warn!(
+                "Failed to set subreaper, container exits won't be noticed: {}",
+                err
+            );
+        }
+        Err(err) => return Err(err).context("Failed to set subreaper"),
+    }
+    // This runs on the main thread, whose name `ps` and `top` show for the process.
+    if let Err(err) = set_name(&process_name(&args.id)) {
+        warn!("Failed to set process name: {}", err);
+    }
 
-    let shutdown_signal = Arc::new(ExitSignal::default());
-    let task_service = TaskService::new(args.runtime, shutdown_signal.clone());
+    // Next to the socket, so that a daemon restarted for the same task finds it.
+    let state_path = socket_path.with_extension("json");
+    let task_service = TaskService::new(
+        &args.config,
+        shutdown_signal.clone(),
+        started_at,
+        state_path,
+    );
+    if let Some(address) = args.config.metrics_address {
+        let listener = std::net::TcpListener::bind(address)
+            .with_context(|| format!("Failed to bind metrics address {}", address))?;
+        listener.set_nonblocking(true)?;
+        tokio::spawn(metrics::serve(
+            tokio::net::TcpListener::from_std(listener)?,
+            task_service.metrics.clone(),
+            task_service.containers.clone(),
+        ));
+    }
 
     let (tx, mut rx) = mpsc::unbounded_channel();
     let containers = task_service.containers.clone();
-    tokio::spawn(async move { handle_signals(tx).await });
+    let orphans_reaped = task_service.orphans_reaped.clone();
+    let metrics = task_service.metrics.clone();
+    let pids = task_service.pids.clone();
+    let log_orphans = args.config.log_orphans;
+    let sigchld = sigchld().context("Failed to listen for SIGCHLD")?;
+    tokio::spawn(async move { handle_signals(sigchld, tx, pids).await });
+    tokio::spawn(async move {
+        if let Err(err) = wait_for_termination().await {
+            error!("Failed to handle termination signals: {}", err);
+            return;
+        }
+        shutdown_signal.signal();
+    });
     tokio::spawn(async move {
         loop {
-            if let Some((pid, exit_code)) = rx.recv().await {
-                let mut found = false;
-                for container in containers.iter() {
-                    if container.pid().await == pid {
-                        container.set_exited(exit_code).await;
-                        found = true;
-                        break;
+            if let Some(Reaped {
+                pid,
+                exit_status,
+                owner,
+            }) = rx.recv().await
+            {
+                let container = owner.and_then(|(container_id, exec_id)| {
+                    let container = containers.get(&container_id)?.clone();
+                    Some((container, exec_id))
+                });
+                if let Some((container, exec_id)) = container {
+                    // Reaped on its own, as waiting for the output to drain can take a while.
+                    let metrics = metrics.clone();
+                    tokio::spawn(async move {
+                        let init = exec_id.is_none() && container.pid().await == pid;
+                        container.reap(exec_id.as_deref(), pid, exit_status).await;
+                        if let Some(time_to_exit) = container.time_to_exit().await.filter(|_| init)
+                        {
+                            metrics.container_phase("exited", time_to_exit);
+                        }
+                    });
+                } else {
+                    // As a subreaper we also reap processes re-parented to us after their
+                    // parent exited, e.g. ones leaked by a container.
+                    let count = orphans_reaped.fetch_add(1, Ordering::Relaxed) + 1;
+                    if log_orphans {
+                        debug!(
+                            "Reaped orphan process {} with {:?} ({} orphans reaped)",
+                            pid, exit_status, count
+                        );
                     }
                 }
-                if !found {
-                    warn!("Received exit code for unknown pid {}", pid);
-                }
             }
         }
     });
 
-    let std_uds = unsafe { UnixListener::from_raw_fd(SOCKET_FD) };
+    let std_uds = match args.config.socket_passing {
+        SocketPassing::Fd => unsafe { UnixListener::from_raw_fd(SOCKET_FD) },
+        SocketPassing::Path => bind_socket(socket_path, args.config.abstract_socket)?,
+    };
     std_uds.set_nonblocking(true)?;
     let uds = tokio::net::UnixListener::from_std(std_uds)?;
     let uds_stream = UnixListenerStream::new(uds);
 
-    Server::builder()
-        .add_service(TaskServer::new(task_service))
-        .serve_with_incoming_shutdown(uds_stream, shutdown_signal.wait())
-        .await?;
+    if let Some(jail) = &args.config.jail {
+        chdir(jail).context("Failed to change into jail")?;
+        if args.config.jail_chroot {
+            chroot(jail).context("Failed to chroot into jail")?;
+            chdir("/").context("Failed to change into jail root")?;
+        }
+    }
+    let runtime = runtime::resolve_executable(&args.config.runtime)?;
+    debug!("Using OCI runtime {:?}", runtime);
 
-    fs::remove_file(socket_path)
-        .await
-        .context("Failed to remove socket")?;
+    Ok((task_service, uds_stream))
+}
 
-    Ok(())
+/// Returns the process name for a daemon, `shim[<id>]`, with the id truncated to fit into the
+/// kernel's 15 byte limit.
+fn process_name(id: &str) -> CString {
+    let max_id_len = PROCESS_NAME_MAX - "shim[]".len();
+    let mut end = id.len().min(max_id_len);
+    while !id.is_char_boundary(end) {
+        end -= 1;
+    }
+    let name = format!("shim[{}]", &id[..end]).replace('\0', "");
+    CString::new(name).expect("nul bytes were removed")
+}
+
+#[cfg(test)]
+mod tests {
+    use std::os::unix::fs::MetadataExt;
+
+    use tempfile::TempDir;
+
+    use super::*;
+
+    fn mode(path: &Path) -> u32 {
+        std::fs::metadata(path).unwrap().mode() & 0o777
+    }
+
+    #[test]
+    fn socket_is_owner_only() {
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join("task.sock");
+        let _listener = bind_socket(&path, false).unwrap();
+        assert_eq!(mode(&path), 0o600);
+    }
+
+    #[test]
+    fn stale_socket_is_replaced_owner_only() {
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join("task.sock");
+        drop(UnixListener::bind(&path).unwrap());
+        std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o777)).unwrap();
+        let _listener = bind_socket(&path, false).unwrap();
+        assert_eq!(mode(&path), 0o600);
+    }
+
+    #[test]
+    fn socket_root_is_owner_only() {
+        let dir = TempDir::new().unwrap();
+        let root = dir.path().join("run").join("shim");
+        create_socket_root(&root).unwrap();
+        assert_eq!(mode(&root), 0o700);
+    }
+
+    #[test]
+    fn existing_socket_root_is_restricted() {
+        let dir = TempDir::new().unwrap();
+        let root = dir.path().join("shim");
+        std::fs::DirBuilder::new()
+            .mode(0o755)
+            .create(&root)
+            .unwrap();
+        create_socket_root(&root).unwrap();
+        assert_eq!(mode(&root), 0o700);
+    }
 }
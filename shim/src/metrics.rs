@@ -0,0 +1,298 @@
+use std::{
+    collections::BTreeMap,
+    fmt::Write as _,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+use dashmap::DashMap;
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+    time::timeout,
+};
+use tracing::{debug, warn};
+
+use crate::{container::Container, monitor::Stream};
+
+/// Upper bounds of the duration histograms' buckets, in seconds.
+const DURATION_BUCKETS: [f64; 13] = [
+    0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 30.0, 60.0,
+];
+
+/// How long a scraper gets to send its request.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// The largest request accepted, scrapers only send a request line and a few headers.
+const REQUEST_MAX: usize = 8192;
+
+/// Runtime flags that take a value, which has to be skipped to find the runtime's command.
+const RUNTIME_VALUE_FLAGS: [&str; 3] = ["--root", "--log", "--log-format"];
+
+/// The shim's Prometheus metrics. Cloning gives another handle to the same metrics. They are
+/// always collected, but only exposed with `--metrics-address`.
+#[derive(Clone, Default)]
+pub struct Metrics {
+    inner: Arc<Inner>,
+}
+
+#[derive(Default)]
+struct Inner {
+    /// Durations of RPCs by method.
+    rpcs: Mutex<BTreeMap<&'static str, Histogram>>,
+
+    /// Durations of OCI runtime invocations by command, e.g. `create`.
+    runtime_commands: Mutex<BTreeMap<String, Histogram>>,
+
+    /// How long containers took to reach a phase of their lifecycle, by phase, see
+    /// [`Metrics::container_phase`].
+    container_phases: Mutex<BTreeMap<&'static str, Histogram>>,
+
+    stdout_bytes: AtomicU64,
+    stderr_bytes: AtomicU64,
+}
+
+#[derive(Default)]
+struct Histogram {
+    /// Observations per bucket of [`DURATION_BUCKETS`], not cumulative.
+    buckets: [u64; DURATION_BUCKETS.len()],
+    count: u64,
+    sum: f64,
+}
+
+impl Histogram {
+    fn observe(&mut self, duration: Duration) {
+        let seconds = duration.as_secs_f64();
+        if let Some(bucket) = DURATION_BUCKETS.iter().position(|&le| seconds <= le) {
+            self.buckets[bucket] += 1;
+        }
+        self.count += 1;
+        self.sum += seconds;
+    }
+
+    fn render(&self, out: &mut String, name: &str, label: &str, value: &str) {
+        let mut cumulative = 0;
+        for (le, count) in DURATION_BUCKETS.iter().zip(self.buckets) {
+            cumulative += count;
+            let _ = writeln!(
+                out,
+                "{}_bucket{{{}=\"{}\",le=\"{}\"}} {}",
+                name, label, value, le, cumulative
+            );
+        }
+        let _ = writeln!(
+            out,
+            "{}_bucket{{{}=\"{}\",le=\"+Inf\"}} {}",
+            name, label, value, self.count
+        );
+        let _ = writeln!(out, "{}_sum{{{}=\"{}\"}} {}", name, label, value, self.sum);
+        let _ = writeln!(
+            out,
+            "{}_count{{{}=\"{}\"}} {}",
+            name, label, value, self.count
+        );
+    }
+}
+
+/// Records the duration of an RPC when dropped, i.e. when its handler returns.
+pub struct RpcTimer {
+    metrics: Metrics,
+    method: &'static str,
+    started_at: Instant,
+}
+
+impl Drop for RpcTimer {
+    fn drop(&mut self) {
+        self.metrics
+            .inner
+            .rpcs
+            .lock()
+            .unwrap()
+            .entry(self.method)
+            .or_default()
+            .observe(self.started_at.elapsed());
+    }
+}
+
+impl Metrics {
+    /// Starts timing an RPC, named like in the proto, e.g. `Create`.
+    pub fn rpc(&self, method: &'static str) -> RpcTimer {
+        RpcTimer {
+            metrics: self.clone(),
+            method,
+            started_at: Instant::now(),
+        }
+    }
+
+    /// Records how long an OCI runtime invocation took, labelled with the runtime's command.
+    pub fn runtime_command(&self, cmd: &std::process::Command, duration: Duration) {
+        let mut args = cmd.get_args().map(|arg| arg.to_string_lossy());
+        let mut command = String::new();
+        while let Some(arg) = args.next() {
+            if RUNTIME_VALUE_FLAGS.contains(&arg.as_ref()) {
+                args.next();
+            } else if !arg.starts_with('-') {
+                command = arg.into_owned();
+                break;
+            }
+        }
+        self.inner
+            .runtime_commands
+            .lock()
+            .unwrap()
+            .entry(command)
+            .or_default()
+            .observe(duration);
+    }
+
+    /// Records how long a container took to reach a phase: `running` from being created, or
+    /// `exited` from running.
+    pub fn container_phase(&self, phase: &'static str, duration: Duration) {
+        self.inner
+            .container_phases
+            .lock()
+            .unwrap()
+            .entry(phase)
+            .or_default()
+            .observe(duration);
+    }
+
+    /// Counts container output copied by a monitor.
+    pub fn stdio_bytes(&self, stream: Stream, bytes: usize) {
+        let counter = match stream {
+            Stream::Stdout => &self.inner.stdout_bytes,
+            Stream::Stderr => &self.inner.stderr_bytes,
+        };
+        counter.fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+
+    /// Renders the metrics in Prometheus' text format, along with the number of containers in
+    /// each status.
+    async fn render(&self, containers: &DashMap<String, Arc<Container>>) -> String {
+        let mut out = String::new();
+
+        let containers: Vec<_> = containers
+            .iter()
+            .map(|container| container.clone())
+            .collect();
+        let mut statuses = BTreeMap::new();
+        for container in containers {
+            let status = format!("{:?}", container.status().await).to_lowercase();
+            *statuses.entry(status).or_insert(0) += 1;
+        }
+        out.push_str("# HELP shim_containers Containers managed by the shim, by status.\n");
+        out.push_str("# TYPE shim_containers gauge\n");
+        for (status, count) in statuses {
+            let _ = writeln!(out, "shim_containers{{status=\"{}\"}} {}", status, count);
+        }
+
+        out.push_str("# HELP shim_rpc_duration_seconds Duration of handled RPCs, by method.\n");
+        out.push_str("# TYPE shim_rpc_duration_seconds histogram\n");
+        for (method, histogram) in self.inner.rpcs.lock().unwrap().iter() {
+            histogram.render(&mut out, "shim_rpc_duration_seconds", "method", method);
+        }
+
+        out.push_str(
+            "# HELP shim_runtime_duration_seconds Duration of OCI runtime invocations, by command.\n",
+        );
+        out.push_str("# TYPE shim_runtime_duration_seconds histogram\n");
+        for (command, histogram) in self.inner.runtime_commands.lock().unwrap().iter() {
+            histogram.render(
+                &mut out,
+                "shim_runtime_duration_seconds",
+                "command",
+                command,
+            );
+        }
+
+        out.push_str(
+            "# HELP shim_container_phase_seconds Time containers took to reach a phase, from \
+             created to running and from running to exited.\n",
+        );
+        out.push_str("# TYPE shim_container_phase_seconds histogram\n");
+        for (phase, histogram) in self.inner.container_phases.lock().unwrap().iter() {
+            histogram.render(&mut out, "shim_container_phase_seconds", "phase", phase);
+        }
+
+        out.push_str("# HELP shim_stdio_bytes_total Container output copied, by stream.\n");
+        out.push_str("# TYPE shim_stdio_bytes_total counter\n");
+        for (stream, counter) in [
+            (Stream::Stdout, &self.inner.stdout_bytes),
+            (Stream::Stderr, &self.inner.stderr_bytes),
+        ] {
+            let _ = writeln!(
+                out,
+                "shim_stdio_bytes_total{{stream=\"{}\"}} {}",
+                stream.name(),
+                counter.load(Ordering::Relaxed)
+            );
+        }
+        out
+    }
+}
+
+/// Serves the metrics over HTTP at `/metrics`, one request per connection.
+pub async fn serve(
+    listener: TcpListener,
+    metrics: Metrics,
+    containers: Arc<DashMap<String, Arc<Container>>>,
+) {
+    loop {
+        let stream = match listener.accept().await {
+            Ok((stream, _)) => stream,
+            Err(err) => {
+                warn!("Failed to accept metrics connection: {}", err);
+                continue;
+            }
+        };
+        let metrics = metrics.clone();
+        let containers = containers.clone();
+        tokio::spawn(async move {
+            if let Err(err) = respond(stream, &metrics, &containers).await {
+                debug!("Failed to serve metrics: {}", err);
+            }
+        });
+    }
+}
+
+async fn respond(
+    mut stream: TcpStream,
+    metrics: &Metrics,
+    containers: &DashMap<String, Arc<Container>>,
+) -> std::io::Result<()> {
+    let mut request = Vec::new();
+    let mut buffer = [0; 1024];
+    // Only the request line matters, but the headers are read so that closing the connection
+    // doesn't reset it before the scraper has read the response.
+    while !request.windows(4).any(|window| window == b"\r\n\r\n") {
+        let read = match timeout(REQUEST_TIMEOUT, stream.read(&mut buffer)).await {
+            Ok(read) => read?,
+            Err(_) => return Ok(()),
+        };
+        if read == 0 || request.len() + read > REQUEST_MAX {
+            return Ok(());
+        }
+        request.extend_from_slice(&buffer[..read]);
+    }
+    let request_line = request
+        .split(|&byte| byte == b'\r')
+        .next()
+        .unwrap_or_default();
+    let mut parts = request_line.split(|&byte| byte == b' ');
+    let (status, body) = match (parts.next(), parts.next()) {
+        (Some(b"GET"), Some(b"/metrics")) => ("200 OK", metrics.render(containers).await),
+        (Some(b"GET"), _) => ("404 Not Found", String::new()),
+        _ => ("405 Method Not Allowed", String::new()),
+    };
+    let response = format!(
+        "HTTP/1.1 {}\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        body.len(),
+        body
+    );
+    stream.write_all(response.as_bytes()).await?;
+    stream.shutdown().await
+}
@@ -0,0 +1,857 @@
+use std::{
+    borrow::Cow,
+    fs::File,
+    io::{self, Write},
+    mem,
+    os::{fd::OwnedFd, unix::fs::FileTypeExt},
+    path::{Path, PathBuf},
+    sync::Mutex,
+    time::Duration,
+};
+
+use anyhow::{Context, Result};
+use clap::ValueEnum;
+use flate2::{write::GzEncoder, Compression};
+use nix::{fcntl::OFlag, libc, unistd::pipe2};
+use serde::Serialize;
+use time::{format_description::well_known::Rfc3339, OffsetDateTime};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::unix::pipe,
+    sync::{mpsc, oneshot},
+    task::JoinHandle,
+    time::{interval, sleep_until, timeout, Instant, Interval},
+};
+use tracing::warn;
+
+use crate::metrics::Metrics;
+
+const BUFFER_SIZE: usize = 32 * 1024;
+
+/// How long a write to a named pipe waits for its reader to make room. Output is discarded
+/// after that, until the reader catches up, so that a reader that stopped reading or a pipe that
+/// has none doesn't hold up the container.
+const FIFO_STALL_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// How long a stopped copy task gets to write out what is already in the pipe. A sink that
+/// blocks, e.g. a file on a hung network filesystem, can hold it up for good.
+const STOP_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// How container output is written to regular files. Named pipes are never compressed.
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum LogCompression {
+    /// Write output as is.
+    None,
+
+    /// Write a gzip stream, finished when the container exits or the sink is swapped.
+    Gzip,
+}
+
+/// The format of container output written to regular files. Named pipes always get the output as
+/// is.
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum LogFormat {
+    /// Write output as is.
+    Raw,
+
+    /// Write each line as a JSON object with its stream and the time it was read, like Docker's
+    /// json-file log driver. Lines split across reads end up in several objects, the last one
+    /// ending in a newline.
+    Json,
+}
+
+/// Which of a container's output streams a monitor copies.
+#[derive(Clone, Copy, Debug)]
+pub enum Stream {
+    Stdout,
+    Stderr,
+}
+
+impl Stream {
+    pub fn name(self) -> &'static str {
+        match self {
+            Stream::Stdout => "stdout",
+            Stream::Stderr => "stderr",
+        }
+    }
+}
+
+/// How a monitor writes container output.
+#[derive(Clone)]
+pub struct LogOptions {
+    /// How often to sync regular files to disk, if at all.
+    pub fsync_interval: Option<Duration>,
+
+    pub compression: LogCompression,
+
+    pub format: LogFormat,
+
+    /// When to rotate regular files, if at all.
+    pub rotation: Option<LogRotation>,
+
+    /// The most output per second read from the container, if limited. The container blocks on
+    /// the full pipe while reading is held back.
+    pub rate_limit: Option<u64>,
+
+    /// The most output written in total, if limited. Anything beyond is discarded.
+    pub output_limit: Option<u64>,
+
+    /// How long to wait for the pipe to be closed once the process writing it exited.
+    pub exit_drain_timeout: Duration,
+
+    /// Counts the output copied.
+    pub metrics: Metrics,
+}
+
+/// Size-based rotation of container output written to regular files.
+#[derive(Clone, Copy, Debug)]
+pub struct LogRotation {
+    /// The size at which a file is rotated.
+    pub max_size: u64,
+
+    /// How many rotated files to keep, `<path>.1` being the most recent.
+    pub max_files: u32,
+}
+
+/// Copies one of a container's output streams from a pipe into its sink, a file or named pipe,
+/// until the container and all its processes have closed the pipe. The sink can be swapped while
+/// the container runs.
+pub struct Monitor {
+    /// The path of the current sink.
+    path: Mutex<PathBuf>,
+
+    stream: Stream,
+
+    options: LogOptions,
+
+    /// Commands for the copy task.
+    commands: mpsc::UnboundedSender<Command>,
+
+    /// The copy task, until it has been drained.
+    task: Mutex<Option<JoinHandle<()>>>,
+}
+
+enum Command {
+    /// Flush the current sink and continue with a new one.
+    Swap {
+        sink: Box<Sink>,
+        done: oneshot::Sender<()>,
+    },
+
+    /// Write out what is already in the pipe, then stop.
+    Stop,
+}
+
+struct Sink {
+    file: SinkFile,
+
+    path: PathBuf,
+
+    /// Whether the sink is a regular file and can be synced.
+    regular: bool,
+
+    /// Rotation of a regular file, until rotating fails.
+    rotation: Option<LogRotation>,
+
+    /// The size of the file, including what this sink wrote to it.
+    size: u64,
+
+    /// The stream whose lines are written as JSON, if the sink is in the JSON format.
+    json_stream: Option<Stream>,
+
+    /// Compresses output into a buffer that is then written to the file.
+    encoder: Option<GzEncoder<Vec<u8>>>,
+
+    /// Whether the reader of a named pipe went away. Output is then discarded, as it has nowhere
+    /// to go until the sink is swapped.
+    reader_gone: bool,
+
+    /// Whether the named pipe is full and output is discarded until there is room again.
+    stalled: bool,
+}
+
+enum SinkFile {
+    File(tokio::fs::File),
+
+    /// A named pipe, written without blocking, see [`FIFO_STALL_TIMEOUT`]. A pipe opened
+    /// read-write because it had no reader yet can't see its reader go away, only stall.
+    Fifo(pipe::Sender),
+}
+
+impl Monitor {
+    /// Creates the pipe the container writes into and starts copying from it into `sink`.
+    /// Returns the monitor and the pipe's write end, which is handed to the container.
+    pub fn spawn(
+        sink: File,
+        path: &Path,
+        stream: Stream,
+        options: LogOptions,
+    ) -> Result<(Self, OwnedFd)> {
+        let (reader, writer) = pipe2(OFlag::O_CLOEXEC).context("Failed to create pipe")?;
+        let reader = pipe::Receiver::from_owned_fd(reader)?;
+        Ok((Self::start(reader, sink, path, stream, options)?, writer))
+    }
+
+    /// Starts copying from the master end of a container's pty into `sink`. The fd has to be in
+    /// non-blocking mode. A pty has no separate stderr, its output counts as stdout.
+    pub fn spawn_console(
+        console: OwnedFd,
+        sink: File,
+        path: &Path,
+        options: LogOptions,
+    ) -> Result<Self> {
+        let reader = pipe::Receiver::from_owned_fd_unchecked(console)?;
+        Self::start(reader, sink, path, Stream::Stdout, options)
+    }
+
+    fn start(
+        reader: pipe::Receiver,
+        sink: File,
+        path: &Path,
+        stream: Stream,
+        options: LogOptions,
+    ) -> Result<Self> {
+        let sink = Sink::new(sink, path, stream, &options)?;
+        let (tx, rx) = mpsc::unbounded_channel();
+        let limits = Limits::new(stream, &options);
+        let task = tokio::spawn(copy(
+            reader,
+            sink,
+            limits,
+            options.fsync_interval.map(interval),
+            rx,
+        ));
+        Ok(Self {
+            path: Mutex::new(path.to_owned()),
+            stream,
+            options,
+            commands: tx,
+            task: Mutex::new(Some(task)),
+        })
+    }
+
+    /// Returns the path of the current sink.
+    pub fn path(&self) -> PathBuf {
+        self.path.lock().unwrap().clone()
+    }
+
+    /// Replaces the sink. Output read before the swap has been written to the old sink once
+    /// this returns; anything the container writes afterwards goes to the new one.
+    pub async fn swap(&self, sink: File, path: &Path) -> Result<()> {
+        let sink = Sink::new(sink, path, self.stream, &self.options)?;
+        let (done, swapped) = oneshot::channel();
+        self.commands
+            .send(Command::Swap {
+                sink: Box::new(sink),
+                done,
+            })
+            .ok()
+            .context("Container output has been closed")?;
+        swapped.await.context("Container output has been closed")?;
+        *self.path.lock().unwrap() = path.to_owned();
+        Ok(())
+    }
+
+    /// Returns whether copying has stopped, e.g. because the container closed the pipe. The
+    /// sink can't be swapped anymore then.
+    pub fn is_closed(&self) -> bool {
+        self.commands.is_closed()
+    }
+
+    /// Waits up to `drain_timeout` for the container to close the pipe and its output to be
+    /// written. If it doesn't, e.g. because the container is still running, copying stops after
+    /// what is already in the pipe, and the sink is finished and synced, unless writing it takes
+    /// longer than [`STOP_TIMEOUT`] and copying is aborted. Returns whether the pipe was closed in
+    /// time.
+    pub async fn drain(&self, drain_timeout: Duration) -> bool {
+        let Some(mut task) = self.task.lock().unwrap().take() else {
+            return true;
+        };
+        if timeout(drain_timeout, &mut task).await.is_err() {
+            let _ = self.commands.send(Command::Stop);
+            if timeout(STOP_TIMEOUT, &mut task).await.is_err() {
+                warn!(
+                    "Writing the {} of a container is stuck, discarding the rest of it",
+                    self.stream.name()
+                );
+                task.abort();
+            }
+            return false;
+        }
+        true
+    }
+
+    /// Like [`Monitor::drain`], once the process writing the pipe exited, with the configured
+    /// exit drain timeout. A timeout means processes it left behind hold the pipe open, and
+    /// their later output is lost, which is logged.
+    pub async fn drain_exited(&self, process: &str) {
+        let drain_timeout = self.options.exit_drain_timeout;
+        if !self.drain(drain_timeout).await {
+            warn!(
+                "The {} of {} was still open {:?} after it exited, output may be truncated: \
+                 processes it left behind still hold it",
+                self.stream.name(),
+                process,
+                drain_timeout
+            );
+        }
+    }
+}
+
+impl Sink {
+    fn new(file: File, path: &Path, stream: Stream, options: &LogOptions) -> Result<Self> {
+        let metadata = file.metadata()?;
+        let regular = metadata.is_file();
+        let sink_file = if metadata.file_type().is_fifo() {
+            SinkFile::Fifo(pipe::Sender::from_file(file)?)
+        } else {
+            SinkFile::File(file.into())
+        };
+        let encoder = (regular && options.compression == LogCompression::Gzip)
+            .then(|| GzEncoder::new(Vec::new(), Compression::default()));
+        Ok(Self {
+            file: sink_file,
+            path: path.to_owned(),
+            regular,
+            rotation: options.rotation.filter(|_| regular),
+            size: metadata.len(),
+            json_stream: (regular && options.format == LogFormat::Json).then_some(stream),
+            encoder,
+            reader_gone: false,
+            stalled: false,
+        })
+    }
+
+    async fn write(&mut self, data: &[u8]) -> io::Result<()> {
+        match self.json_stream {
+            Some(stream) => self.write_bytes(&json_lines(stream, data)).await,
+            None => self.write_bytes(data).await,
+        }
+    }
+
+    async fn write_bytes(&mut self, data: &[u8]) -> io::Result<()> {
+        if self.reader_gone {
+            return Ok(());
+        }
+        let result = match &mut self.encoder {
+            Some(encoder) => {
+                encoder.write_all(data)?;
+                self.write_compressed().await
+            }
+            None => self.write_file(data).await,
+        };
+        match result {
+            Err(err) if err.kind() == io::ErrorKind::BrokenPipe => {
+                warn!("Reader of container output went away, discarding output");
+                self.reader_gone = true;
+                return Ok(());
+            }
+            result => result?,
+        }
+        if let Some(rotation) = self
+            .rotation
+            .filter(|rotation| self.size >= rotation.max_size)
+        {
+            if let Err(err) = self.rotate(rotation).await {
+                warn!(
+                    "Failed to rotate container log {:?}, no longer rotating it: {}",
+                    self.path, err
+                );
+                self.rotation = None;
+            }
+        }
+        Ok(())
+    }
+
+    async fn write_file(&mut self, data: &[u8]) -> io::Result<()> {
+        match &mut self.file {
+            SinkFile::File(file) => file.write_all(data).await?,
+            SinkFile::Fifo(fifo) => {
+                let stalled = !write_fifo(fifo, data, self.stalled).await?;
+                if stalled && !self.stalled {
+                    warn!(
+                        "Reader of {:?} is not keeping up, discarding output until it does",
+                        self.path
+                    );
+                } else if !stalled && self.stalled {
+                    warn!(
+                        "Reader of {:?} caught up, no longer discarding output",
+                        self.path
+                    );
+                }
+                self.stalled = stalled;
+            }
+        }
+        self.size += data.len() as u64;
+        Ok(())
+    }
+
+    /// Finishes the file and moves it to `<path>.1`, after moving older rotations up by one, then
+    /// continues in a new file. A compressed file gets a complete stream of its own.
+    async fn rotate(&mut self, rotation: LogRotation) -> io::Result<()> {
+        self.finish().await?;
+        // Even if rotating fails, output goes on in a new stream.
+        if self.encoder.is_some() {
+            self.encoder = Some(GzEncoder::new(Vec::new(), Compression::default()));
+        }
+        for n in (1..rotation.max_files).rev() {
+            match tokio::fs::rename(rotated_path(&self.path, n), rotated_path(&self.path, n + 1))
+                .await
+            {
+                Err(err) if err.kind() == io::ErrorKind::NotFound => {}
+                result => result?,
+            }
+        }
+        if rotation.max_files > 0 {
+            tokio::fs::rename(&self.path, rotated_path(&self.path, 1)).await?;
+        }
+        self.file = SinkFile::File(
+            tokio::fs::OpenOptions::new()
+                .create(true)
+                .truncate(true)
+                .write(true)
+                .custom_flags(libc::O_NOFOLLOW)
+                .open(&self.path)
+                .await?,
+        );
+        self.size = 0;
+        Ok(())
+    }
+
+    /// Writes out whatever the encoder has compressed so far.
+    async fn write_compressed(&mut self) -> io::Result<()> {
+        if let Some(encoder) = &mut self.encoder {
+            let compressed = mem::take(encoder.get_mut());
+            self.write_file(&compressed).await?;
+        }
+        Ok(())
+    }
+
+    /// Writes out all output received so far. Compressed output is flushed at a block boundary,
+    /// which costs some compression.
+    async fn flush(&mut self) -> io::Result<()> {
+        if self.reader_gone {
+            return Ok(());
+        }
+        if let Some(encoder) = &mut self.encoder {
+            encoder.flush()?;
+        }
+        self.write_compressed().await?;
+        self.flush_file().await
+    }
+
+    /// Like [`Sink::flush`], but also ends the compressed stream. Nothing can be written after.
+    async fn finish(&mut self) -> io::Result<()> {
+        if self.reader_gone {
+            return Ok(());
+        }
+        if let Some(encoder) = &mut self.encoder {
+            encoder.try_finish()?;
+        }
+        self.write_compressed().await?;
+        self.flush_file().await
+    }
+
+    async fn flush_file(&mut self) -> io::Result<()> {
+        match &mut self.file {
+            SinkFile::File(file) => file.flush().await,
+            SinkFile::Fifo(_) => Ok(()),
+        }
+    }
+
+    async fn sync(&self) {
+        if let SinkFile::File(file) = &self.file {
+            if self.regular {
+                if let Err(err) = file.sync_data().await {
+                    warn!("Failed to sync container log: {}", err);
+                }
+            }
+        }
+    }
+}
+
+/// Applies the rate and output limits to a monitor's output, and counts the output admitted.
+struct Limits {
+    rate_limit: Option<u64>,
+    output_limit: Option<u64>,
+    stream: Stream,
+    metrics: Metrics,
+
+    /// Output admitted so far.
+    total: u64,
+
+    /// When the output admitted so far has been read at the rate limit.
+    resume_at: Instant,
+}
+
+impl Limits {
+    fn new(stream: Stream, options: &LogOptions) -> Self {
+        Self {
+            rate_limit: options.rate_limit,
+            output_limit: options.output_limit,
+            stream,
+            metrics: options.metrics.clone(),
+            total: 0,
+            resume_at: Instant::now(),
+        }
+    }
+
+    /// Returns when reading may resume, if it is held back by the rate limit.
+    fn throttled_until(&self) -> Option<Instant> {
+        (self.resume_at > Instant::now()).then_some(self.resume_at)
+    }
+
+    /// Returns the part of `data` that is within the output limit, warning once it is reached.
+    /// Discarded output doesn't count against the rate limit, so that a capped container isn't
+    /// held back for nothing.
+    fn admit<'a>(&mut self, data: &'a [u8], sink: &Sink) -> &'a [u8] {
+        let admitted = match self.output_limit {
+            Some(limit) => {
+                let left = limit.saturating_sub(self.total);
+                if left > 0 && left <= data.len() as u64 {
+                    warn!(
+                        "Output to {:?} reached the limit of {} bytes, discarding further output",
+                        sink.path, limit
+                    );
+                }
+                &data[..data.len().min(left as usize)]
+            }
+            None => data,
+        };
+        self.total += admitted.len() as u64;
+        self.metrics.stdio_bytes(self.stream, admitted.len());
+        if let Some(rate) = self.rate_limit {
+            let delay = Duration::from_secs_f64(admitted.len() as f64 / rate as f64);
+            self.resume_at = self.resume_at.max(Instant::now()) + delay;
+        }
+        admitted
+    }
+}
+
+async fn copy(
+    mut reader: pipe::Receiver,
+    mut sink: Sink,
+    mut limits: Limits,
+    mut fsync_ticks: Option<Interval>,
+    mut commands: mpsc::UnboundedReceiver<Command>,
+) {
+    let mut buffer = vec![0; BUFFER_SIZE];
+    loop {
+        let throttled_until = limits.throttled_until();
+        tokio::select! {
+            read = reader.read(&mut buffer), if throttled_until.is_none() => match read {
+                Ok(0) => break,
+                Ok(n) => write_output(&mut sink, &mut limits, &buffer[..n]).await,
+                // A pty master reports EIO rather than EOF once the container has closed it.
+                Err(err) if err.raw_os_error() == Some(libc::EIO) => break,
+                Err(err) => {
+                    warn!("Failed to read container output: {}", err);
+                    break;
+                }
+            },
+            Some(command) = commands.recv() => match command {
+                Command::Swap { sink: new_sink, done } => {
+                    if let Err(err) = sink.finish().await {
+                        warn!("Failed to flush container output: {}", err);
+                    }
+                    if fsync_ticks.is_some() {
+                        sink.sync().await;
+                    }
+                    sink = *new_sink;
+                    let _ = done.send(());
+                }
+                Command::Stop => {
+                    loop {
+                        match reader.try_read(&mut buffer) {
+                            Ok(0) => break,
+                            Ok(n) => write_output(&mut sink, &mut limits, &buffer[..n]).await,
+                            Err(err) if err.kind() == io::ErrorKind::WouldBlock => break,
+                            Err(err) => {
+                                warn!("Failed to read container output: {}", err);
+                                break;
+                            }
+                        }
+                    }
+                    break;
+                }
+            },
+            // Reading resumes once the rate limit allows it.
+            _ = sleep_until(throttled_until.unwrap_or_else(Instant::now)),
+                if throttled_until.is_some() => {}
+            _ = tick(&mut fsync_ticks) => {
+                if let Err(err) = sink.flush().await {
+                    warn!("Failed to flush container output: {}", err);
+                }
+                sink.sync().await;
+            }
+        }
+    }
+    if let Err(err) = sink.finish().await {
+        warn!("Failed to flush container output: {}", err);
+    }
+    // The last output is the most interesting, so always make sure it is on disk.
+    sink.sync().await;
+}
+
+/// A line of output in Docker's json-file format.
+#[derive(Serialize)]
+struct JsonLine<'a> {
+    log: Cow<'a, str>,
+    stream: &'static str,
+    time: &'a str,
+}
+
+/// Formats output as JSON lines, see [`LogFormat::Json`]. Invalid UTF-8 is replaced, as JSON
+/// strings can't hold it.
+fn json_lines(stream: Stream, data: &[u8]) -> Vec<u8> {
+    let time = OffsetDateTime::now_utc()
+        .format(&Rfc3339)
+        .expect("current time is representable in RFC 3339");
+    let mut lines = Vec::with_capacity(data.len() + 64);
+    for line in data.split_inclusive(|&byte| byte == b'\n') {
+        let line = JsonLine {
+            log: String::from_utf8_lossy(line),
+            stream: stream.name(),
+            time: &time,
+        };
+        serde_json::to_writer(&mut lines, &line).expect("JSON lines serialize");
+        lines.push(b'\n');
+    }
+    lines
+}
+
+/// Returns the path of the `n`th rotation of a log file.
+pub fn rotated_path(path: &Path, n: u32) -> PathBuf {
+    let mut rotated = path.as_os_str().to_owned();
+    rotated.push(format!(".{}", n));
+    rotated.into()
+}
+
+/// Writes all of `data` into a named pipe, waiting up to [`FIFO_STALL_TIMEOUT`] for the reader
+/// to make room, or not at all if the pipe is already `stalled`. Returns `false` if the rest of
+/// the data had to be discarded.
+async fn write_fifo(fifo: &pipe::Sender, mut data: &[u8], stalled: bool) -> io::Result<bool> {
+    while !data.is_empty() {
+        match fifo.try_write(data) {
+            Ok(n) => data = &data[n..],
+            Err(err) if err.kind() == io::ErrorKind::WouldBlock => {
+                if stalled {
+                    return Ok(false);
+                }
+                match timeout(FIFO_STALL_TIMEOUT, fifo.writable()).await {
+                    Ok(writable) => writable?,
+                    Err(_) => return Ok(false),
+                }
+            }
+            Err(err) => return Err(err),
+        }
+    }
+    Ok(true)
+}
+
+/// Writes output read from the container into the sink, within the output limit, logging
+/// failures. Reading goes on even if the sink is broken so that the container doesn't block on a
+/// full pipe.
+async fn write_output(sink: &mut Sink, limits: &mut Limits, data: &[u8]) {
+    let data = limits.admit(data, sink);
+    if data.is_empty() {
+        return;
+    }
+    if let Err(err) = sink.write(data).await {
+        warn!("Failed to write container output: {}", err);
+    }
+}
+
+async fn tick(ticks: &mut Option<Interval>) {
+    match ticks {
+        Some(ticks) => {
+            ticks.tick().await;
+        }
+        None => std::future::pending().await,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        fs::OpenOptions,
+        io::{ErrorKind, Read},
+        os::unix::fs::OpenOptionsExt,
+        thread,
+    };
+
+    use nix::{sys::stat::Mode, unistd::mkfifo};
+
+    use super::*;
+
+    fn options() -> LogOptions {
+        LogOptions {
+            fsync_interval: None,
+            compression: LogCompression::None,
+            format: LogFormat::Raw,
+            rotation: None,
+            rate_limit: None,
+            output_limit: None,
+            exit_drain_timeout: Duration::from_secs(1),
+            metrics: Metrics::default(),
+        }
+    }
+
+    fn fifo(dir: &Path) -> PathBuf {
+        let path = dir.join("fifo");
+        mkfifo(&path, Mode::from_bits_truncate(0o600)).unwrap();
+        path
+    }
+
+    /// Reads what is in a non-blocking pipe, waiting up to a second for anything to arrive.
+    fn read_available(reader: &mut File) -> Vec<u8> {
+        let mut buffer = vec![0; BUFFER_SIZE];
+        for _ in 0..100 {
+            match reader.read(&mut buffer) {
+                Ok(n) if n > 0 => return buffer[..n].to_vec(),
+                Ok(_) => {}
+                Err(err) if err.kind() == ErrorKind::WouldBlock => {}
+                Err(err) => panic!("Failed to read pipe: {}", err),
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+        Vec::new()
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn fifo_output_is_discarded_once_reader_is_gone() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = fifo(dir.path());
+        let mut reader = OpenOptions::new()
+            .read(true)
+            .custom_flags(libc::O_NONBLOCK)
+            .open(&path)
+            .unwrap();
+        let sink = OpenOptions::new().write(true).open(&path).unwrap();
+        let (monitor, writer) = Monitor::spawn(sink, &path, Stream::Stdout, options()).unwrap();
+        let mut writer = File::from(writer);
+        writer.write_all(b"hello\n").unwrap();
+        assert_eq!(read_available(&mut reader), b"hello\n");
+        drop(reader);
+        // Far more than the pipes hold, so the container would block if the monitor did.
+        writer.write_all(&vec![b'x'; 1024 * 1024]).unwrap();
+        drop(writer);
+        assert!(monitor.drain(Duration::from_secs(5)).await);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn fifo_output_is_discarded_while_no_reader_reads() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = fifo(dir.path());
+        // Opened like a named pipe without a reader at create.
+        let sink = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .custom_flags(libc::O_NONBLOCK)
+            .open(&path)
+            .unwrap();
+        let (monitor, writer) = Monitor::spawn(sink, &path, Stream::Stdout, options()).unwrap();
+        let mut writer = File::from(writer);
+        writer.write_all(b"first\n").unwrap();
+        writer.write_all(&vec![b'x'; 1024 * 1024]).unwrap();
+        // What fit into the pipe is still there for a reader that connects late.
+        let mut reader = OpenOptions::new()
+            .read(true)
+            .custom_flags(libc::O_NONBLOCK)
+            .open(&path)
+            .unwrap();
+        drop(writer);
+        assert!(monitor.drain(Duration::from_secs(5)).await);
+        assert!(read_available(&mut reader).starts_with(b"first\n"));
+    }
+
+    fn limits(rate_limit: Option<u64>, output_limit: Option<u64>) -> Limits {
+        let options = LogOptions {
+            rate_limit,
+            output_limit,
+            ..options()
+        };
+        Limits::new(Stream::Stdout, &options)
+    }
+
+    fn sink(dir: &Path) -> Sink {
+        let path = dir.join("log");
+        Sink::new(
+            File::create(&path).unwrap(),
+            &path,
+            Stream::Stdout,
+            &options(),
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn json_lines_wraps_each_line() {
+        let lines = json_lines(Stream::Stderr, b"first\nsecond\npartial");
+        let lines: Vec<serde_json::Value> = lines
+            .split(|&byte| byte == b'\n')
+            .filter(|line| !line.is_empty())
+            .map(|line| serde_json::from_slice(line).unwrap())
+            .collect();
+        let logs: Vec<_> = lines
+            .iter()
+            .map(|line| line["log"].as_str().unwrap())
+            .collect();
+        assert_eq!(logs, ["first\n", "second\n", "partial"]);
+        for line in &lines {
+            assert_eq!(line["stream"], "stderr");
+            assert!(line["time"].as_str().unwrap().ends_with('Z'));
+        }
+    }
+
+    #[test]
+    fn json_lines_replaces_invalid_utf8() {
+        let lines = json_lines(Stream::Stdout, b"a\xffb\n");
+        let line: serde_json::Value = serde_json::from_slice(lines.trim_ascii_end()).unwrap();
+        assert_eq!(line["log"], "a\u{fffd}b\n");
+    }
+
+    #[test]
+    fn rotated_path_appends_the_rotation() {
+        let path = Path::new("/var/log/container.log");
+        assert_eq!(rotated_path(path, 1), Path::new("/var/log/container.log.1"));
+        assert_eq!(
+            rotated_path(path, 12),
+            Path::new("/var/log/container.log.12")
+        );
+    }
+
+    #[test]
+    fn admit_caps_output_at_the_limit() {
+        let dir = tempfile::tempdir().unwrap();
+        let sink = sink(dir.path());
+        let mut limits = limits(None, Some(10));
+        assert_eq!(limits.admit(b"123456", &sink), b"123456");
+        assert_eq!(limits.admit(b"789012", &sink), b"7890");
+        assert_eq!(limits.admit(b"345", &sink), b"");
+        assert!(limits.throttled_until().is_none());
+    }
+
+    #[test]
+    fn admit_holds_back_reading_at_the_rate_limit() {
+        let dir = tempfile::tempdir().unwrap();
+        let sink = sink(dir.path());
+        let mut limits = limits(Some(1000), None);
+        let start = Instant::now();
+        assert_eq!(limits.admit(&[0; 500], &sink).len(), 500);
+        let resume_at = limits.throttled_until().expect("reading is held back");
+        assert!(resume_at >= start + Duration::from_millis(500));
+        assert!(resume_at <= Instant::now() + Duration::from_millis(500));
+    }
+
+    #[test]
+    fn discarded_output_does_not_count_against_the_rate_limit() {
+        let dir = tempfile::tempdir().unwrap();
+        let sink = sink(dir.path());
+        let mut limits = limits(Some(1000), Some(100));
+        limits.admit(&[0; 100], &sink);
+        let resume_at = limits.throttled_until().expect("reading is held back");
+        assert_eq!(limits.admit(&[0; 10_000], &sink), b"");
+        assert_eq!(limits.throttled_until(), Some(resume_at));
+    }
+}
@@ -0,0 +1,150 @@
+use std::{fs, path::Path};
+
+use anyhow::{bail, Context, Result};
+use nix::{
+    errno::Errno,
+    mount::{mount, umount2, MntFlags, MsFlags},
+};
+use serde::{Deserialize, Serialize};
+use tracing::{debug, warn};
+
+/// The longest mount data the kernel accepts, one page.
+const MOUNT_DATA_MAX: usize = 4096;
+
+/// A mount of the container's rootfs, as passed by containerd's snapshotters.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Mount {
+    /// The filesystem type, e.g. `overlay` or `bind`.
+    pub kind: String,
+
+    pub source: String,
+
+    /// fstab style options, e.g. `ro` or `lowerdir=...`.
+    pub options: Vec<String>,
+}
+
+/// Mounts `mounts` on top of each other onto `target`, creating it if needed. Mounts made before
+/// a failing one are unmounted again.
+pub fn mount_all(mounts: &[Mount], target: &Path) -> Result<()> {
+    fs::create_dir_all(target).with_context(|| format!("Failed to create {:?}", target))?;
+    for (i, entry) in mounts.iter().enumerate() {
+        if let Err(err) = mount_one(entry, target) {
+            if i > 0 {
+                unmount_all(target);
+            }
+            return Err(err).with_context(|| {
+                format!(
+                    "Failed to mount {} {:?} on {:?}",
+                    entry.kind, entry.source, target
+                )
+            });
+        }
+    }
+    Ok(())
+}
+
+/// Unmounts everything mounted on `target`, logging failures. Mounts that are still busy, e.g.
+/// because a leftover process has files open in them, are detached.
+pub fn unmount_all(target: &Path) {
+    let mut unmounted = 0;
+    loop {
+        let result = match umount2(target, MntFlags::empty()) {
+            Err(Errno::EBUSY) => {
+                warn!("{:?} is busy, detaching it", target);
+                umount2(target, MntFlags::MNT_DETACH)
+            }
+            result => result,
+        };
+        match result {
+            Ok(()) => unmounted += 1,
+            // Nothing (more) is mounted there.
+            Err(Errno::EINVAL) | Err(Errno::ENOENT) => {
+                if unmounted == 0 {
+                    debug!("{:?} was already unmounted", target);
+                }
+                return;
+            }
+            Err(err) => {
+                warn!("Failed to unmount {:?}: {}", target, err);
+                return;
+            }
+        }
+    }
+}
+
+fn mount_one(entry: &Mount, target: &Path) -> Result<()> {
+    let (flags, data) = parse_options(&entry.options);
+    if data.len() >= MOUNT_DATA_MAX {
+        // Overlays with many layers hit this first, their lowerdirs are listed in the data.
+        bail!(
+            "mount options are {} bytes, more than the kernel's limit of {}",
+            data.len(),
+            MOUNT_DATA_MAX
+        );
+    }
+    // Bind mounts take the type of their source, and snapshotters pass them as `bind`.
+    let kind = (!flags.contains(MsFlags::MS_BIND)).then_some(entry.kind.as_str());
+    let data = (!data.is_empty()).then_some(data.as_str());
+    mount(Some(entry.source.as_str()), target, kind, flags, data)?;
+    // A bind mount ignores flags such as MS_RDONLY until it is remounted with them.
+    if flags.contains(MsFlags::MS_BIND) && !(flags - bind_flags()).is_empty() {
+        mount(
+            None::<&str>,
+            target,
+            None::<&str>,
+            (flags - MsFlags::MS_REC) | MsFlags::MS_REMOUNT,
+            None::<&str>,
+        )?;
+    }
+    Ok(())
+}
+
+fn bind_flags() -> MsFlags {
+    MsFlags::MS_BIND | MsFlags::MS_REC
+}
+
+/// Splits fstab style options into mount flags and the data passed to the filesystem, e.g.
+/// overlay's `lowerdir`, `upperdir` and `workdir`.
+fn parse_options(options: &[String]) -> (MsFlags, String) {
+    let mut flags = MsFlags::empty();
+    let mut data = Vec::new();
+    for option in options {
+        let (clear, flag) = match option.as_str() {
+            "async" => (true, MsFlags::MS_SYNCHRONOUS),
+            "atime" => (true, MsFlags::MS_NOATIME),
+            "bind" => (false, MsFlags::MS_BIND),
+            "defaults" => (false, MsFlags::empty()),
+            "dev" => (true, MsFlags::MS_NODEV),
+            "diratime" => (true, MsFlags::MS_NODIRATIME),
+            "dirsync" => (false, MsFlags::MS_DIRSYNC),
+            "exec" => (true, MsFlags::MS_NOEXEC),
+            "mand" => (false, MsFlags::MS_MANDLOCK),
+            "noatime" => (false, MsFlags::MS_NOATIME),
+            "nodev" => (false, MsFlags::MS_NODEV),
+            "nodiratime" => (false, MsFlags::MS_NODIRATIME),
+            "noexec" => (false, MsFlags::MS_NOEXEC),
+            "nomand" => (true, MsFlags::MS_MANDLOCK),
+            "norelatime" => (true, MsFlags::MS_RELATIME),
+            "nostrictatime" => (true, MsFlags::MS_STRICTATIME),
+            "nosuid" => (false, MsFlags::MS_NOSUID),
+            "rbind" => (false, bind_flags()),
+            "relatime" => (false, MsFlags::MS_RELATIME),
+            "remount" => (false, MsFlags::MS_REMOUNT),
+            "ro" => (false, MsFlags::MS_RDONLY),
+            "rw" => (true, MsFlags::MS_RDONLY),
+            "strictatime" => (false, MsFlags::MS_STRICTATIME),
+            "suid" => (true, MsFlags::MS_NOSUID),
+            "sync" => (false, MsFlags::MS_SYNCHRONOUS),
+            _ => {
+                data.push(option.as_str());
+                continue;
+            }
+        };
+        if clear {
+            flags.remove(flag);
+        } else {
+            flags.insert(flag);
+        }
+    }
+    (flags, data.join(","))
+}
@@ -0,0 +1,188 @@
+use std::{
+    fmt,
+    fs::File,
+    io,
+    os::{fd::AsFd, unix::process::CommandExt},
+    process::{Command, Output, Stdio},
+    time::Duration,
+};
+
+use anyhow::{Context, Result};
+use nix::{
+    errno::Errno,
+    sched::{setns, CloneFlags},
+};
+use tokio::time::timeout;
+
+use crate::process::PidRegistry;
+
+/// Namespaces that can be entered, in the order they are joined. The user namespace comes first
+/// so that the others are joined with the privileges it grants, and the mount namespace last.
+const NAMESPACES: [(&str, CloneFlags); 7] = [
+    ("user", CloneFlags::CLONE_NEWUSER),
+    ("ipc", CloneFlags::CLONE_NEWIPC),
+    ("uts", CloneFlags::CLONE_NEWUTS),
+    ("net", CloneFlags::CLONE_NEWNET),
+    ("pid", CloneFlags::CLONE_NEWPID),
+    ("cgroup", CloneFlags::CLONE_NEWCGROUP),
+    ("mnt", CloneFlags::CLONE_NEWNS),
+];
+
+/// Failures of [`run`] that callers tell apart from other errors, e.g. to report them with a
+/// specific gRPC status. Other failures are plain errors.
+#[derive(Debug)]
+pub enum NsenterError {
+    /// The request names no command or an unknown namespace.
+    InvalidArgument(String),
+
+    /// The process whose namespaces are entered no longer exists.
+    ProcessGone(String),
+
+    /// The shim isn't allowed to enter the namespaces.
+    PermissionDenied(String),
+}
+
+impl fmt::Display for NsenterError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            NsenterError::InvalidArgument(message)
+            | NsenterError::ProcessGone(message)
+            | NsenterError::PermissionDenied(message) => f.write_str(message),
+        }
+    }
+}
+
+impl std::error::Error for NsenterError {}
+
+/// Runs a command inside some of the namespaces of process `pid` and captures its output.
+///
+/// This requires CAP_SYS_ADMIN, plus CAP_SYS_PTRACE if `pid` belongs to another user. Joining a
+/// pid namespace only affects children, so the command itself keeps its pid in the shim's
+/// namespace while anything it spawns lives in the container's. A command still running after
+/// `time_limit` is killed and the call fails with [`Elapsed`](tokio::time::error::Elapsed).
+pub async fn run(
+    pids: &PidRegistry,
+    pid: i32,
+    namespaces: &[String],
+    args: &[String],
+    time_limit: Duration,
+) -> Result<Output> {
+    let Some((program, args)) = args.split_first() else {
+        return Err(NsenterError::InvalidArgument("No command given".to_string()).into());
+    };
+    if let Some(namespace) = namespaces
+        .iter()
+        .find(|namespace| !NAMESPACES.iter().any(|(name, _)| name == namespace))
+    {
+        return Err(
+            NsenterError::InvalidArgument(format!("Unknown namespace {}", namespace)).into(),
+        );
+    }
+    let mut files = Vec::new();
+    for (name, flag) in NAMESPACES {
+        if !namespaces.iter().any(|namespace| namespace == name) {
+            continue;
+        }
+        let path = format!("/proc/{}/ns/{}", pid, name);
+        let file = File::open(&path).map_err(|err| {
+            let message = format!("Failed to open {}: {}", path, err);
+            match err.kind() {
+                io::ErrorKind::NotFound => NsenterError::ProcessGone(message).into(),
+                io::ErrorKind::PermissionDenied => NsenterError::PermissionDenied(message).into(),
+                _ => anyhow::Error::from(err).context(format!("Failed to open {}", path)),
+            }
+        })?;
+        files.push((file, flag));
+    }
+
+    let mut cmd = Command::new(program);
+    cmd.args(args)
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped());
+    // SAFETY: `setns` is async-signal-safe and the namespace files are opened before forking.
+    unsafe {
+        cmd.pre_exec(move || {
+            for (file, flag) in &files {
+                setns(file.as_fd(), *flag)?;
+            }
+            Ok(())
+        });
+    }
+    let helper = pids.spawn(&mut cmd).map_err(|err| {
+        // Errors of `setns` in the child are reported as the spawn's.
+        if err.raw_os_error() == Some(Errno::EPERM as i32) {
+            NsenterError::PermissionDenied(format!("Failed to enter namespaces: {}", err)).into()
+        } else {
+            anyhow::Error::from(err).context("Failed to run command")
+        }
+    })?;
+    // Dropping the helper on timeout kills the command.
+    timeout(time_limit, helper.wait_with_output())
+        .await?
+        .context("Failed to run command")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Instant;
+
+    use tokio::time::error::Elapsed;
+
+    use crate::testing::{pids, TIMEOUT};
+
+    fn args(args: &[&str]) -> Vec<String> {
+        args.iter().map(|arg| arg.to_string()).collect()
+    }
+
+    #[tokio::test]
+    async fn invalid_requests_are_told_apart() {
+        let pid = std::process::id() as i32;
+        let err = run(&pids(), pid, &[], &[], TIMEOUT).await.unwrap_err();
+        assert!(matches!(
+            err.downcast_ref(),
+            Some(NsenterError::InvalidArgument(_))
+        ));
+        let err = run(&pids(), pid, &args(&["nope"]), &args(&["true"]), TIMEOUT)
+            .await
+            .unwrap_err();
+        assert!(matches!(
+            err.downcast_ref(),
+            Some(NsenterError::InvalidArgument(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn missing_process_is_told_apart() {
+        let err = run(
+            &pids(),
+            i32::MAX,
+            &args(&["net"]),
+            &args(&["true"]),
+            TIMEOUT,
+        )
+        .await
+        .unwrap_err();
+        assert!(matches!(
+            err.downcast_ref(),
+            Some(NsenterError::ProcessGone(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn commands_that_take_too_long_are_killed() {
+        let started = Instant::now();
+        let err = run(
+            &pids(),
+            std::process::id() as i32,
+            &[],
+            &args(&["sleep", "60"]),
+            Duration::from_millis(100),
+        )
+        .await
+        .unwrap_err();
+        assert!(err.is::<Elapsed>());
+        assert!(started.elapsed() < TIMEOUT);
+    }
+}
@@ -0,0 +1,93 @@
+use std::time::Duration;
+
+use anyhow::Result;
+use tokio::{
+    sync::oneshot,
+    task::{self, JoinHandle},
+    time::sleep,
+};
+use tracing::{debug, warn};
+
+use crate::{cgroup::Cgroup, events::Publisher};
+
+/// How often the cgroup's OOM kill count is checked.
+const OOM_POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Watches a container's cgroup for processes killed by the OOM killer, logging each kill and
+/// publishing a `TaskOOM` event for it.
+pub struct OomWatcher {
+    stop: oneshot::Sender<()>,
+    task: JoinHandle<()>,
+}
+
+impl OomWatcher {
+    pub fn spawn(container_id: &str, cgroup: Cgroup, events: Publisher) -> Self {
+        let (stop, stopped) = oneshot::channel();
+        let task = tokio::spawn(watch(container_id.to_string(), cgroup, events, stopped));
+        Self { stop, task }
+    }
+
+    /// Stops watching after a last check, so that an OOM kill that ended the container is still
+    /// reported.
+    pub async fn stop(self) {
+        let _ = self.stop.send(());
+        let _ = self.task.await;
+    }
+}
+
+async fn watch(
+    container_id: String,
+    cgroup: Cgroup,
+    events: Publisher,
+    mut stopped: oneshot::Receiver<()>,
+) {
+    let mut kills = match oom_kills(&cgroup).await {
+        Ok(Some(kills)) => kills,
+        Ok(None) => {
+            debug!(
+                "Cgroup {} doesn't count OOM kills, not watching container {}",
+                cgroup, container_id
+            );
+            return;
+        }
+        Err(err) => {
+            warn!(
+                "Failed to watch container {} for OOM kills: {:#}",
+                container_id, err
+            );
+            return;
+        }
+    };
+    loop {
+        let stop = tokio::select! {
+            _ = sleep(OOM_POLL_INTERVAL) => false,
+            _ = &mut stopped => true,
+        };
+        match oom_kills(&cgroup).await {
+            Ok(Some(current)) if current > kills => {
+                warn!(
+                    "Container {} had {} process(es) killed by the OOM killer",
+                    container_id,
+                    current - kills
+                );
+                kills = current;
+                events.task_oom(&container_id);
+            }
+            Ok(_) => {}
+            // The cgroup is gone once the container has been deleted.
+            Err(err) => debug!(
+                "Failed to read OOM kills of container {}: {:#}",
+                container_id, err
+            ),
+        }
+        if stop {
+            return;
+        }
+    }
+}
+
+/// Reads the cgroup's OOM kill count on a blocking thread, as the read may back off.
+async fn oom_kills(cgroup: &Cgroup) -> Result<Option<u64>> {
+    let cgroup = cgroup.clone();
+    task::spawn_blocking(move || cgroup.oom_kills()).await?
+}
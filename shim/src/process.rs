@@ -0,0 +1,512 @@
+use std::{
+    io,
+    os::{
+        fd::{AsFd, AsRawFd, OwnedFd},
+        unix::process::ExitStatusExt,
+    },
+    path::PathBuf,
+    process::{self, Child, Command, Output},
+    sync::Arc,
+};
+
+use anyhow::{Context, Result};
+use dashmap::{mapref::entry::Entry, DashMap};
+use nix::{
+    ioctl_write_ptr_bad, libc,
+    sys::signal::{kill, Signal},
+    unistd::Pid,
+};
+use prost_types::Timestamp;
+use time::OffsetDateTime;
+use tokio::{
+    io::AsyncReadExt,
+    net::unix::pipe,
+    sync::{oneshot, watch, Mutex, RwLock},
+};
+use tracing::warn;
+
+use crate::{
+    container::Status,
+    monitor::Monitor,
+    signal::{self, ExitStatus},
+    utils::timestamp,
+};
+
+/// The lifecycle of a process in a container, either its init process or one started with exec.
+pub struct Process {
+    /// The process ID, 0 until the process has been created.
+    pid: RwLock<i32>,
+
+    status: RwLock<Status>,
+
+    exit_status: RwLock<ExitStatus>,
+
+    exited_at: RwLock<Option<OffsetDateTime>>,
+
+    /// The process' exit once it has exited, watched by waiters.
+    exit: watch::Sender<Option<Exit>>,
+
+    /// The master end of the process' pty, if it was started with a terminal.
+    console: RwLock<Option<OwnedFd>>,
+}
+
+/// How a process exited, as sent to its waiters.
+#[derive(Clone, Copy, Debug)]
+pub struct Exit {
+    pub exit_status: ExitStatus,
+    pub exited_at: Timestamp,
+}
+
+/// A process started in a running container with `runtime exec`.
+pub struct Exec {
+    /// The exec ID, unique within the container.
+    pub id: String,
+
+    /// A file or named pipe copied into the process' stdin, or empty for none.
+    pub stdin: PathBuf,
+
+    /// The process' stdout and stderr paths, or empty to discard the output.
+    pub stdout: PathBuf,
+    pub stderr: PathBuf,
+
+    /// The OCI process spec the runtime is given, written into the bundle.
+    pub spec: PathBuf,
+
+    pub process: Arc<Process>,
+
+    /// Copy the process' output into its sinks once it has been started.
+    pub stdout_monitor: RwLock<Option<Monitor>>,
+    pub stderr_monitor: RwLock<Option<Monitor>>,
+
+    /// The write end of the process' stdin pipe, until it is closed. Shared with the task
+    /// copying the stdin path into it.
+    pub stdin_pipe: Arc<Mutex<Option<pipe::Sender>>>,
+
+    /// Set once stdin is closed, which stops the task copying into it.
+    pub stdin_closed: watch::Sender<bool>,
+}
+
+/// Maps the pids of the containers' processes to their container and exec ID, so that the reaper
+/// can route an exit to the right process. Helper commands the shim runs itself, such as the OCI
+/// runtime, are registered too, so that the reaper hands their exit back rather than reaping
+/// them as orphans.
+#[derive(Clone, Default)]
+pub struct PidRegistry {
+    registrations: Arc<DashMap<i32, Registration>>,
+
+    /// Held for reading while a helper is spawned and registered, and for writing while reaping,
+    /// so that a helper is never reaped before it has been registered.
+    spawning: Arc<std::sync::RwLock<()>>,
+}
+
+struct Registration {
+    owner: Owner,
+
+    /// Refers to the registered process itself rather than its pid, so that the exit of another
+    /// process reusing the pid is not mistaken for its own. `None` on kernels without pidfds.
+    pidfd: Option<OwnedFd>,
+}
+
+enum Owner {
+    /// A container's init process, or one of its exec'd processes.
+    Container {
+        container_id: String,
+        exec_id: Option<String>,
+    },
+
+    /// A helper command, whose exit is sent to its [`Helper`].
+    Helper(oneshot::Sender<ExitStatus>),
+}
+
+/// A helper command spawned with [`PidRegistry::spawn`]. It is killed if dropped before it has
+/// exited, e.g. when waiting for it times out.
+pub struct Helper {
+    child: Child,
+    exited: oneshot::Receiver<ExitStatus>,
+    pids: PidRegistry,
+    done: bool,
+}
+
+/// A child process reaped by [`PidRegistry::reap`].
+pub struct Reaped {
+    pub pid: i32,
+
+    pub exit_status: ExitStatus,
+
+    /// The container and exec ID the process was registered with, `None` for orphans.
+    pub owner: Option<(String, Option<String>)>,
+}
+
+impl PidRegistry {
+    /// Registers a newly started process. Returns `false`, without registering it, if it has
+    /// already been reaped, e.g. as an orphan before its pid was known.
+    pub fn register(&self, pid: i32, container_id: &str, exec_id: Option<&str>) -> bool {
+        // Held while checking, so that the reaper either reaped the process before or finds it
+        // registered.
+        let entry = self.registrations.entry(pid);
+        let pidfd = match signal::pidfd_open(pid) {
+            Ok(pidfd) => Some(pidfd),
+            Err(nix::Error::ESRCH) => return false,
+            // Fall back to the pid alone, e.g. before Linux 5.3.
+            Err(_) => {
+                if let Err(nix::Error::ESRCH) = kill(Pid::from_raw(pid), None) {
+                    return false;
+                }
+                None
+            }
+        };
+        entry.insert(Registration {
+            owner: Owner::Container {
+                container_id: container_id.to_string(),
+                exec_id: exec_id.map(str::to_string),
+            },
+            pidfd,
+        });
+        true
+    }
+
+    /// Spawns a helper command and registers it, so that its exit can be waited for with
+    /// [`Helper::wait_with_output`]. Its stdout and stderr are captured if they are piped.
+    pub fn spawn(&self, cmd: &mut Command) -> io::Result<Helper> {
+        let _spawning = self.spawning.read().unwrap();
+        let child = cmd.spawn()?;
+        let pid = child.id() as i32;
+        // Can't fail with ESRCH, the child is only reaped once it is registered.
+        let pidfd = signal::pidfd_open(pid).ok();
+        let (tx, exited) = oneshot::channel();
+        self.registrations.insert(
+            pid,
+            Registration {
+                owner: Owner::Helper(tx),
+                pidfd,
+            },
+        );
+        Ok(Helper {
+            child,
+            exited,
+            pids: self.clone(),
+            done: false,
+        })
+    }
+
+    /// Signals a registered process. Processes that are no longer registered have exited, and
+    /// their pid may belong to someone else by now, so they are not signalled.
+    pub fn signal(&self, pid: i32, signal: Signal) -> Result<()> {
+        let Some(registration) = self.registrations.get(&pid) else {
+            warn!("Process {} has exited, ignoring signal {}", pid, signal);
+            return Ok(());
+        };
+        let pidfd = registration.pidfd.as_ref().map(AsFd::as_fd);
+        signal::forward_signal(Pid::from_raw(pid), pidfd, signal)
+    }
+
+    pub fn unregister(&self, pid: i32) {
+        self.registrations.remove(&pid);
+    }
+
+    /// Reaps a container's process registered with `pid` if it has exited, e.g. when the
+    /// runtime reports it stopped but the reaper has not seen its exit yet. Like
+    /// [`PidRegistry::reap`] it goes through the pidfd, so a process that reused the pid is left
+    /// alone. Returns `None` if the process is still running, or is no longer registered because
+    /// the reaper got to it first.
+    pub fn reap_registered(
+        &self,
+        pid: i32,
+        container_id: &str,
+        exec_id: Option<&str>,
+    ) -> Option<ExitStatus> {
+        let _spawning = self.spawning.write().unwrap();
+        let Entry::Occupied(entry) = self.registrations.entry(pid) else {
+            return None;
+        };
+        match &entry.get().owner {
+            Owner::Container {
+                container_id: owner,
+                exec_id: owner_exec_id,
+            } if owner == container_id && owner_exec_id.as_deref() == exec_id => {}
+            _ => return None,
+        }
+        let exit_status = match &entry.get().pidfd {
+            Some(pidfd) => signal::try_reap_pidfd(pidfd.as_fd()),
+            None => signal::try_reap(Pid::from_raw(pid)),
+        }?;
+        entry.remove();
+        Some(exit_status)
+    }
+
+    /// Reaps an exited child. A registered process is reaped through its pidfd, so that an
+    /// unrelated process that got the same pid is reaped as an orphan instead. The exit of a
+    /// helper is sent to its [`Helper`] instead of being returned.
+    pub fn reap(&self, pid: i32) -> Option<Reaped> {
+        let reaped = |exit_status, owner| Reaped {
+            pid,
+            exit_status,
+            owner,
+        };
+        let _spawning = self.spawning.write().unwrap();
+        match self.registrations.entry(pid) {
+            Entry::Occupied(entry) => {
+                let exit_status = match &entry.get().pidfd {
+                    Some(pidfd) => signal::try_reap_pidfd(pidfd.as_fd()),
+                    None => signal::try_reap(Pid::from_raw(pid)),
+                };
+                if let Some(exit_status) = exit_status {
+                    return match entry.remove().owner {
+                        Owner::Container {
+                            container_id,
+                            exec_id,
+                        } => Some(reaped(exit_status, Some((container_id, exec_id)))),
+                        Owner::Helper(tx) => {
+                            let _ = tx.send(exit_status);
+                            None
+                        }
+                    };
+                }
+                // The registered process is gone without us having reaped it, so the pid no
+                // longer belongs to it.
+                match entry.remove().owner {
+                    Owner::Container { container_id, .. } => warn!(
+                        "Pid {} of container {} was reused by another process",
+                        pid, container_id
+                    ),
+                    Owner::Helper(_) => {
+                        warn!("Pid {} of a helper was reused by another process", pid)
+                    }
+                }
+                signal::try_reap(Pid::from_raw(pid)).map(|exit_status| reaped(exit_status, None))
+            }
+            // The entry is held while reaping, see `register`.
+            Entry::Vacant(_entry) => {
+                signal::try_reap(Pid::from_raw(pid)).map(|exit_status| reaped(exit_status, None))
+            }
+        }
+    }
+}
+
+impl Helper {
+    /// Waits for the command to exit, reading its piped stdout and stderr meanwhile.
+    pub async fn wait_with_output(mut self) -> io::Result<Output> {
+        let stdout = read_pipe(self.child.stdout.take().map(OwnedFd::from));
+        let stderr = read_pipe(self.child.stderr.take().map(OwnedFd::from));
+        let (stdout, stderr) = tokio::try_join!(stdout, stderr)?;
+        let exit_status = (&mut self.exited)
+            .await
+            .map_err(|_| io::Error::other("Helper was reaped without its exit"))?;
+        self.done = true;
+        let status = match exit_status {
+            ExitStatus::Exited(code) => process::ExitStatus::from_raw(code << 8),
+            ExitStatus::Signaled(signal) => process::ExitStatus::from_raw(signal),
+        };
+        Ok(Output {
+            status,
+            stdout,
+            stderr,
+        })
+    }
+}
+
+impl Drop for Helper {
+    fn drop(&mut self) {
+        if !self.done {
+            // The reaper still reaps it once it has been killed.
+            let _ = self.pids.signal(self.child.id() as i32, Signal::SIGKILL);
+        }
+    }
+}
+
+async fn read_pipe(fd: Option<OwnedFd>) -> io::Result<Vec<u8>> {
+    let mut output = Vec::new();
+    if let Some(fd) = fd {
+        pipe::Receiver::from_owned_fd(fd)?
+            .read_to_end(&mut output)
+            .await?;
+    }
+    Ok(output)
+}
+
+impl Process {
+    pub fn new(status: Status) -> Self {
+        Self {
+            pid: RwLock::new(0),
+            status: RwLock::new(status),
+            exit_status: RwLock::new(ExitStatus::Exited(0)),
+            exited_at: RwLock::new(None),
+            exit: watch::Sender::new(None),
+            console: RwLock::new(None),
+        }
+    }
+
+    pub async fn pid(&self) -> i32 {
+        *self.pid.read().await
+    }
+
+    pub async fn status(&self) -> Status {
+        *self.status.read().await
+    }
+
+    pub async fn exit_status(&self) -> ExitStatus {
+        *self.exit_status.read().await
+    }
+
+    pub async fn exited_at(&self) -> Option<Timestamp> {
+        self.exited_at.read().await.map(timestamp)
+    }
+
+    /// Returns when the process exited, see [`Process::exited_at`] for the protobuf form.
+    pub async fn exit_time(&self) -> Option<OffsetDateTime> {
+        *self.exited_at.read().await
+    }
+
+    /// Sets the pid and status together, so that no one sees the status without the pid.
+    pub async fn set_pid_status(&self, pid: i32, status: Status) {
+        let mut pid_guard = self.pid.write().await;
+        let mut status_guard = self.status.write().await;
+        *pid_guard = pid;
+        *status_guard = status;
+    }
+
+    pub async fn set_pid(&self, pid: i32) {
+        *self.pid.write().await = pid;
+    }
+
+    pub async fn set_status(&self, status: Status) {
+        *self.status.write().await = status;
+    }
+
+    /// Marks the process as stopped and notifies waiters. `record` is called with the exit
+    /// before anyone is notified, so that anyone told about the exit can find what it recorded.
+    pub async fn set_exited(
+        &self,
+        exit_status: ExitStatus,
+        record: impl FnOnce(ExitStatus, OffsetDateTime),
+    ) {
+        let mut status_guard = self.status.write().await;
+        let mut exit_status_guard = self.exit_status.write().await;
+        let mut exited_at_guard = self.exited_at.write().await;
+        let exited_at = OffsetDateTime::now_utc();
+        *status_guard = Status::STOPPED;
+        *exit_status_guard = exit_status;
+        *exited_at_guard = Some(exited_at);
+        record(exit_status, exited_at);
+        self.exit.send_replace(Some(Exit {
+            exit_status,
+            exited_at: timestamp(exited_at),
+        }));
+    }
+
+    /// Marks the process as having stopped at `exited_at` without notifying anyone, e.g. for a
+    /// container recovered from a previous daemon.
+    pub async fn restore_exited(&self, exit_status: ExitStatus, exited_at: OffsetDateTime) {
+        *self.status.write().await = Status::STOPPED;
+        *self.exit_status.write().await = exit_status;
+        *self.exited_at.write().await = Some(exited_at);
+        self.exit.send_replace(Some(Exit {
+            exit_status,
+            exited_at: timestamp(exited_at),
+        }));
+    }
+
+    /// Waits for the process to exit and returns how it exited, right away if it already has.
+    /// The exit is kept apart from the process' state, so that a waiter gets the exit it was
+    /// woken for even if the process is deleted meanwhile.
+    pub async fn wait(&self) -> Exit {
+        let mut exit = self.exit.subscribe();
+        let exit = exit
+            .wait_for(Option::is_some)
+            .await
+            .expect("the sender lives as long as the process");
+        exit.expect("waited for the exit")
+    }
+
+    pub async fn set_console(&self, console: OwnedFd) {
+        *self.console.write().await = Some(console);
+    }
+
+    /// Sets the window size of the process' terminal, which sends it a SIGWINCH.
+    pub async fn resize_console(&self, width: u16, height: u16) -> Result<()> {
+        let console = self.console.read().await;
+        let console = console.as_ref().context("Process has no terminal")?;
+        let size = libc::winsize {
+            ws_row: height,
+            ws_col: width,
+            ws_xpixel: 0,
+            ws_ypixel: 0,
+        };
+        unsafe { set_window_size(console.as_raw_fd(), &size) }
+            .context("Failed to set terminal size")?;
+        Ok(())
+    }
+}
+
+ioctl_write_ptr_bad!(set_window_size, libc::TIOCSWINSZ, libc::winsize);
+
+impl Exec {
+    pub fn new(id: &str, stdin: PathBuf, stdout: PathBuf, stderr: PathBuf, spec: PathBuf) -> Self {
+        Self {
+            id: id.to_string(),
+            stdin,
+            stdout,
+            stderr,
+            spec,
+            process: Arc::new(Process::new(Status::CREATED)),
+            stdout_monitor: RwLock::new(None),
+            stderr_monitor: RwLock::new(None),
+            stdin_pipe: Arc::new(Mutex::new(None)),
+            stdin_closed: watch::Sender::new(false),
+        }
+    }
+
+    /// Closes the process' stdin, so that it reads EOF once it has read what was written.
+    pub async fn close_stdin(&self) {
+        self.stdin_closed.send_replace(true);
+        *self.stdin_pipe.lock().await = None;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use tokio::{task::JoinSet, time::timeout};
+
+    use super::*;
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn waiters_around_the_exit_all_see_it() {
+        for _ in 0..20 {
+            let process = Arc::new(Process::new(Status::RUNNING));
+            let mut waiters = JoinSet::new();
+            for i in 0..200 {
+                let process = process.clone();
+                waiters.spawn(async move {
+                    // Spread the waiters around the exit, some subscribing after it.
+                    if i % 4 == 0 {
+                        tokio::task::yield_now().await;
+                    }
+                    process.wait().await.exit_status
+                });
+            }
+            let exiting = process.clone();
+            tokio::spawn(async move {
+                exiting.set_exited(ExitStatus::Exited(3), |_, _| {}).await;
+            });
+            let results = timeout(Duration::from_secs(10), waiters.join_all())
+                .await
+                .expect("a waiter missed the exit");
+            assert!(results
+                .into_iter()
+                .all(|exit_status| exit_status == ExitStatus::Exited(3)));
+        }
+    }
+
+    #[tokio::test]
+    async fn wait_after_exit_returns_right_away() {
+        let process = Process::new(Status::RUNNING);
+        process.set_exited(ExitStatus::Signaled(9), |_, _| {}).await;
+        let exit = timeout(Duration::from_secs(1), process.wait())
+            .await
+            .expect("wait blocked on an exited process");
+        assert_eq!(exit.exit_status, ExitStatus::Signaled(9));
+        assert_eq!(process.status().await, Status::STOPPED);
+    }
+}
@@ -0,0 +1,488 @@
+use std::{
+    env, fs, io,
+    os::unix::{fs::PermissionsExt, process::ExitStatusExt},
+    path::{Path, PathBuf},
+    process::{Command, Stdio},
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use anyhow::{bail, Context, Result};
+use serde::{Deserialize, Serialize};
+use tokio::time::timeout;
+use tracing::error;
+
+use crate::{config::Config, container::ContainerError, metrics::Metrics, process::PidRegistry};
+
+/// How much of the runtime's stderr is kept in errors, in bytes.
+const OUTPUT_MAX: usize = 4096;
+
+/// The OCI runtime used to manage containers.
+pub struct Runtime {
+    /// Path to the OCI runtime executable.
+    pub path: PathBuf,
+
+    /// Root directory of the runtime's state, unless a container's options set one.
+    root: Option<PathBuf>,
+
+    /// Whether the runtime manages cgroups through systemd, unless a container's options say so.
+    systemd_cgroup: bool,
+
+    /// How often a create that failed with a transient error is retried.
+    pub create_retries: u32,
+
+    /// Substrings of runtime log errors that mark a failure as transient.
+    transient_errors: Vec<String>,
+
+    /// How long an invocation may take before the runtime is killed.
+    invocation_timeout: Duration,
+
+    /// Where invocations are registered, so that the reaper hands back their exit.
+    pids: PidRegistry,
+
+    /// Guards against repeatedly invoking a broken runtime.
+    breaker: CircuitBreaker,
+
+    /// Records how long invocations take.
+    metrics: Metrics,
+}
+
+impl Runtime {
+    pub fn new(config: &Config, pids: PidRegistry, metrics: Metrics) -> Self {
+        Self {
+            path: config.runtime.clone(),
+            root: config.runtime_root.clone(),
+            systemd_cgroup: config.systemd_cgroup,
+            create_retries: config.create_retries,
+            transient_errors: config.create_retry_errors.clone(),
+            invocation_timeout: config.runtime_timeout,
+            pids,
+            breaker: CircuitBreaker::new(
+                config.runtime_failure_threshold,
+                config.runtime_failure_cooldown,
+            ),
+            metrics,
+        }
+    }
+
+    /// Returns a command invoking the runtime for a container with the given options, with
+    /// stdin set to null.
+    pub fn command(&self, options: &RuntimeOptions) -> Command {
+        let mut cmd = Command::new(options.binary_name.as_ref().unwrap_or(&self.path));
+        // Used for every invocation, so that the runtime finds the container again.
+        if let Some(root) = options.root.as_ref().or(self.root.as_ref()) {
+            cmd.arg("--root").arg(root);
+        }
+        if self.systemd_cgroup(options) {
+            cmd.arg("--systemd-cgroup");
+        }
+        cmd.stdin(Stdio::null());
+        cmd
+    }
+
+    /// Returns whether the runtime manages a container's cgroups through systemd.
+    pub fn systemd_cgroup(&self, options: &RuntimeOptions) -> bool {
+        self.systemd_cgroup || options.systemd_cgroup
+    }
+
+    /// Runs an OCI runtime command to completion. If stderr is piped, its contents are included
+    /// in the error if the runtime fails, and logged when it is deemed broken. A runtime that
+    /// takes longer than `--runtime-timeout`, or whose invocation is dropped before it finishes,
+    /// is killed. On timeout an `Elapsed` error is returned.
+    pub async fn run(&self, cmd: Command) -> Result<()> {
+        self.output(cmd).await?;
+        Ok(())
+    }
+
+    /// Returns whether the errors from a failed invocation's log indicate a transient failure.
+    pub fn is_transient(&self, errors: &[String]) -> bool {
+        errors.iter().any(|error| {
+            self.transient_errors
+                .iter()
+                .any(|transient| error.contains(transient.as_str()))
+        })
+    }
+
+    /// Returns the state of a container as reported by `runtime state`.
+    pub async fn state(&self, id: &str, options: &RuntimeOptions) -> Result<State> {
+        let state = self.state_json(id, options).await?;
+        serde_json::from_slice(&state).context("Failed to parse OCI runtime state")
+    }
+
+    /// Returns the raw JSON output of `runtime state`, including runtime specific fields.
+    pub async fn state_json(&self, id: &str, options: &RuntimeOptions) -> Result<Vec<u8>> {
+        let mut cmd = self.command(options);
+        cmd.arg("state").arg(id);
+        cmd.stdout(Stdio::piped()).stderr(Stdio::piped());
+        self.output(cmd).await
+    }
+
+    /// Returns the pids of the processes in a container as reported by `runtime ps`.
+    pub async fn ps(&self, id: &str, options: &RuntimeOptions) -> Result<Vec<i32>> {
+        let mut cmd = self.command(options);
+        cmd.arg("ps").arg("--format").arg("json").arg(id);
+        cmd.stdout(Stdio::piped()).stderr(Stdio::piped());
+        let output = self.output(cmd).await?;
+        // runc prints `null` rather than an empty list.
+        let pids: Option<Vec<i32>> =
+            serde_json::from_slice(&output).context("Failed to parse OCI runtime ps output")?;
+        Ok(pids.unwrap_or_default())
+    }
+
+    /// Returns the runtime's JSON encoded features as reported by `runtime features`, which
+    /// older runtimes don't support.
+    pub async fn features(&self, options: &RuntimeOptions) -> Result<Vec<u8>> {
+        let mut cmd = self.command(options);
+        cmd.arg("features");
+        cmd.stdout(Stdio::piped()).stderr(Stdio::piped());
+        self.output(cmd).await
+    }
+
+    /// Returns the runtime's name and version as reported by `runtime --version`.
+    pub async fn version(&self, options: &RuntimeOptions) -> Result<Version> {
+        let mut cmd = self.command(options);
+        cmd.arg("--version");
+        cmd.stdout(Stdio::piped()).stderr(Stdio::piped());
+        let output = self.output(cmd).await?;
+        Version::parse(&String::from_utf8_lossy(&output))
+            .context("Failed to parse OCI runtime version")
+    }
+
+    /// Like [`Runtime::run`], but returns the captured stdout if it was piped.
+    async fn output(&self, mut cmd: Command) -> Result<Vec<u8>> {
+        self.breaker.check()?;
+        let started_at = Instant::now();
+        let child = match self.pids.spawn(&mut cmd) {
+            Ok(child) => child,
+            Err(err) => {
+                let message = format!("Failed to spawn OCI runtime: {}", err);
+                self.breaker
+                    .record_failure(FailureKind::Spawn(err.kind()), &message);
+                bail!(message);
+            }
+        };
+        // Dropping the child on timeout kills it, and the reaper reaps it.
+        let output = timeout(self.invocation_timeout, child.wait_with_output()).await;
+        self.metrics.runtime_command(&cmd, started_at.elapsed());
+        let output = output.map_err(|elapsed| {
+            let message = format!(
+                "OCI runtime did not finish within {:?}",
+                self.invocation_timeout
+            );
+            error!("{}, killed it", message);
+            self.breaker.record_failure(FailureKind::Timeout, &message);
+            anyhow::Error::new(elapsed).context(message)
+        })?;
+        match output {
+            Ok(output) if output.status.success() => {
+                self.breaker.record_success();
+                Ok(output.stdout)
+            }
+            Ok(output) => {
+                let stderr = String::from_utf8_lossy(&output.stderr);
+                let stderr = truncate(stderr.trim(), OUTPUT_MAX);
+                // An exit code is the runtime's answer, e.g. to a container that doesn't exist,
+                // so only a runtime killed by a signal counts as broken.
+                match output.status.signal() {
+                    Some(signal) => self
+                        .breaker
+                        .record_failure(FailureKind::Signaled(signal), stderr),
+                    None => self.breaker.record_success(),
+                }
+                let message = if stderr.is_empty() {
+                    format!("OCI runtime exited with status {}", output.status)
+                } else {
+                    format!(
+                        "OCI runtime exited with status {}: {}",
+                        output.status, stderr
+                    )
+                };
+                bail!(ContainerError::RuntimeFailed(message))
+            }
+            Err(err) => bail!("Failed to wait for OCI runtime: {}", err),
+        }
+    }
+}
+
+/// Resolves the runtime binary like spawning it would, searching `$PATH` for a bare name such
+/// as `runc`, and checks that it is an executable file. Checked up front, so that a
+/// misconfigured runtime fails the daemon's start rather than every request.
+pub fn resolve_executable(path: &Path) -> Result<PathBuf> {
+    if path.components().count() > 1 {
+        check_executable(path)?;
+        return Ok(path.to_owned());
+    }
+    let search_path = env::var_os("PATH").unwrap_or_default();
+    env::split_paths(&search_path)
+        .map(|dir| dir.join(path))
+        .find(|candidate| check_executable(candidate).is_ok())
+        .with_context(|| format!("OCI runtime {:?} not found in $PATH", path))
+}
+
+fn check_executable(path: &Path) -> Result<()> {
+    let metadata =
+        fs::metadata(path).with_context(|| format!("OCI runtime {:?} not found", path))?;
+    if !metadata.is_file() {
+        bail!("OCI runtime {:?} is not a file", path);
+    }
+    if metadata.permissions().mode() & 0o111 == 0 {
+        bail!("OCI runtime {:?} is not executable", path);
+    }
+    Ok(())
+}
+
+/// Cuts `s` down to at most `max` bytes, on a character boundary.
+fn truncate(s: &str, max: usize) -> &str {
+    let mut end = s.len().min(max);
+    while !s.is_char_boundary(end) {
+        end -= 1;
+    }
+    &s[..end]
+}
+
+/// Reads the messages of error entries from a JSON formatted runtime log (`--log-format json`).
+pub fn log_errors<P: AsRef<Path>>(path: P) -> Vec<String> {
+    #[derive(Deserialize)]
+    struct Entry {
+        level: String,
+        msg: String,
+    }
+
+    let Ok(contents) = fs::read_to_string(path) else {
+        return Vec::new();
+    };
+    contents
+        .lines()
+        .filter_map(|line| serde_json::from_str::<Entry>(line).ok())
+        .filter(|entry| entry.level == "error")
+        .map(|entry| entry.msg)
+        .collect()
+}
+
+/// How the runtime is invoked for a container, from containerd's runc options.
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct RuntimeOptions {
+    /// Runtime binary to use instead of the configured one.
+    pub binary_name: Option<PathBuf>,
+
+    /// Root directory of the runtime's state (`--root`).
+    pub root: Option<PathBuf>,
+
+    /// Have the runtime manage cgroups through systemd (`--systemd-cgroup`).
+    pub systemd_cgroup: bool,
+
+    /// Don't use pivot_root to jail the container (`create --no-pivot`).
+    pub no_pivot_root: bool,
+
+    /// Don't create a new session keyring for the container (`create --no-new-keyring`).
+    pub no_new_keyring: bool,
+}
+
+/// The runtime's name and version as reported by `runtime --version`.
+#[derive(Debug)]
+pub struct Version {
+    /// e.g. `runc`.
+    pub name: String,
+
+    /// e.g. `1.1.12`.
+    pub version: String,
+
+    /// The commit the runtime was built from, empty if not reported.
+    pub commit: String,
+}
+
+impl Version {
+    /// Parses output like runc's, whose first line is `<name> version <version>`, optionally
+    /// followed by a `commit: <commit>` line.
+    fn parse(output: &str) -> Result<Self> {
+        let mut lines = output.lines();
+        let (name, version) = lines
+            .next()
+            .and_then(|line| line.split_once(" version "))
+            .context("missing version line")?;
+        let commit = lines
+            .find_map(|line| line.strip_prefix("commit:"))
+            .unwrap_or_default();
+        Ok(Self {
+            name: name.trim().to_string(),
+            version: version.trim().to_string(),
+            commit: commit.trim().to_string(),
+        })
+    }
+}
+
+/// Container state as reported by the OCI runtime.
+#[derive(Deserialize, Debug)]
+pub struct State {
+    /// The runtime's status of the container, e.g. `created` or `stopped`.
+    pub status: String,
+
+    /// The pid of the container's init process, 0 once it has stopped.
+    #[serde(default)]
+    pub pid: i32,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum FailureKind {
+    /// The runtime could not be spawned.
+    Spawn(io::ErrorKind),
+
+    /// The runtime was killed by the given signal.
+    Signaled(i32),
+
+    /// The runtime did not finish in time and was killed.
+    Timeout,
+}
+
+/// Trips after `threshold` consecutive failures of the same kind, failing fast until `cooldown`
+/// has elapsed. Once the cooldown is over a single further failure trips it again. Invocations
+/// that exit with an error code don't count as failures, the runtime works if it can answer.
+struct CircuitBreaker {
+    threshold: u32,
+    cooldown: Duration,
+    state: Mutex<BreakerState>,
+}
+
+#[derive(Default)]
+struct BreakerState {
+    last_failure: Option<FailureKind>,
+    failures: u32,
+    open_until: Option<Instant>,
+}
+
+impl CircuitBreaker {
+    fn new(threshold: u32, cooldown: Duration) -> Self {
+        Self {
+            threshold,
+            cooldown,
+            state: Mutex::new(BreakerState::default()),
+        }
+    }
+
+    fn check(&self) -> Result<()> {
+        let mut state = self.state.lock().unwrap();
+        match state.open_until {
+            Some(open_until) if open_until > Instant::now() => {
+                bail!(
+                    "OCI runtime appears broken after {} consecutive failures, retrying in {}s",
+                    state.failures,
+                    (open_until - Instant::now()).as_secs() + 1
+                );
+            }
+            Some(_) => {
+                state.open_until = None;
+                state.failures = self.threshold.saturating_sub(1);
+            }
+            None => {}
+        }
+        Ok(())
+    }
+
+    fn record_success(&self) {
+        *self.state.lock().unwrap() = BreakerState::default();
+    }
+
+    fn record_failure(&self, kind: FailureKind, output: &str) {
+        if self.threshold == 0 {
+            return;
+        }
+        let mut state = self.state.lock().unwrap();
+        if state.last_failure == Some(kind) {
+            state.failures += 1;
+        } else {
+            state.last_failure = Some(kind);
+            state.failures = 1;
+        }
+        if state.failures >= self.threshold && state.open_until.is_none() {
+            state.open_until = Some(Instant::now() + self.cooldown);
+            error!(
+                "OCI runtime appears broken after {} consecutive failures ({:?}), failing fast for {}s: {}",
+                state.failures,
+                kind,
+                self.cooldown.as_secs(),
+                output
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::Fixture;
+
+    fn tripped(breaker: &CircuitBreaker) -> bool {
+        breaker.check().is_err()
+    }
+
+    #[test]
+    fn breaker_trips_after_threshold_failures_of_same_kind() {
+        let breaker = CircuitBreaker::new(3, Duration::from_secs(60));
+        breaker.record_failure(FailureKind::Timeout, "");
+        breaker.record_failure(FailureKind::Timeout, "");
+        assert!(!tripped(&breaker));
+        breaker.record_failure(FailureKind::Timeout, "");
+        assert!(tripped(&breaker));
+    }
+
+    #[test]
+    fn breaker_counts_kinds_separately() {
+        let breaker = CircuitBreaker::new(2, Duration::from_secs(60));
+        breaker.record_failure(FailureKind::Timeout, "");
+        breaker.record_failure(FailureKind::Signaled(9), "");
+        breaker.record_failure(FailureKind::Spawn(io::ErrorKind::NotFound), "");
+        assert!(!tripped(&breaker));
+        breaker.record_failure(FailureKind::Spawn(io::ErrorKind::NotFound), "");
+        assert!(tripped(&breaker));
+    }
+
+    #[test]
+    fn breaker_resets_on_success() {
+        let breaker = CircuitBreaker::new(2, Duration::from_secs(60));
+        breaker.record_failure(FailureKind::Timeout, "");
+        breaker.record_success();
+        breaker.record_failure(FailureKind::Timeout, "");
+        assert!(!tripped(&breaker));
+    }
+
+    #[test]
+    fn breaker_retrips_on_one_failure_after_cooldown() {
+        let breaker = CircuitBreaker::new(2, Duration::ZERO);
+        breaker.record_failure(FailureKind::Timeout, "");
+        breaker.record_failure(FailureKind::Timeout, "");
+        // The cooldown is over, so the next invocation is let through.
+        assert!(!tripped(&breaker));
+        breaker.record_failure(FailureKind::Timeout, "");
+        assert!(breaker.state.lock().unwrap().open_until.is_some());
+    }
+
+    #[test]
+    fn breaker_disabled_with_zero_threshold() {
+        let breaker = CircuitBreaker::new(0, Duration::from_secs(60));
+        for _ in 0..10 {
+            breaker.record_failure(FailureKind::Timeout, "");
+        }
+        assert!(!tripped(&breaker));
+    }
+
+    #[test]
+    fn transient_errors_are_matched_as_substrings() {
+        let fixture = Fixture::new();
+        let runtime = &fixture.runtime;
+        assert!(runtime.is_transient(&["open /dev/fuse: device or resource busy".to_string()]));
+        assert!(runtime.is_transient(&[
+            "exec format error".to_string(),
+            "resource temporarily unavailable".to_string()
+        ]));
+        assert!(!runtime.is_transient(&["exec /bin/app: exec format error".to_string()]));
+        assert!(!runtime.is_transient(&[]));
+    }
+
+    #[test]
+    fn transient_errors_are_configurable() {
+        let fixture = Fixture::with_args(&["--create-retry-error", "cgroup not found"]);
+        let runtime = &fixture.runtime;
+        assert!(runtime.is_transient(&["mkdir: cgroup not found".to_string()]));
+        assert!(!runtime.is_transient(&["device or resource busy".to_string()]));
+    }
+}
@@ -0,0 +1,24 @@
+use std::os::fd::{AsRawFd, BorrowedFd};
+
+use tracing::info;
+
+/// Receives the seccomp notify listener fd of a container, e.g. to hand it to a seccomp agent.
+///
+/// The fd stays owned by the container and remains open until the container is dropped.
+pub trait SeccompNotifyHandler: Send + Sync {
+    fn handle(&self, container_id: &str, listener: BorrowedFd<'_>);
+}
+
+/// Default handler which only keeps the listener fd open so that the container's notifying
+/// syscalls block instead of failing.
+pub struct KeepOpen;
+
+impl SeccompNotifyHandler for KeepOpen {
+    fn handle(&self, container_id: &str, listener: BorrowedFd<'_>) {
+        info!(
+            "Holding seccomp notify fd {} for container {}",
+            listener.as_raw_fd(),
+            container_id
+        );
+    }
+}
@@ -1,32 +1,243 @@
-use std::{path::PathBuf, sync::Arc};
+use std::{
+    fs, io,
+    os::unix::process::ExitStatusExt,
+    path::{Path, PathBuf},
+    sync::{atomic::AtomicU64, Arc, Mutex},
+    time::{Duration, Instant},
+};
 
-use dashmap::DashMap;
+use ::time::OffsetDateTime;
+use dashmap::{mapref::entry::Entry, DashMap};
 use nix::sys::signal::Signal;
+use prost::Message;
+use prost_types::Any;
 use shim_protos::proto::{
-    task_server::Task, CreateTaskRequest, CreateTaskResponse, DeleteRequest, DeleteResponse,
-    KillRequest, ShutdownRequest, StartRequest, StartResponse, WaitRequest, WaitResponse,
+    task_server::Task, CheckpointTaskRequest, CloseIoRequest, ConnectShimRequest,
+    ConnectShimResponse, CpuMetrics, CreateTaskRequest, CreateTaskResponse, DeleteRequest,
+    DeleteResponse, ExecProcessRequest, KillRequest, LogFileStats, LogStatsRequest,
+    LogStatsResponse, MemoryMetrics, Metrics, NsenterRequest, NsenterResponse, PauseRequest,
+    PidsMetrics, PidsRequest, PidsResponse, ProcessInfo, ReconcileFailure, ReconcileRequest,
+    ReconcileResponse, ReconciledContainer, ResizePtyRequest, ResourcesRequest, ResourcesResponse,
+    ResumeRequest, Rlimit, RuncCheckpointOptions, RuncOptions, RuntimeStateRequest,
+    RuntimeStateResponse, ShutdownRequest, StartRequest, StartResponse, StateRequest,
+    StateResponse, StatsRequest, StatsResponse, UpdateStdioRequest, UpdateTaskRequest, WaitRequest,
+    WaitResponse, WriteStdinRequest, WriteStdinResponse,
+};
+use tokio::{
+    io::AsyncWriteExt,
+    task::JoinSet,
+    time::{self, error::Elapsed},
 };
-use tonic::{Request, Response, Status};
-use tracing::debug;
+use tonic::{Request, Response, Status, Streaming};
+use tracing::{debug, info, warn};
 
 use crate::{
-    container::{Container, Status as ContainerStatus},
-    utils::ExitSignal,
+    cgroup::Stats,
+    config::Config,
+    container::{
+        CheckpointOptions, Container, ContainerError, CreateOptions, LogStats,
+        Status as ContainerStatus,
+    },
+    events::Publisher,
+    metrics::Metrics as ShimMetrics,
+    mount::Mount,
+    nsenter::{self, NsenterError},
+    process::PidRegistry,
+    runtime::{Runtime, RuntimeOptions},
+    seccomp::{KeepOpen, SeccompNotifyHandler},
+    spec::{validate_exec_process, LinuxResources, SpecPatch},
+    utils::{timestamp, write_atomic, ExitSignal},
 };
 
+/// The type URL containerd uses for JSON encoded OCI `LinuxResources`.
+const LINUX_RESOURCES_TYPE_URL: &str =
+    "types.containerd.io/opencontainers/runtime-spec/1/LinuxResources";
+
+/// The type containerd's runc options are sent as, in `CreateTaskRequest.options`.
+const RUNC_OPTIONS_TYPE: &str = "containerd.runc.v1.Options";
+
+/// The type containerd's runc checkpoint options are sent as, in `CheckpointTaskRequest.options`.
+const RUNC_CHECKPOINT_OPTIONS_TYPE: &str = "containerd.runc.v1.CheckpointOptions";
+
+/// The type URL of the protobuf encoded `Metrics` returned by `Stats`.
+const METRICS_TYPE_URL: &str = "type.googleapis.com/shim.task.Metrics";
+
+/// How long to wait on shutdown for the containers' remaining output to be written.
+pub const SHUTDOWN_DRAIN_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// The range of `oom_score_adj` values the kernel accepts.
+const OOM_SCORE_ADJ_MIN: i32 = -1000;
+const OOM_SCORE_ADJ_MAX: i32 = 1000;
+
 pub struct TaskService {
-    pub runtime: PathBuf,
-    pub containers: Arc<DashMap<String, Container>>,
+    pub config: Config,
+    pub runtime: Runtime,
+    pub containers: Arc<DashMap<String, Arc<Container>>>,
     pub exit_signal: Arc<ExitSignal>,
+    pub orphans_reaped: Arc<AtomicU64>,
+    pub pids: PidRegistry,
+    pub events: Publisher,
+    pub seccomp_handler: Box<dyn SeccompNotifyHandler>,
+    pub metrics: ShimMetrics,
+    /// When the daemon started.
+    pub started_at: OffsetDateTime,
+    last_reconcile: Mutex<Option<Instant>>,
+    /// Where the containers' bundles are recorded for a restarted daemon to recover them.
+    state_path: PathBuf,
 }
 
 impl TaskService {
-    pub fn new(runtime: PathBuf, exit_signal: Arc<ExitSignal>) -> Self {
+    pub fn new(
+        config: &Config,
+        exit_signal: Arc<ExitSignal>,
+        started_at: OffsetDateTime,
+        state_path: PathBuf,
+    ) -> Self {
+        let pids = PidRegistry::default();
+        let metrics = ShimMetrics::default();
         Self {
-            runtime,
+            config: config.clone(),
+            runtime: Runtime::new(config, pids.clone(), metrics.clone()),
             containers: Arc::new(DashMap::new()),
             exit_signal,
+            orphans_reaped: Arc::new(AtomicU64::new(0)),
+            pids,
+            events: Publisher::new(config),
+            seccomp_handler: Box::new(KeepOpen),
+            metrics,
+            started_at,
+            last_reconcile: Mutex::new(None),
+            state_path,
+        }
+    }
+
+    /// Recovers the containers recorded by a previous daemon for this task, e.g. one that
+    /// crashed or was restarted while they kept running. Containers the runtime no longer knows
+    /// about are dropped.
+    pub async fn recover(&self) {
+        let bundles: Vec<PathBuf> = match fs::read(&self.state_path) {
+            Ok(contents) => match serde_json::from_slice(&contents) {
+                Ok(bundles) => bundles,
+                Err(err) => {
+                    warn!("Ignoring invalid state file {:?}: {}", self.state_path, err);
+                    Vec::new()
+                }
+            },
+            Err(err) if err.kind() == io::ErrorKind::NotFound => return,
+            Err(err) => {
+                warn!("Failed to read state file {:?}: {}", self.state_path, err);
+                return;
+            }
+        };
+        for bundle in bundles {
+            let container =
+                match Container::load(&bundle, self.pids.clone(), self.events.clone()).await {
+                    Ok(container) => Arc::new(container),
+                    Err(err) => {
+                        warn!("Failed to load container from {:?}: {:#}", bundle, err);
+                        continue;
+                    }
+                };
+            match container.recover(&self.runtime).await {
+                Ok(()) => {
+                    info!(
+                        "Recovered container {} with status {:?}",
+                        container.id,
+                        container.status().await
+                    );
+                    self.containers.insert(container.id.clone(), container);
+                }
+                Err(err) => warn!("Failed to recover container {}: {:#}", container.id, err),
+            }
         }
+        self.save_state();
+    }
+
+    /// Records the bundles of the current containers in the state file, or removes it if there
+    /// are none. The containers' own state is kept in their bundles.
+    fn save_state(&self) {
+        let bundles: Vec<_> = self
+            .containers
+            .iter()
+            .map(|container| container.bundle.clone())
+            .collect();
+        let result = if bundles.is_empty() {
+            match fs::remove_file(&self.state_path) {
+                Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(()),
+                result => result.map_err(Into::into),
+            }
+        } else {
+            write_state(&self.state_path, &bundles)
+        };
+        if let Err(err) = result {
+            warn!("Failed to save state to {:?}: {:#}", self.state_path, err);
+        }
+    }
+
+    fn container(&self, id: &str) -> Option<Arc<Container>> {
+        self.containers.get(id).map(|container| container.clone())
+    }
+
+    async fn start_exec(
+        &self,
+        container: &Container,
+        exec_id: &str,
+    ) -> Result<Response<StartResponse>, Status> {
+        let process = container
+            .process(exec_id)
+            .await
+            .ok_or_else(|| Status::new(tonic::Code::NotFound, "Process not found"))?;
+        if process.status().await != ContainerStatus::CREATED {
+            return Err(Status::new(
+                tonic::Code::FailedPrecondition,
+                "Process is not created",
+            ));
+        }
+        if let Err(err) = container
+            .start_exec(
+                &self.runtime,
+                exec_id,
+                self.config.log_options(&self.metrics),
+            )
+            .await
+        {
+            return Err(Status::new(
+                error_code(&err),
+                format!("Failed to start exec process: {:#}", err),
+            ));
+        }
+        let pid = process.pid().await as u32;
+        Ok(Response::new(StartResponse { pid }))
+    }
+
+    async fn delete_exec(
+        &self,
+        container: &Container,
+        exec_id: &str,
+    ) -> Result<Response<DeleteResponse>, Status> {
+        let process = container
+            .process(exec_id)
+            .await
+            .ok_or_else(|| Status::new(tonic::Code::NotFound, "Process not found"))?;
+        if process.status().await == ContainerStatus::RUNNING {
+            return Err(Status::new(
+                tonic::Code::FailedPrecondition,
+                "Process is still running",
+            ));
+        }
+        if let Err(err) = container.delete_exec(exec_id, SHUTDOWN_DRAIN_TIMEOUT).await {
+            return Err(Status::new(
+                error_code(&err),
+                format!("Failed to delete exec process: {}", err),
+            ));
+        }
+        let exit_status = process.exit_status().await;
+        Ok(Response::new(DeleteResponse {
+            pid: process.pid().await as u32,
+            exit_status: exit_status.code(self.config.exit_code_format),
+            exited_at: process.exited_at().await,
+            signaled: exit_status.signaled(),
+        }))
     }
 }
 
@@ -36,107 +247,262 @@ impl Task for TaskService {
         &self,
         request: Request<CreateTaskRequest>,
     ) -> Result<Response<CreateTaskResponse>, Status> {
+        let _timer = self.metrics.rpc("Create");
         debug!("Creating container");
         let request = request.into_inner();
-        if self.containers.contains_key(&request.id) {
+        if let Some(oom_score_adj) = request.oom_score_adj {
+            if !(OOM_SCORE_ADJ_MIN..=OOM_SCORE_ADJ_MAX).contains(&oom_score_adj) {
+                return Err(Status::new(
+                    tonic::Code::InvalidArgument,
+                    format!(
+                        "oom_score_adj {} is outside of {}..={}",
+                        oom_score_adj, OOM_SCORE_ADJ_MIN, OOM_SCORE_ADJ_MAX
+                    ),
+                ));
+            }
+        }
+        let spec_patch = SpecPatch {
+            apparmor_profile: Some(request.apparmor_profile).filter(|profile| !profile.is_empty()),
+            selinux_label: Some(request.selinux_label).filter(|label| !label.is_empty()),
+        };
+        if let Err(err) = spec_patch.validate() {
             return Err(Status::new(
-                tonic::Code::AlreadyExists,
-                "Container already exists",
+                tonic::Code::InvalidArgument,
+                format!("Invalid security label: {}", err),
             ));
         }
-        let container = Container::new(
+        let runtime_options = match request.options.as_ref().map(runtime_options).transpose() {
+            Ok(options) => options.unwrap_or_default(),
+            Err(err) => {
+                return Err(Status::new(
+                    tonic::Code::InvalidArgument,
+                    format!("Invalid runtime options: {}", err),
+                ))
+            }
+        };
+        let container = Arc::new(Container::new(
             &request.id,
             &request.bundle.into(),
             &request.stdout.into(),
             &request.stderr.into(),
-        );
-        if let Err(err) = container.create(&self.runtime).await {
+            CreateOptions {
+                create_fifos: request.create_fifos,
+                merge_stderr: request.merge_stderr,
+                oom_score_adj: request.oom_score_adj,
+                stdin: request.stdin,
+                stdin_path: request.stdin_path.into(),
+                checkpoint: Some(PathBuf::from(request.checkpoint))
+                    .filter(|path| !path.as_os_str().is_empty()),
+                terminal: request.terminal,
+                spec_patch,
+                runtime: runtime_options,
+                rootfs: request
+                    .rootfs
+                    .into_iter()
+                    .map(|mount| Mount {
+                        kind: mount.r#type,
+                        source: mount.source,
+                        options: mount.options,
+                    })
+                    .collect(),
+            },
+            self.pids.clone(),
+            self.events.clone(),
+        ));
+        // Registered up front so that concurrent creates with the same id fail, and other
+        // requests see the container as being created.
+        match self.containers.entry(request.id.clone()) {
+            Entry::Occupied(_) => {
+                return Err(Status::new(
+                    tonic::Code::AlreadyExists,
+                    "Container already exists",
+                ))
+            }
+            Entry::Vacant(entry) => {
+                entry.insert(container.clone());
+            }
+        }
+        if let Err(err) = container
+            .create(
+                &self.runtime,
+                self.config.log_options(&self.metrics),
+                self.config.cgroup_options(),
+                self.seccomp_handler.as_ref(),
+            )
+            .await
+        {
+            self.containers.remove(&request.id);
             return Err(Status::new(
-                tonic::Code::Internal,
+                error_code(&err),
                 format!("Failed to create container: {}", err),
             ));
         }
-        let pid = container.pid().await as u32;
-        self.containers.insert(request.id, container);
-        Ok(Response::new(CreateTaskResponse { pid }))
+        self.save_state();
+        let pid = container.pid().await;
+        self.events
+            .task_create(&request.id, &container.bundle.to_string_lossy(), pid);
+        Ok(Response::new(CreateTaskResponse { pid: pid as u32 }))
     }
 
     async fn start(
         &self,
         request: Request<StartRequest>,
     ) -> Result<Response<StartResponse>, Status> {
+        let _timer = self.metrics.rpc("Start");
         debug!("Starting container");
         let request = request.into_inner();
         let container = self
-            .containers
-            .get(&request.id)
+            .container(&request.id)
             .ok_or_else(|| Status::new(tonic::Code::NotFound, "Container not found"))?;
-        if let Err(err) = container.start(&self.runtime).await {
+        if !request.exec_id.is_empty() {
+            return self.start_exec(&container, &request.exec_id).await;
+        }
+        if container.status().await != ContainerStatus::CREATED {
             return Err(Status::new(
-                tonic::Code::Internal,
+                tonic::Code::FailedPrecondition,
+                "Container is not created",
+            ));
+        }
+        if let Some(delay) = self.config.start_delay {
+            time::sleep(delay).await;
+        }
+        if let Err(err) = container
+            .start(&self.runtime, self.config.cgroup_options())
+            .await
+        {
+            return Err(Status::new(
+                error_code(&err),
                 format!("Failed to start container: {}", err),
             ));
         }
-        let pid = container.pid().await as u32;
-        Ok(Response::new(StartResponse { pid }))
+        if let Some(time_to_running) = container.time_to_running().await {
+            self.metrics.container_phase("running", time_to_running);
+        }
+        let pid = container.pid().await;
+        self.events.task_start(&request.id, pid);
+        Ok(Response::new(StartResponse { pid: pid as u32 }))
     }
 
     async fn delete(
         &self,
         request: Request<DeleteRequest>,
     ) -> Result<Response<DeleteResponse>, Status> {
+        let _timer = self.metrics.rpc("Delete");
         debug!("Deleting container");
         let request = request.into_inner();
         let container = self
-            .containers
-            .get(&request.id)
+            .container(&request.id)
             .ok_or_else(|| Status::new(tonic::Code::NotFound, "Container not found"))?;
-        if let Err(err) = container.delete(&self.runtime).await {
+        if !request.exec_id.is_empty() {
+            return self.delete_exec(&container, &request.exec_id).await;
+        }
+        if let Err(err) = container
+            .delete(&self.runtime, self.config.delete_timeout, false)
+            .await
+        {
             return Err(Status::new(
-                tonic::Code::Internal,
+                error_code(&err),
                 format!("Failed to delete container: {}", err),
             ));
         }
-        let pid = container.pid().await as u32;
-        drop(container);
+        let pid = container.pid().await;
+        let exit_status = container.exit_status().await;
+        let exited_at = container.exited_at().await;
         self.containers.remove(&request.id);
-        Ok(Response::new(DeleteResponse { pid }))
+        self.save_state();
+        self.events
+            .task_delete(&request.id, pid, exit_status, exited_at);
+        Ok(Response::new(DeleteResponse {
+            pid: pid as u32,
+            exit_status: exit_status.code(self.config.exit_code_format),
+            exited_at,
+            signaled: exit_status.signaled(),
+        }))
     }
 
     async fn wait(&self, request: Request<WaitRequest>) -> Result<Response<WaitResponse>, Status> {
+        let _timer = self.metrics.rpc("Wait");
         debug!("Waiting for container");
         let request = request.into_inner();
         let container = self
-            .containers
-            .get(&request.id)
+            .container(&request.id)
             .ok_or_else(|| Status::new(tonic::Code::NotFound, "Container not found"))?;
-        let status = container.status().await;
-        if status != ContainerStatus::RUNNING && status != ContainerStatus::CREATED {
-            return Ok(Response::new(WaitResponse {
-                exit_status: container.exit_code().await as u32,
-                exited_at: container.exited_at().await,
-            }));
-        }
-        let mut rx = container.wait_channel().await;
-        if rx.recv().await.is_none() {
+        let process = container
+            .process(&request.exec_id)
+            .await
+            .ok_or_else(|| Status::new(tonic::Code::NotFound, "Process not found"))?;
+        let status = process.status().await;
+        if status == ContainerStatus::UNKNOWN {
             return Err(Status::new(
-                tonic::Code::Aborted,
-                "Container exited unexpectedly",
+                tonic::Code::Unavailable,
+                "Container is still being created",
             ));
         }
+        if status == ContainerStatus::STOPPED {
+            let exit_status = process.exit_status().await;
+            return Ok(Response::new(WaitResponse {
+                exit_status: exit_status.code(self.config.exit_code_format),
+                exited_at: process.exited_at().await,
+                signaled: exit_status.signaled(),
+            }));
+        }
+        let timeout = match request.timeout.map(Duration::try_from).transpose() {
+            Ok(timeout) => timeout,
+            Err(err) => {
+                return Err(Status::new(
+                    tonic::Code::InvalidArgument,
+                    format!("Invalid timeout: {}", err),
+                ))
+            }
+        };
+        let exit = match timeout {
+            Some(timeout) => match time::timeout(timeout, process.wait()).await {
+                Ok(exit) => exit,
+                Err(_) => {
+                    return Err(Status::new(
+                        tonic::Code::DeadlineExceeded,
+                        format!(
+                            "Container is still {:?} with pid {}",
+                            process.status().await,
+                            process.pid().await
+                        ),
+                    ));
+                }
+            },
+            None => process.wait().await,
+        };
         Ok(Response::new(WaitResponse {
-            exit_status: container.exit_code().await as u32,
-            exited_at: container.exited_at().await,
+            exit_status: exit.exit_status.code(self.config.exit_code_format),
+            exited_at: Some(exit.exited_at),
+            signaled: exit.exit_status.signaled(),
         }))
     }
 
     async fn kill(&self, request: Request<KillRequest>) -> Result<Response<()>, Status> {
+        let _timer = self.metrics.rpc("Kill");
         debug!("Killing container");
         let request = request.into_inner();
         let container = self
-            .containers
-            .get(&request.id)
+            .container(&request.id)
             .ok_or_else(|| Status::new(tonic::Code::NotFound, "Container not found"))?;
+        let process = container
+            .process(&request.exec_id)
+            .await
+            .ok_or_else(|| Status::new(tonic::Code::NotFound, "Process not found"))?;
+        if process.status().await == ContainerStatus::UNKNOWN {
+            return Err(Status::new(
+                tonic::Code::Unavailable,
+                "Container is still being created",
+            ));
+        }
+        // An exec'd process has no pid until it has been started, and kill(0) would signal our
+        // own process group.
+        if process.pid().await == 0 {
+            return Err(Status::new(
+                tonic::Code::FailedPrecondition,
+                "Process has not been started",
+            ));
+        }
         let signal = match Signal::try_from(request.signal as i32) {
             Ok(signal) => signal,
             Err(err) => {
@@ -146,9 +512,22 @@ impl Task for TaskService {
                 ))
             }
         };
-        if let Err(err) = container.kill(signal).await {
+        let result = if request.all {
+            if !request.exec_id.is_empty() {
+                return Err(Status::new(
+                    tonic::Code::InvalidArgument,
+                    "All processes can only be signalled through the container",
+                ));
+            }
+            container
+                .kill_all(signal, self.config.cgroup_options())
+                .await
+        } else {
+            container.kill(&request.exec_id, signal).await
+        };
+        if let Err(err) = result {
             return Err(Status::new(
-                tonic::Code::Internal,
+                error_code(&err),
                 format!("Failed to kill container: {}", err),
             ));
         }
@@ -156,18 +535,1037 @@ impl Task for TaskService {
     }
 
     async fn shutdown(&self, _request: Request<ShutdownRequest>) -> Result<Response<()>, Status> {
+        let _timer = self.metrics.rpc("Shutdown");
         debug!("Shutting down container");
-        for container in self.containers.iter() {
-            // Kills all containers so that all `TaskService::wait` calls return and Tonic can shutdown.
-            if let Err(err) = container.delete(&self.runtime).await {
+        let containers: Vec<_> = self
+            .containers
+            .iter()
+            .map(|container| container.clone())
+            .collect();
+        // Stopped concurrently, so that shutdown takes at most one grace period.
+        let mut stops = JoinSet::new();
+        for container in &containers {
+            let container = container.clone();
+            let grace_period = self.config.shutdown_grace_period;
+            stops.spawn(async move {
+                if let Err(err) = container.stop(grace_period).await {
+                    warn!("Failed to stop container {}: {}", container.id, err);
+                }
+            });
+        }
+        while stops.join_next().await.is_some() {}
+        for container in &containers {
+            // Deletes all containers so that all `TaskService::wait` calls return and Tonic can shutdown.
+            // Forced, as a container that did not stop in time is still running.
+            if let Err(err) = container
+                .delete(&self.runtime, self.config.delete_timeout, true)
+                .await
+            {
                 return Err(Status::new(
-                    tonic::Code::Internal,
+                    error_code(&err),
                     format!("Failed to delete container: {}", err),
                 ));
             }
         }
+        for container in &containers {
+            container.drain_output(SHUTDOWN_DRAIN_TIMEOUT).await;
+        }
         self.containers.clear();
+        self.save_state();
         self.exit_signal.signal();
         Ok(Response::new(()))
     }
+
+    async fn log_stats(
+        &self,
+        request: Request<LogStatsRequest>,
+    ) -> Result<Response<LogStatsResponse>, Status> {
+        let _timer = self.metrics.rpc("LogStats");
+        debug!("Getting container log stats");
+        let request = request.into_inner();
+        let container = self
+            .container(&request.id)
+            .ok_or_else(|| Status::new(tonic::Code::NotFound, "Container not found"))?;
+        let (stdout, stderr) = container.log_stats().await;
+        Ok(Response::new(LogStatsResponse {
+            stdout: Some(stdout.into()),
+            stderr: Some(stderr.into()),
+        }))
+    }
+
+    async fn nsenter(
+        &self,
+        request: Request<NsenterRequest>,
+    ) -> Result<Response<NsenterResponse>, Status> {
+        let _timer = self.metrics.rpc("Nsenter");
+        debug!("Running command in container namespaces");
+        let request = request.into_inner();
+        let container = self
+            .container(&request.id)
+            .ok_or_else(|| Status::new(tonic::Code::NotFound, "Container not found"))?;
+        let status = container.status().await;
+        if status != ContainerStatus::CREATED && status != ContainerStatus::RUNNING {
+            return Err(Status::new(
+                tonic::Code::FailedPrecondition,
+                "Container is not running",
+            ));
+        }
+        let output = match nsenter::run(
+            &self.pids,
+            container.pid().await,
+            &request.namespaces,
+            &request.args,
+            self.config.nsenter_timeout,
+        )
+        .await
+        {
+            Ok(output) => output,
+            Err(err) => {
+                return Err(Status::new(
+                    nsenter_error_code(&err),
+                    format!("Failed to run command: {:#}", err),
+                ))
+            }
+        };
+        Ok(Response::new(NsenterResponse {
+            stdout: output.stdout,
+            stderr: output.stderr,
+            exit_status: output
+                .status
+                .code()
+                .or_else(|| output.status.signal().map(|signal| 128 + signal))
+                .unwrap_or(-1),
+        }))
+    }
+
+    async fn update_stdio(
+        &self,
+        request: Request<UpdateStdioRequest>,
+    ) -> Result<Response<()>, Status> {
+        let _timer = self.metrics.rpc("UpdateStdio");
+        debug!("Updating container stdio");
+        let request = request.into_inner();
+        let container = self
+            .container(&request.id)
+            .ok_or_else(|| Status::new(tonic::Code::NotFound, "Container not found"))?;
+        if !request.stderr.is_empty() && container.options.merge_stderr {
+            return Err(Status::new(
+                tonic::Code::InvalidArgument,
+                "Container stderr is merged into stdout",
+            ));
+        }
+        let stdout = Some(Path::new(&request.stdout)).filter(|path| !path.as_os_str().is_empty());
+        let stderr = Some(Path::new(&request.stderr)).filter(|path| !path.as_os_str().is_empty());
+        if let Err(err) = container.update_stdio(stdout, stderr).await {
+            return Err(Status::new(
+                error_code(&err),
+                format!("Failed to update container stdio: {}", err),
+            ));
+        }
+        Ok(Response::new(()))
+    }
+
+    async fn resources(
+        &self,
+        request: Request<ResourcesRequest>,
+    ) -> Result<Response<ResourcesResponse>, Status> {
+        let _timer = self.metrics.rpc("Resources");
+        debug!("Reading container resources");
+        let request = request.into_inner();
+        let container = self
+            .container(&request.id)
+            .ok_or_else(|| Status::new(tonic::Code::NotFound, "Container not found"))?;
+        let resources = match container.resources(self.config.cgroup_options()).await {
+            Ok(resources) => resources,
+            Err(err) => {
+                return Err(Status::new(
+                    tonic::Code::Unavailable,
+                    format!("Failed to read container resources: {:#}", err),
+                ))
+            }
+        };
+        let value = match serde_json::to_vec(&resources) {
+            Ok(value) => value,
+            Err(err) => {
+                return Err(Status::new(
+                    tonic::Code::Internal,
+                    format!("Failed to encode container resources: {}", err),
+                ))
+            }
+        };
+        Ok(Response::new(ResourcesResponse {
+            resources: Some(Any {
+                type_url: LINUX_RESOURCES_TYPE_URL.to_string(),
+                value,
+            }),
+        }))
+    }
+
+    async fn reconcile(
+        &self,
+        _request: Request<ReconcileRequest>,
+    ) -> Result<Response<ReconcileResponse>, Status> {
+        let _timer = self.metrics.rpc("Reconcile");
+        debug!("Reconciling containers");
+        {
+            let mut last_reconcile = self.last_reconcile.lock().unwrap();
+            if let Some(elapsed) = last_reconcile.map(|last| last.elapsed()) {
+                if elapsed < self.config.reconcile_interval {
+                    return Err(Status::new(
+                        tonic::Code::ResourceExhausted,
+                        format!(
+                            "Containers were reconciled {}ms ago, retry in {}ms",
+                            elapsed.as_millis(),
+                            (self.config.reconcile_interval - elapsed).as_millis()
+                        ),
+                    ));
+                }
+            }
+            *last_reconcile = Some(Instant::now());
+        }
+        let containers: Vec<_> = self
+            .containers
+            .iter()
+            .map(|container| container.clone())
+            .collect();
+        let mut response = ReconcileResponse::default();
+        for container in containers {
+            match container.reconcile(&self.runtime).await {
+                Ok(Some(reconciled)) => response.changed.push(ReconciledContainer {
+                    id: container.id.clone(),
+                    previous_status: reconciled.previous_status.into(),
+                    status: reconciled.status.into(),
+                    pid: reconciled.pid as u32,
+                }),
+                Ok(None) => {}
+                Err(err) => response.failed.push(ReconcileFailure {
+                    id: container.id.clone(),
+                    error: format!("{:#}", err),
+                }),
+            }
+        }
+        Ok(Response::new(response))
+    }
+
+    async fn connect_shim(
+        &self,
+        request: Request<ConnectShimRequest>,
+    ) -> Result<Response<ConnectShimResponse>, Status> {
+        let _timer = self.metrics.rpc("ConnectShim");
+        debug!("Connecting to shim");
+        let request = request.into_inner();
+        let (id, task_pid) = match self.container(&request.id) {
+            Some(container) => (container.id.clone(), container.pid().await as u32),
+            None => (String::new(), 0),
+        };
+        let uptime = OffsetDateTime::now_utc() - self.started_at;
+        Ok(Response::new(ConnectShimResponse {
+            shim_pid: std::process::id(),
+            task_pid,
+            id,
+            started_at: Some(timestamp(self.started_at)),
+            uptime: Duration::try_from(uptime)
+                .ok()
+                .and_then(|uptime| uptime.try_into().ok()),
+        }))
+    }
+
+    async fn runtime_state(
+        &self,
+        request: Request<RuntimeStateRequest>,
+    ) -> Result<Response<RuntimeStateResponse>, Status> {
+        let _timer = self.metrics.rpc("RuntimeState");
+        debug!("Getting OCI runtime state");
+        let request = request.into_inner();
+        let container = self
+            .container(&request.id)
+            .ok_or_else(|| Status::new(tonic::Code::NotFound, "Container not found"))?;
+        match container.runtime_state(&self.runtime).await {
+            Ok(state) => Ok(Response::new(RuntimeStateResponse { state })),
+            Err(err) => Err(Status::new(
+                error_code(&err),
+                format!("Failed to get OCI runtime state: {}", err),
+            )),
+        }
+    }
+
+    async fn write_stdin(
+        &self,
+        request: Request<Streaming<WriteStdinRequest>>,
+    ) -> Result<Response<WriteStdinResponse>, Status> {
+        let _timer = self.metrics.rpc("WriteStdin");
+        debug!("Writing container stdin");
+        let mut stream = request.into_inner();
+        let Some(first) = stream.message().await? else {
+            return Err(Status::new(
+                tonic::Code::InvalidArgument,
+                "Stdin stream is empty",
+            ));
+        };
+        let container = self
+            .container(&first.id)
+            .ok_or_else(|| Status::new(tonic::Code::NotFound, "Container not found"))?;
+        let mut closed = container.stdin_closed();
+        let mut stdin = container.stdin().await;
+        let Some(pipe) = stdin.as_mut() else {
+            return Err(Status::new(
+                tonic::Code::FailedPrecondition,
+                "Container stdin is not open",
+            ));
+        };
+        let closed_early = |bytes_written| {
+            Status::new(
+                tonic::Code::FailedPrecondition,
+                format!(
+                    "Container stdin was closed after writing {} bytes",
+                    bytes_written
+                ),
+            )
+        };
+        let mut bytes_written = 0;
+        let mut keep_open = false;
+        let mut message = Some(first);
+        while let Some(chunk) = message {
+            keep_open = chunk.keep_open;
+            // Waits for the container to read when the pipe is full, while gRPC flow control
+            // holds back the client. CloseIO interrupts the wait.
+            let written = tokio::select! {
+                written = pipe.write_all(&chunk.data) => written,
+                _ = closed.wait_for(|closed| *closed) => {
+                    *stdin = None;
+                    return Err(closed_early(bytes_written));
+                }
+            };
+            if let Err(err) = written {
+                *stdin = None;
+                return Err(Status::new(
+                    tonic::Code::FailedPrecondition,
+                    format!("Failed to write container stdin: {}", err),
+                ));
+            }
+            bytes_written += chunk.data.len() as u64;
+            message = tokio::select! {
+                message = stream.message() => message?,
+                _ = closed.wait_for(|closed| *closed) => {
+                    *stdin = None;
+                    return Err(closed_early(bytes_written));
+                }
+            };
+        }
+        if !keep_open {
+            *stdin = None;
+        }
+        Ok(Response::new(WriteStdinResponse { bytes_written }))
+    }
+
+    async fn close_io(&self, request: Request<CloseIoRequest>) -> Result<Response<()>, Status> {
+        let _timer = self.metrics.rpc("CloseIO");
+        debug!("Closing container stdin");
+        let request = request.into_inner();
+        let container = self
+            .container(&request.id)
+            .ok_or_else(|| Status::new(tonic::Code::NotFound, "Container not found"))?;
+        if !request.exec_id.is_empty() {
+            let exec = container
+                .exec(&request.exec_id)
+                .await
+                .ok_or_else(|| Status::new(tonic::Code::NotFound, "Process not found"))?;
+            if request.stdin {
+                exec.close_stdin().await;
+            }
+            return Ok(Response::new(()));
+        }
+        if request.stdin {
+            container.close_stdin().await;
+        }
+        Ok(Response::new(()))
+    }
+
+    async fn state(
+        &self,
+        request: Request<StateRequest>,
+    ) -> Result<Response<StateResponse>, Status> {
+        let _timer = self.metrics.rpc("State");
+        debug!("Getting container state");
+        let request = request.into_inner();
+        let container = self
+            .container(&request.id)
+            .ok_or_else(|| Status::new(tonic::Code::NotFound, "Container not found"))?;
+        let (process, stdout, stderr, rlimits) = if request.exec_id.is_empty() {
+            let process = container
+                .process("")
+                .await
+                .expect("the init process always exists");
+            let rlimits = container
+                .rlimits()
+                .await
+                .into_iter()
+                .map(|rlimit| Rlimit {
+                    r#type: rlimit.kind,
+                    hard: rlimit.hard,
+                    soft: rlimit.soft,
+                })
+                .collect();
+            (
+                process,
+                container.stdout.clone(),
+                container.stderr.clone(),
+                rlimits,
+            )
+        } else {
+            let exec = container
+                .exec(&request.exec_id)
+                .await
+                .ok_or_else(|| Status::new(tonic::Code::NotFound, "Process not found"))?;
+            (
+                exec.process.clone(),
+                exec.stdout.clone(),
+                exec.stderr.clone(),
+                Vec::new(),
+            )
+        };
+        let exit_status = process.exit_status().await;
+        Ok(Response::new(StateResponse {
+            id: container.id.clone(),
+            exec_id: request.exec_id,
+            bundle: container.bundle.to_string_lossy().into_owned(),
+            pid: process.pid().await as u32,
+            status: process.status().await.into(),
+            stdout: stdout.to_string_lossy().into_owned(),
+            stderr: stderr.to_string_lossy().into_owned(),
+            exit_status: exit_status.code(self.config.exit_code_format),
+            exited_at: process.exited_at().await,
+            signaled: exit_status.signaled(),
+            rlimits,
+        }))
+    }
+
+    async fn exec(&self, request: Request<ExecProcessRequest>) -> Result<Response<()>, Status> {
+        let _timer = self.metrics.rpc("Exec");
+        debug!("Adding exec process");
+        let request = request.into_inner();
+        if !valid_exec_id(&request.exec_id) {
+            return Err(Status::new(
+                tonic::Code::InvalidArgument,
+                format!("Invalid exec id {:?}", request.exec_id),
+            ));
+        }
+        let spec = request.spec.map(|spec| spec.value).unwrap_or_default();
+        if let Err(err) = validate_exec_process(&spec) {
+            return Err(Status::new(
+                tonic::Code::InvalidArgument,
+                format!("Invalid process spec: {:#}", err),
+            ));
+        }
+        let container = self
+            .container(&request.id)
+            .ok_or_else(|| Status::new(tonic::Code::NotFound, "Container not found"))?;
+        if container.status().await != ContainerStatus::RUNNING {
+            return Err(Status::new(
+                tonic::Code::FailedPrecondition,
+                "Container is not running",
+            ));
+        }
+        if container.exec(&request.exec_id).await.is_some() {
+            return Err(Status::new(
+                tonic::Code::AlreadyExists,
+                "Exec process already exists",
+            ));
+        }
+        if let Err(err) = container
+            .add_exec(
+                &request.exec_id,
+                &spec,
+                Path::new(&request.stdin),
+                Path::new(&request.stdout),
+                Path::new(&request.stderr),
+            )
+            .await
+        {
+            return Err(Status::new(
+                error_code(&err),
+                format!("Failed to add exec process: {}", err),
+            ));
+        }
+        Ok(Response::new(()))
+    }
+
+    async fn resize_pty(&self, request: Request<ResizePtyRequest>) -> Result<Response<()>, Status> {
+        let _timer = self.metrics.rpc("ResizePty");
+        debug!("Resizing pty");
+        let request = request.into_inner();
+        let (Ok(width), Ok(height)) = (request.width.try_into(), request.height.try_into()) else {
+            return Err(Status::new(
+                tonic::Code::InvalidArgument,
+                format!("Invalid terminal size {}x{}", request.width, request.height),
+            ));
+        };
+        let container = self
+            .container(&request.id)
+            .ok_or_else(|| Status::new(tonic::Code::NotFound, "Container not found"))?;
+        let process = container
+            .process(&request.exec_id)
+            .await
+            .ok_or_else(|| Status::new(tonic::Code::NotFound, "Process not found"))?;
+        if let Err(err) = process.resize_console(width, height).await {
+            return Err(Status::new(
+                tonic::Code::FailedPrecondition,
+                format!("Failed to resize pty: {:#}", err),
+            ));
+        }
+        Ok(Response::new(()))
+    }
+
+    async fn pids(&self, request: Request<PidsRequest>) -> Result<Response<PidsResponse>, Status> {
+        let _timer = self.metrics.rpc("Pids");
+        debug!("Listing container processes");
+        let request = request.into_inner();
+        let container = self
+            .container(&request.id)
+            .ok_or_else(|| Status::new(tonic::Code::NotFound, "Container not found"))?;
+        let pids = match container.pids(&self.runtime).await {
+            Ok(pids) => pids,
+            Err(err) => {
+                return Err(Status::new(
+                    error_code(&err),
+                    format!("Failed to list container processes: {}", err),
+                ))
+            }
+        };
+        let processes = pids
+            .into_iter()
+            .map(|(pid, exec_id)| ProcessInfo {
+                pid: pid as u32,
+                exec_id: exec_id.unwrap_or_default(),
+            })
+            .collect();
+        Ok(Response::new(PidsResponse { processes }))
+    }
+
+    async fn stats(
+        &self,
+        request: Request<StatsRequest>,
+    ) -> Result<Response<StatsResponse>, Status> {
+        let _timer = self.metrics.rpc("Stats");
+        debug!("Reading container stats");
+        let request = request.into_inner();
+        let container = self
+            .container(&request.id)
+            .ok_or_else(|| Status::new(tonic::Code::NotFound, "Container not found"))?;
+        // The cgroup is gone once the container has exited.
+        let stats = match container.stats(self.config.cgroup_options()).await {
+            Ok(stats) => stats,
+            Err(err) => {
+                return Err(Status::new(
+                    tonic::Code::Unavailable,
+                    format!("Failed to read container stats: {:#}", err),
+                ))
+            }
+        };
+        Ok(Response::new(StatsResponse {
+            stats: Some(Any {
+                type_url: METRICS_TYPE_URL.to_string(),
+                value: Metrics::from(stats).encode_to_vec(),
+            }),
+        }))
+    }
+
+    async fn pause(&self, request: Request<PauseRequest>) -> Result<Response<()>, Status> {
+        let _timer = self.metrics.rpc("Pause");
+        debug!("Pausing container");
+        let request = request.into_inner();
+        let container = self
+            .container(&request.id)
+            .ok_or_else(|| Status::new(tonic::Code::NotFound, "Container not found"))?;
+        if container.status().await != ContainerStatus::RUNNING {
+            return Err(Status::new(
+                tonic::Code::FailedPrecondition,
+                "Container is not running",
+            ));
+        }
+        if let Err(err) = container.pause(&self.runtime).await {
+            return Err(Status::new(
+                error_code(&err),
+                format!("Failed to pause container: {}", err),
+            ));
+        }
+        Ok(Response::new(()))
+    }
+
+    async fn resume(&self, request: Request<ResumeRequest>) -> Result<Response<()>, Status> {
+        let _timer = self.metrics.rpc("Resume");
+        debug!("Resuming container");
+        let request = request.into_inner();
+        let container = self
+            .container(&request.id)
+            .ok_or_else(|| Status::new(tonic::Code::NotFound, "Container not found"))?;
+        if container.status().await != ContainerStatus::PAUSED {
+            return Err(Status::new(
+                tonic::Code::FailedPrecondition,
+                "Container is not paused",
+            ));
+        }
+        if let Err(err) = container.resume(&self.runtime).await {
+            return Err(Status::new(
+                error_code(&err),
+                format!("Failed to resume container: {}", err),
+            ));
+        }
+        Ok(Response::new(()))
+    }
+
+    async fn update(&self, request: Request<UpdateTaskRequest>) -> Result<Response<()>, Status> {
+        let _timer = self.metrics.rpc("Update");
+        debug!("Updating container resources");
+        let request = request.into_inner();
+        let Some(resources) = request.resources else {
+            return Err(Status::new(
+                tonic::Code::InvalidArgument,
+                "No resources given",
+            ));
+        };
+        if resources.type_url != LINUX_RESOURCES_TYPE_URL {
+            return Err(Status::new(
+                tonic::Code::InvalidArgument,
+                format!("Unsupported resources type {:?}", resources.type_url),
+            ));
+        }
+        if let Err(err) = serde_json::from_slice::<LinuxResources>(&resources.value) {
+            return Err(Status::new(
+                tonic::Code::InvalidArgument,
+                format!("Invalid resources: {}", err),
+            ));
+        }
+        let container = self
+            .container(&request.id)
+            .ok_or_else(|| Status::new(tonic::Code::NotFound, "Container not found"))?;
+        if container.status().await != ContainerStatus::RUNNING {
+            return Err(Status::new(
+                tonic::Code::FailedPrecondition,
+                "Container is not running",
+            ));
+        }
+        if let Err(err) = container.update(&self.runtime, &resources.value).await {
+            return Err(Status::new(
+                error_code(&err),
+                format!("Failed to update container resources: {}", err),
+            ));
+        }
+        Ok(Response::new(()))
+    }
+
+    async fn checkpoint(
+        &self,
+        request: Request<CheckpointTaskRequest>,
+    ) -> Result<Response<()>, Status> {
+        let _timer = self.metrics.rpc("Checkpoint");
+        debug!("Checkpointing container");
+        let request = request.into_inner();
+        let options = match checkpoint_options(request.path, request.options.as_ref()) {
+            Ok(options) => options,
+            Err(err) => {
+                return Err(Status::new(
+                    tonic::Code::InvalidArgument,
+                    format!("Invalid checkpoint options: {}", err),
+                ))
+            }
+        };
+        let container = self
+            .container(&request.id)
+            .ok_or_else(|| Status::new(tonic::Code::NotFound, "Container not found"))?;
+        if let Err(err) = container.checkpoint(&self.runtime, &options).await {
+            return Err(Status::new(
+                error_code(&err),
+                format!("Failed to checkpoint container: {}", err),
+            ));
+        }
+        Ok(Response::new(()))
+    }
+}
+
+/// Exec IDs name files in the bundle, so only allow characters that are safe there.
+fn valid_exec_id(exec_id: &str) -> bool {
+    !exec_id.is_empty()
+        && !exec_id.starts_with('.')
+        && exec_id
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '_' | '-' | '.'))
+}
+
+impl From<ContainerStatus> for String {
+    fn from(status: ContainerStatus) -> Self {
+        format!("{:?}", status).to_lowercase()
+    }
+}
+
+impl From<Stats> for Metrics {
+    fn from(stats: Stats) -> Self {
+        Self {
+            memory: stats.memory.map(|memory| MemoryMetrics {
+                usage: memory.usage,
+                limit: memory.limit,
+            }),
+            cpu: stats.cpu.map(|cpu| CpuMetrics {
+                usage_usec: cpu.usage_usec,
+                user_usec: cpu.user_usec,
+                system_usec: cpu.system_usec,
+            }),
+            pids: stats.pids.map(|pids| PidsMetrics {
+                current: pids.current,
+                limit: pids.limit,
+            }),
+        }
+    }
+}
+
+impl From<LogStats> for LogFileStats {
+    fn from(stats: LogStats) -> Self {
+        Self {
+            size: stats.size,
+            rotations: stats.rotations,
+            total_size: stats.total_size,
+        }
+    }
+}
+
+/// Returns the code for a failed container operation, from its [`ContainerError`] if it has
+/// one. A runtime that had to be killed for taking too long is a deadline error, anything else
+/// is internal.
+fn error_code(err: &anyhow::Error) -> tonic::Code {
+    match err.downcast_ref::<ContainerError>() {
+        Some(ContainerError::AlreadyExists(_)) => tonic::Code::AlreadyExists,
+        Some(ContainerError::NotFound(_)) => tonic::Code::NotFound,
+        Some(ContainerError::InvalidState(_)) => tonic::Code::FailedPrecondition,
+        Some(ContainerError::RuntimeFailed(_)) => tonic::Code::Internal,
+        None if err.is::<Elapsed>() => tonic::Code::DeadlineExceeded,
+        None => tonic::Code::Internal,
+    }
+}
+
+/// Returns the code for a failed Nsenter, from its [`NsenterError`] if it has one. A command
+/// that had to be killed for taking too long is a deadline error, anything else is internal.
+fn nsenter_error_code(err: &anyhow::Error) -> tonic::Code {
+    match err.downcast_ref::<NsenterError>() {
+        Some(NsenterError::InvalidArgument(_)) => tonic::Code::InvalidArgument,
+        Some(NsenterError::ProcessGone(_)) => tonic::Code::FailedPrecondition,
+        Some(NsenterError::PermissionDenied(_)) => tonic::Code::PermissionDenied,
+        None if err.is::<Elapsed>() => tonic::Code::DeadlineExceeded,
+        None => tonic::Code::Internal,
+    }
+}
+
+/// Decodes containerd's runc options. Empty strings leave the defaults in place.
+pub fn runtime_options(options: &Any) -> anyhow::Result<RuntimeOptions> {
+    // containerd sends the bare type name, other clients may prefix it with a domain.
+    let name = options.type_url.rsplit('/').next().unwrap_or_default();
+    if name != RUNC_OPTIONS_TYPE {
+        anyhow::bail!("unsupported type {:?}", options.type_url);
+    }
+    let options = RuncOptions::decode(options.value.as_slice())?;
+    let path =
+        |value: String| Some(PathBuf::from(value)).filter(|path| !path.as_os_str().is_empty());
+    Ok(RuntimeOptions {
+        binary_name: path(options.binary_name),
+        root: path(options.root),
+        systemd_cgroup: options.systemd_cgroup,
+        no_pivot_root: options.no_pivot_root,
+        no_new_keyring: options.no_new_keyring,
+    })
+}
+
+/// Decodes containerd's runc checkpoint options, which may override the request's image path.
+fn checkpoint_options(path: String, options: Option<&Any>) -> anyhow::Result<CheckpointOptions> {
+    let options = match options {
+        Some(options) => {
+            let name = options.type_url.rsplit('/').next().unwrap_or_default();
+            if name != RUNC_CHECKPOINT_OPTIONS_TYPE {
+                anyhow::bail!("unsupported type {:?}", options.type_url);
+            }
+            RuncCheckpointOptions::decode(options.value.as_slice())?
+        }
+        None => RuncCheckpointOptions::default(),
+    };
+    let image_path = if options.image_path.is_empty() {
+        path
+    } else {
+        options.image_path
+    };
+    if image_path.is_empty() {
+        anyhow::bail!("no image path given");
+    }
+    Ok(CheckpointOptions {
+        image_path: image_path.into(),
+        work_path: Some(PathBuf::from(options.work_path))
+            .filter(|path| !path.as_os_str().is_empty()),
+        leave_running: !options.exit,
+        tcp_established: options.open_tcp,
+        external_unix_sockets: options.external_unix_sockets,
+        shell_job: options.terminal,
+        file_locks: options.file_locks,
+        empty_namespaces: options.empty_namespaces,
+        cgroups_mode: Some(options.cgroups_mode).filter(|mode| !mode.is_empty()),
+    })
+}
+
+/// Writes the state file atomically, so that a restarted daemon never reads a partial one.
+fn write_state(path: &Path, bundles: &[PathBuf]) -> anyhow::Result<()> {
+    write_atomic(path, &serde_json::to_vec(bundles)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        fs::{File, OpenOptions},
+        io::{Read, Write},
+    };
+
+    use flate2::read::MultiGzDecoder;
+    use nix::{sys::stat::Mode, unistd::mkfifo};
+    use tokio::{
+        signal::unix::{signal, SignalKind},
+        task,
+    };
+
+    use super::*;
+    use crate::{
+        signal::ExitStatus,
+        testing::{exec_spec, wait_until, Fixture, TIMEOUT},
+    };
+
+    /// Runs a container with `cmd` and returns what Wait and Delete report of its exit.
+    async fn exit_of(fixture: &Fixture, cmd: &str) -> (WaitResponse, DeleteResponse) {
+        let service = fixture.service();
+        let container = fixture.container("exit", cmd, CreateOptions::default());
+        fixture.run(&container).await.unwrap();
+        let request = WaitRequest {
+            id: container.id.clone(),
+            ..Default::default()
+        };
+        let wait = time::timeout(TIMEOUT, service.wait(Request::new(request)))
+            .await
+            .expect("the container did not exit")
+            .unwrap()
+            .into_inner();
+        let request = DeleteRequest {
+            id: container.id.clone(),
+            ..Default::default()
+        };
+        let delete = service.delete(Request::new(request)).await.unwrap();
+        (wait, delete.into_inner())
+    }
+
+    #[tokio::test]
+    async fn normal_exit_reports_the_exit_code() {
+        for format in ["shell", "raw"] {
+            let fixture = Fixture::with_args(&["--exit-code-format", format]);
+            let (wait, delete) = exit_of(&fixture, "exit 3").await;
+            assert_eq!((wait.exit_status, wait.signaled), (3, false));
+            assert_eq!((delete.exit_status, delete.signaled), (3, false));
+        }
+    }
+
+    #[tokio::test]
+    async fn signaled_exit_is_128_plus_signal_by_default() {
+        let fixture = Fixture::new();
+        let (wait, delete) = exit_of(&fixture, "kill -9 $$").await;
+        assert_eq!((wait.exit_status, wait.signaled), (137, true));
+        assert_eq!((delete.exit_status, delete.signaled), (137, true));
+    }
+
+    #[tokio::test]
+    async fn signaled_exit_is_the_signal_in_raw_format() {
+        let fixture = Fixture::with_args(&["--exit-code-format", "raw"]);
+        let (wait, delete) = exit_of(&fixture, "kill -9 $$").await;
+        assert_eq!((wait.exit_status, wait.signaled), (9, true));
+        assert_eq!((delete.exit_status, delete.signaled), (9, true));
+    }
+
+    #[tokio::test]
+    async fn wait_times_out_on_a_running_container() {
+        let fixture = Fixture::new();
+        let service = fixture.service();
+        let container = fixture.container("wait", "exec sleep 30", CreateOptions::default());
+        fixture.run(&container).await.unwrap();
+        let request = WaitRequest {
+            id: container.id.clone(),
+            timeout: Some(Duration::from_millis(100).try_into().unwrap()),
+            ..Default::default()
+        };
+        let status = time::timeout(TIMEOUT, service.wait(Request::new(request.clone())))
+            .await
+            .expect("the wait ignored its timeout")
+            .unwrap_err();
+        assert_eq!(status.code(), tonic::Code::DeadlineExceeded);
+        assert!(status.message().contains("RUNNING"));
+        assert_eq!(container.status().await, ContainerStatus::RUNNING);
+
+        // A later wait still sees the exit.
+        container.kill("", Signal::SIGKILL).await.unwrap();
+        let wait = time::timeout(TIMEOUT, service.wait(Request::new(request)))
+            .await
+            .expect("the container did not exit")
+            .unwrap()
+            .into_inner();
+        assert_eq!((wait.exit_status, wait.signaled), (137, true));
+    }
+
+    #[tokio::test]
+    async fn wait_rejects_a_negative_timeout() {
+        let fixture = Fixture::new();
+        let service = fixture.service();
+        let container = fixture.container("wait", "exec sleep 30", CreateOptions::default());
+        fixture.run(&container).await.unwrap();
+        let request = WaitRequest {
+            id: container.id.clone(),
+            timeout: Some(prost_types::Duration {
+                seconds: -1,
+                nanos: 0,
+            }),
+            ..Default::default()
+        };
+        let status = service.wait(Request::new(request)).await.unwrap_err();
+        assert_eq!(status.code(), tonic::Code::InvalidArgument);
+    }
+
+    #[tokio::test]
+    async fn kill_during_create_is_unavailable() {
+        let fixture = Fixture::new();
+        let service = fixture.service();
+        let container = fixture.container("create", "exec sleep 30", CreateOptions::default());
+        let mut signals = signal(SignalKind::user_defined2()).unwrap();
+        let hold = fixture.root().join("hold-create");
+        fs::write(&hold, "").unwrap();
+        let kill = |signal: Signal| KillRequest {
+            id: container.id.clone(),
+            signal: signal as u32,
+            ..Default::default()
+        };
+        let created = fixture.create(&container);
+        let killed = async {
+            // The runtime has been invoked, but the container's pid isn't known yet.
+            assert!(wait_until(|| fixture.invocations("create", &container) == 1).await);
+            let status = service
+                .kill(Request::new(kill(Signal::SIGUSR2)))
+                .await
+                .unwrap_err();
+            fs::remove_file(&hold).unwrap();
+            status
+        };
+        let (created, status) = tokio::join!(created, killed);
+        created.unwrap();
+        assert_eq!(status.code(), tonic::Code::Unavailable);
+        // kill(0) would have delivered it to the test's own process group.
+        assert!(time::timeout(Duration::from_millis(200), signals.recv())
+            .await
+            .is_err());
+        service
+            .kill(Request::new(kill(Signal::SIGKILL)))
+            .await
+            .unwrap();
+        let exit = time::timeout(TIMEOUT, container.process("").await.unwrap().wait())
+            .await
+            .unwrap();
+        assert_eq!(
+            exit.exit_status,
+            ExitStatus::Signaled(Signal::SIGKILL as i32)
+        );
+    }
+
+    /// Runs a container that writes a last line when it is terminated, shuts down the service
+    /// and returns the container's stdout file.
+    async fn output_after_shutdown(fixture: &Fixture) -> PathBuf {
+        let service = fixture.service();
+        let container = fixture.container(
+            "shutdown",
+            "trap 'echo last words; exit 1' TERM; touch \"$0.ready\"; while :; do sleep 0.1; done",
+            CreateOptions::default(),
+        );
+        fixture.run(&container).await.unwrap();
+        let ready = container.bundle.join("cmd.ready");
+        assert!(wait_until(|| ready.exists()).await);
+        service
+            .shutdown(Request::new(ShutdownRequest::default()))
+            .await
+            .unwrap();
+        assert!(service.containers.is_empty());
+        container.stdout.clone()
+    }
+
+    #[tokio::test]
+    async fn last_line_survives_shutdown() {
+        let fixture = Fixture::new();
+        let stdout = output_after_shutdown(&fixture).await;
+        assert_eq!(fs::read_to_string(stdout).unwrap(), "last words\n");
+    }
+
+    #[tokio::test]
+    async fn last_compressed_line_survives_shutdown() {
+        let fixture = Fixture::with_args(&["--log-compression", "gzip"]);
+        let stdout = output_after_shutdown(&fixture).await;
+        let mut output = String::new();
+        MultiGzDecoder::new(fs::File::open(stdout).unwrap())
+            .read_to_string(&mut output)
+            .unwrap();
+        assert_eq!(output, "last words\n");
+    }
+
+    #[tokio::test]
+    async fn exec_stdio_goes_through_its_fifos() {
+        let fixture = Fixture::new();
+        let service = fixture.service();
+        let container = fixture.container("exec", "exec sleep 30", CreateOptions::default());
+        fixture.run(&container).await.unwrap();
+        let stdin = fixture.dir.path().join("exec-stdin");
+        let stdout = fixture.dir.path().join("exec-stdout");
+        for fifo in [&stdin, &stdout] {
+            mkfifo(fifo, Mode::from_bits_truncate(0o600)).unwrap();
+        }
+        let request = ExecProcessRequest {
+            id: container.id.clone(),
+            exec_id: "cat".to_string(),
+            stdin: stdin.to_string_lossy().into_owned(),
+            stdout: stdout.to_string_lossy().into_owned(),
+            spec: Some(Any {
+                type_url: String::new(),
+                value: exec_spec("cat"),
+            }),
+            ..Default::default()
+        };
+        service.exec(Request::new(request)).await.unwrap();
+        let request = StartRequest {
+            id: container.id.clone(),
+            exec_id: "cat".to_string(),
+        };
+        service.start(Request::new(request)).await.unwrap();
+
+        // Opening the fifos waits for the shim to open their other ends.
+        let output = task::spawn_blocking(move || {
+            let mut writer = OpenOptions::new().write(true).open(stdin).unwrap();
+            writer.write_all(b"hello\n").unwrap();
+            let mut output = [0; 6];
+            File::open(stdout).unwrap().read_exact(&mut output).unwrap();
+            // Kept open, so that only CloseIO gives the process EOF.
+            (output, writer)
+        });
+        let (output, _writer) = time::timeout(TIMEOUT, output).await.unwrap().unwrap();
+        assert_eq!(&output, b"hello\n");
+
+        let request = CloseIoRequest {
+            id: container.id.clone(),
+            exec_id: "cat".to_string(),
+            stdin: true,
+        };
+        service.close_io(Request::new(request)).await.unwrap();
+        let request = WaitRequest {
+            id: container.id.clone(),
+            exec_id: "cat".to_string(),
+            ..Default::default()
+        };
+        let wait = time::timeout(TIMEOUT, service.wait(Request::new(request)))
+            .await
+            .expect("the exec did not exit")
+            .unwrap()
+            .into_inner();
+        assert_eq!(wait.exit_status, 0);
+    }
 }
@@ -1,55 +1,87 @@
-use std::time::Duration;
+use std::os::fd::{AsRawFd, BorrowedFd, FromRawFd, OwnedFd};
 
 use anyhow::{bail, Result};
 use nix::{
-    libc::pid_t,
+    errno::Errno,
+    libc::{self, pid_t},
     sys::{
         signal::{kill, Signal},
-        wait::{waitpid, WaitPidFlag, WaitStatus},
+        wait::{waitid, waitpid, Id, WaitPidFlag, WaitStatus},
     },
     unistd::Pid,
 };
+use serde::{Deserialize, Serialize};
 use tokio::{
-    signal::unix::{signal, SignalKind},
+    signal::unix::{signal, Signal as SignalStream, SignalKind},
     sync::mpsc,
-    time::sleep,
 };
 use tracing::{debug, error, info, warn};
 
-pub async fn handle_signals(sender: mpsc::UnboundedSender<(pid_t, i32)>) -> Result<()> {
-    let mut sigchld = signal(SignalKind::child())?;
+use crate::{
+    config::ExitCodeFormat,
+    process::{PidRegistry, Reaped},
+};
+
+/// How a reaped process terminated.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ExitStatus {
+    /// The process exited with the given code.
+    Exited(i32),
+
+    /// The process was terminated by the given signal.
+    Signaled(i32),
+}
+
+impl ExitStatus {
+    /// Returns the exit code to report to clients.
+    pub fn code(&self, format: ExitCodeFormat) -> u32 {
+        match (self, format) {
+            (ExitStatus::Exited(code), _) => *code as u32,
+            (ExitStatus::Signaled(signal), ExitCodeFormat::Shell) => 128 + *signal as u32,
+            (ExitStatus::Signaled(signal), ExitCodeFormat::Raw) => *signal as u32,
+        }
+    }
 
+    pub fn signaled(&self) -> bool {
+        matches!(self, ExitStatus::Signaled(_))
+    }
+}
+
+/// Listens for SIGCHLD, so that no exit is missed between setting up the reaper and running
+/// [`handle_signals`]. Children that exit before it are only noticed on the next SIGCHLD.
+pub fn sigchld() -> Result<SignalStream> {
+    Ok(signal(SignalKind::child())?)
+}
+
+/// Reaps exited children as they are signalled by `sigchld`, sending the exits of container
+/// processes and orphans to `sender`. Helpers get their exit through the [`PidRegistry`].
+pub async fn handle_signals(
+    mut sigchld: SignalStream,
+    sender: mpsc::UnboundedSender<Reaped>,
+    pids: PidRegistry,
+) -> Result<()> {
     loop {
         tokio::select! {
             _ = sigchld.recv() => {
                 debug!("Received SIGCHLD");
-                // Because container PIDs are not known a priori, we call `waitpid` with a PID of -1.
-                // However, not all SIGCHLD signals are from container processes (e.g. a
-                // `process::Command` to create a container).
-                //
-                // TODO: Sleeping is a hack awit to avoid prematurely reaping commands
-                sleep(Duration::from_millis(10)).await;
+                // Because container PIDs are not known a priori, we wait for any child. Helpers
+                // such as the OCI runtime are registered before they can be reaped, so their
+                // exits are handed back rather than taken for orphans.
                 loop {
-                    match waitpid(Pid::from_raw(-1), Some(WaitPidFlag::WNOHANG)) {
-                        Ok(WaitStatus::Exited(pid, status)) => {
-                            info!("Process {} exited with status {}", pid, status);
-                            if let Err(err) = sender.send((pid.as_raw(), status)) {
-                                error!("Failed to send exit status: {}", err);
-                            }
-                        }
-                        Ok(WaitStatus::Signaled(pid, signal, _)) => {
-                            info!("Process {} exited with signal {}", pid, signal);
-                            if let Err(err) = sender.send((pid.as_raw(), 128 + signal as i32)) {
-                                error!("Failed to send exit status: {}", err);
-                            }
-                        }
+                    // Only look at the next exited child here, the registry reaps it through
+                    // the pidfd of the process registered with its pid.
+                    let flags = WaitPidFlag::WEXITED | WaitPidFlag::WNOHANG | WaitPidFlag::WNOWAIT;
+                    let pid = match retry_eintr(|| waitid(Id::All, flags)) {
                         Ok(WaitStatus::StillAlive) => {
                             // Still some unterminated child process
                             break;
                         }
-                        Ok(_) => {
+                        Ok(status) => match status.pid() {
+                            Some(pid) => pid,
                             // Unknown status
-                        }
+                            None => break,
+                        },
                         Err(nix::Error::ECHILD) => {
                             // No child processes
                             break;
@@ -59,14 +91,100 @@ pub async fn handle_signals(sender: mpsc::UnboundedSender<(pid_t, i32)>) -> Resu
                             break;
                         }
                     };
+                    // A helper, whose exit went to whoever spawned it, or already reaped.
+                    let Some(reaped) = pids.reap(pid.as_raw()) else {
+                        continue;
+                    };
+                    match reaped.exit_status {
+                        ExitStatus::Exited(status) => {
+                            info!("Process {} exited with status {}", pid, status)
+                        }
+                        ExitStatus::Signaled(signal) => {
+                            info!("Process {} exited with signal {}", pid, signal)
+                        }
+                    }
+                    if let Err(err) = sender.send(reaped) {
+                        error!("Failed to send exit status: {}", err);
+                    }
                 }
             }
         }
     }
 }
 
-pub fn forward_signal(pid: Pid, signal: Signal) -> Result<()> {
-    match kill(pid, signal) {
+/// Waits until the process is asked to terminate with SIGTERM or SIGINT.
+pub async fn wait_for_termination() -> Result<()> {
+    let mut sigterm = signal(SignalKind::terminate())?;
+    let mut sigint = signal(SignalKind::interrupt())?;
+    tokio::select! {
+        _ = sigterm.recv() => info!("Received SIGTERM, shutting down"),
+        _ = sigint.recv() => info!("Received SIGINT, shutting down"),
+    }
+    Ok(())
+}
+
+/// Opens a pidfd referring to the process with the given pid.
+pub fn pidfd_open(pid: pid_t) -> nix::Result<OwnedFd> {
+    let fd = unsafe { libc::syscall(libc::SYS_pidfd_open, pid, 0) };
+    Errno::result(fd).map(|fd| unsafe { OwnedFd::from_raw_fd(fd as i32) })
+}
+
+fn pidfd_send_signal(pidfd: BorrowedFd, signal: Signal) -> nix::Result<()> {
+    let result = unsafe {
+        libc::syscall(
+            libc::SYS_pidfd_send_signal,
+            pidfd.as_raw_fd(),
+            signal as libc::c_int,
+            std::ptr::null::<libc::siginfo_t>(),
+            0,
+        )
+    };
+    Errno::result(result).map(drop)
+}
+
+/// Reaps the process a pidfd refers to if it has exited. Returns `None` if it is still running
+/// or is not our child, e.g. because it was already reaped.
+pub fn try_reap_pidfd(pidfd: BorrowedFd) -> Option<ExitStatus> {
+    let flags = WaitPidFlag::WEXITED | WaitPidFlag::WNOHANG;
+    match retry_eintr(|| waitid(Id::PIDFd(pidfd), flags)) {
+        Ok(WaitStatus::Exited(_, code)) => Some(ExitStatus::Exited(code)),
+        Ok(WaitStatus::Signaled(_, signal, _)) => Some(ExitStatus::Signaled(signal as i32)),
+        Ok(_) | Err(_) => None,
+    }
+}
+
+/// Reaps a specific process if it has exited. Returns `None` if it is still running or was
+/// already reaped, e.g. by [`handle_signals`].
+pub fn try_reap(pid: Pid) -> Option<ExitStatus> {
+    match retry_eintr(|| waitpid(pid, Some(WaitPidFlag::WNOHANG))) {
+        Ok(WaitStatus::Exited(_, code)) => Some(ExitStatus::Exited(code)),
+        Ok(WaitStatus::Signaled(_, signal, _)) => Some(ExitStatus::Signaled(signal as i32)),
+        Ok(_) | Err(_) => None,
+    }
+}
+
+/// Retries a system call that was interrupted by a signal before it could complete.
+fn retry_eintr<T>(mut call: impl FnMut() -> nix::Result<T>) -> nix::Result<T> {
+    loop {
+        match call() {
+            Err(Errno::EINTR) => continue,
+            result => return result,
+        }
+    }
+}
+
+/// Sends a signal to a process, through its pidfd if it has one so that it can't reach another
+/// process that reused the pid after it exited.
+pub fn forward_signal(pid: Pid, pidfd: Option<BorrowedFd>, signal: Signal) -> Result<()> {
+    let result = match pidfd {
+        Some(pidfd) => match pidfd_send_signal(pidfd, signal) {
+            // pidfd_open is older than pidfd_send_signal, but seccomp may still deny it.
+            Err(nix::Error::ENOSYS) => kill(pid, signal),
+            result => result,
+        },
+        None => kill(pid, signal),
+    };
+    match result {
         Ok(()) => Ok(()),
         Err(nix::Error::ESRCH) => {
             warn!("Process {} not found, ignoring signal", pid);
@@ -77,3 +195,109 @@ pub fn forward_signal(pid: Pid, signal: Signal) -> Result<()> {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        fs,
+        os::{fd::AsFd, unix::process::ExitStatusExt, unix::thread::JoinHandleExt},
+        process::Command,
+        sync::atomic::{AtomicUsize, Ordering},
+        thread,
+        time::Duration,
+    };
+
+    use nix::{
+        sys::signal::{sigaction, SaFlags, SigAction, SigHandler, SigSet},
+        unistd::{pipe, read, write},
+    };
+    use tokio::time::{sleep, timeout};
+
+    use super::*;
+    use crate::testing;
+
+    static INTERRUPTS: AtomicUsize = AtomicUsize::new(0);
+
+    extern "C" fn count_interrupt(_: libc::c_int) {
+        INTERRUPTS.fetch_add(1, Ordering::SeqCst);
+    }
+
+    #[test]
+    fn interrupted_calls_are_retried() {
+        let mut calls = 0;
+        let result = retry_eintr(|| {
+            calls += 1;
+            if calls < 3 {
+                Err(Errno::EINTR)
+            } else {
+                Ok(calls)
+            }
+        });
+        assert_eq!(result, Ok(3));
+    }
+
+    #[test]
+    fn other_errors_are_not_retried() {
+        let mut calls = 0;
+        let result: nix::Result<()> = retry_eintr(|| {
+            calls += 1;
+            Err(Errno::ECHILD)
+        });
+        assert_eq!(result, Err(Errno::ECHILD));
+        assert_eq!(calls, 1);
+    }
+
+    #[test]
+    fn signal_during_blocking_call_is_not_an_error() {
+        // Without SA_RESTART, a blocking call interrupted by the signal fails with EINTR.
+        let action = SigAction::new(
+            SigHandler::Handler(count_interrupt),
+            SaFlags::empty(),
+            SigSet::empty(),
+        );
+        unsafe { sigaction(Signal::SIGUSR1, &action) }.unwrap();
+        let (reader, writer) = pipe().unwrap();
+        let waiter = thread::spawn(move || {
+            let mut buf = [0; 1];
+            retry_eintr(|| read(reader.as_raw_fd(), &mut buf))
+        });
+        let thread = waiter.as_pthread_t();
+        while INTERRUPTS.load(Ordering::SeqCst) < 5 {
+            assert_eq!(unsafe { libc::pthread_kill(thread, libc::SIGUSR1) }, 0);
+            thread::sleep(Duration::from_millis(10));
+        }
+        write(&writer, b"x").unwrap();
+        assert_eq!(waiter.join().unwrap(), Ok(1));
+    }
+
+    #[tokio::test]
+    async fn signal_is_forwarded_through_pidfd() {
+        let dir = tempfile::tempdir().unwrap();
+        let pid_file = dir.path().join("pid");
+        let script = format!("echo $$ > '{}'; exec sleep 30", pid_file.display());
+        let helper = testing::pids()
+            .spawn(Command::new("sh").arg("-c").arg(script))
+            .unwrap();
+        let pid = loop {
+            let pid = fs::read_to_string(&pid_file).unwrap_or_default();
+            if let Ok(pid) = pid.trim().parse() {
+                break pid;
+            }
+            sleep(Duration::from_millis(10)).await;
+        };
+        let pidfd = pidfd_open(pid).unwrap();
+        forward_signal(Pid::from_raw(pid), Some(pidfd.as_fd()), Signal::SIGKILL).unwrap();
+        let output = timeout(Duration::from_secs(10), helper.wait_with_output())
+            .await
+            .expect("the signal was not delivered")
+            .unwrap();
+        assert_eq!(output.status.signal(), Some(libc::SIGKILL));
+    }
+
+    #[test]
+    fn signal_to_missing_process_is_ignored() {
+        // Beyond the largest pid_max, so no process can have it.
+        let pid = Pid::from_raw(i32::MAX);
+        assert!(forward_signal(pid, None, Signal::SIGTERM).is_ok());
+    }
+}
@@ -0,0 +1,287 @@
+use std::{fs, path::Path};
+
+use anyhow::{bail, Context, Result};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+const CONFIG_FILE: &str = "config.json";
+
+/// The subset of the bundle's OCI runtime spec that the shim cares about.
+#[derive(Deserialize, Debug, Default)]
+#[serde(rename_all = "camelCase")]
+pub struct Spec {
+    #[serde(default)]
+    pub process: Option<Process>,
+
+    #[serde(default)]
+    pub linux: Option<Linux>,
+}
+
+#[derive(Deserialize, Debug, Default)]
+#[serde(rename_all = "camelCase")]
+pub struct Process {
+    /// Kept unparsed so that malformed rlimits don't fail loading the whole spec, see
+    /// [`Spec::rlimits`].
+    #[serde(default)]
+    pub rlimits: Option<serde_json::Value>,
+}
+
+/// A resource limit the runtime applies to the container's processes.
+#[derive(Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct Rlimit {
+    /// The resource, e.g. `RLIMIT_NOFILE`.
+    #[serde(rename = "type")]
+    pub kind: String,
+
+    pub hard: u64,
+
+    pub soft: u64,
+}
+
+#[derive(Deserialize, Debug, Default)]
+#[serde(rename_all = "camelCase")]
+pub struct Linux {
+    #[serde(default)]
+    pub seccomp: Option<Seccomp>,
+
+    #[serde(default)]
+    pub cgroups_path: Option<String>,
+}
+
+#[derive(Deserialize, Debug, Default)]
+#[serde(rename_all = "camelCase")]
+pub struct Seccomp {
+    /// Socket to which the runtime sends the seccomp notify fd.
+    #[serde(default)]
+    pub listener_path: Option<String>,
+}
+
+/// The fields of an OCI process spec given for exec that are checked before the runtime sees it.
+#[derive(Deserialize)]
+struct ExecProcess {
+    #[serde(default)]
+    args: Vec<String>,
+}
+
+/// Checks that a JSON encoded OCI process spec is one the runtime can exec.
+pub fn validate_exec_process(spec: &[u8]) -> Result<()> {
+    let process: ExecProcess = serde_json::from_slice(spec).context("Invalid process spec")?;
+    if process.args.is_empty() {
+        bail!("Process spec has no args");
+    }
+    Ok(())
+}
+
+impl Spec {
+    /// Reads the spec from the bundle's `config.json`.
+    pub fn load<P: AsRef<Path>>(bundle: P) -> Result<Self> {
+        let path = bundle.as_ref().join(CONFIG_FILE);
+        let contents = fs::read(&path).with_context(|| format!("Failed to read {:?}", path))?;
+        serde_json::from_slice(&contents).with_context(|| format!("Failed to parse {:?}", path))
+    }
+
+    /// Returns the spec's `process.rlimits`, or an error if they are malformed.
+    pub fn rlimits(&self) -> Result<Vec<Rlimit>> {
+        match self
+            .process
+            .as_ref()
+            .and_then(|process| process.rlimits.as_ref())
+        {
+            Some(rlimits) => {
+                Vec::<Rlimit>::deserialize(rlimits).context("Invalid process.rlimits in spec")
+            }
+            None => Ok(Vec::new()),
+        }
+    }
+
+    /// Checks that `linux.cgroupsPath` is in the `slice:prefix:name` form the runtime expects
+    /// when it manages cgroups through systemd, e.g. `system.slice:docker:1234`. An unset path
+    /// is left to the runtime's default.
+    pub fn validate_systemd_cgroups_path(&self) -> Result<()> {
+        let Some(path) = self
+            .linux
+            .as_ref()
+            .and_then(|linux| linux.cgroups_path.as_deref())
+            .filter(|path| !path.is_empty())
+        else {
+            return Ok(());
+        };
+        let parts: Vec<_> = path.split(':').collect();
+        let [slice, _prefix, name] = parts[..] else {
+            bail!(
+                "cgroupsPath {:?} is not in the slice:prefix:name form systemd cgroups need",
+                path
+            );
+        };
+        if !slice.is_empty() && !slice.ends_with(".slice") {
+            bail!("cgroupsPath {:?} does not start with a .slice", path);
+        }
+        if name.is_empty() {
+            bail!("cgroupsPath {:?} has no name", path);
+        }
+        Ok(())
+    }
+
+    pub fn seccomp_listener_path(&self) -> Option<&str> {
+        self.linux
+            .as_ref()?
+            .seccomp
+            .as_ref()?
+            .listener_path
+            .as_deref()
+            .filter(|path| !path.is_empty())
+    }
+}
+
+/// Changes to a bundle's spec that are applied without modifying the bundle's `config.json`.
+#[derive(Debug, Default)]
+pub struct SpecPatch {
+    /// Overrides `process.apparmorProfile`.
+    pub apparmor_profile: Option<String>,
+
+    /// Overrides `process.selinuxLabel`.
+    pub selinux_label: Option<String>,
+}
+
+impl SpecPatch {
+    pub fn is_empty(&self) -> bool {
+        self.apparmor_profile.is_none() && self.selinux_label.is_none()
+    }
+
+    /// Checks that the security labels are syntactically plausible.
+    pub fn validate(&self) -> Result<()> {
+        if let Some(profile) = &self.apparmor_profile {
+            validate_apparmor_profile(profile)?;
+        }
+        if let Some(label) = &self.selinux_label {
+            validate_selinux_label(label)?;
+        }
+        Ok(())
+    }
+
+    /// Writes a patched copy of the bundle's spec into `dir`, which can then be passed to the
+    /// runtime as the bundle. A relative root path is made absolute so that the rootfs is still
+    /// found in the original bundle. Fields the shim doesn't know about are kept as is.
+    pub fn write(&self, bundle: &Path, dir: &Path) -> Result<()> {
+        let path = bundle.join(CONFIG_FILE);
+        let contents = fs::read(&path).with_context(|| format!("Failed to read {:?}", path))?;
+        let mut spec: Value = serde_json::from_slice(&contents)
+            .with_context(|| format!("Failed to parse {:?}", path))?;
+        let Some(spec_object) = spec.as_object_mut() else {
+            bail!("{:?} is not a JSON object", path);
+        };
+        if let Some(root) = spec_object.get_mut("root").and_then(Value::as_object_mut) {
+            if let Some(root_path) = root.get("path").and_then(Value::as_str) {
+                let root_path = bundle.join(root_path);
+                root.insert("path".into(), root_path.to_string_lossy().into());
+            }
+        }
+        let process = spec_object
+            .entry("process")
+            .or_insert_with(|| Value::Object(Default::default()));
+        let Some(process) = process.as_object_mut() else {
+            bail!("process in {:?} is not a JSON object", path);
+        };
+        if let Some(profile) = &self.apparmor_profile {
+            process.insert("apparmorProfile".into(), profile.as_str().into());
+        }
+        if let Some(label) = &self.selinux_label {
+            process.insert("selinuxLabel".into(), label.as_str().into());
+        }
+        fs::create_dir_all(dir).with_context(|| format!("Failed to create {:?}", dir))?;
+        let patched = dir.join(CONFIG_FILE);
+        fs::write(&patched, serde_json::to_vec(&spec)?)
+            .with_context(|| format!("Failed to write {:?}", patched))?;
+        Ok(())
+    }
+}
+
+/// Checks that an AppArmor profile name is plausible: non-empty, printable and without
+/// whitespace.
+fn validate_apparmor_profile(profile: &str) -> Result<()> {
+    if profile.is_empty() || profile.len() > 255 {
+        bail!("AppArmor profile must be 1 to 255 bytes long");
+    }
+    if profile.chars().any(|c| c.is_whitespace() || c.is_control()) {
+        bail!("AppArmor profile {:?} contains whitespace", profile);
+    }
+    Ok(())
+}
+
+/// Checks that an SELinux label has the `user:role:type[:level]` form.
+fn validate_selinux_label(label: &str) -> Result<()> {
+    if label.chars().any(|c| c.is_whitespace() || c.is_control()) {
+        bail!("SELinux label {:?} contains whitespace", label);
+    }
+    // The level may itself contain colons, e.g. `s0:c1,c2`.
+    let parts: Vec<_> = label.splitn(4, ':').collect();
+    if parts.len() < 3 || parts.iter().any(|part| part.is_empty()) {
+        bail!(
+            "SELinux label {:?} is not of the form user:role:type[:level]",
+            label
+        );
+    }
+    Ok(())
+}
+
+/// Resource limits of a container, `linux.resources` in the spec. Only the limits the shim reads
+/// back from the cgroup are included.
+#[derive(Serialize, Deserialize, Debug, Default, Clone, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub struct LinuxResources {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub memory: Option<LinuxMemory>,
+
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub cpu: Option<LinuxCpu>,
+
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub pids: Option<LinuxPids>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Default, Clone, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub struct LinuxMemory {
+    /// Memory limit in bytes.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub limit: Option<i64>,
+
+    /// Memory soft limit in bytes.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub reservation: Option<i64>,
+
+    /// Limit of memory plus swap in bytes.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub swap: Option<i64>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Default, Clone, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub struct LinuxCpu {
+    /// Relative CPU weight (cgroup v1 shares).
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub shares: Option<u64>,
+
+    /// CPU time in microseconds the container may use per period.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub quota: Option<i64>,
+
+    /// CFS period in microseconds.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub period: Option<u64>,
+
+    /// CPUs the container may run on, e.g. `0-3,7`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub cpus: Option<String>,
+
+    /// Memory nodes the container may use.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub mems: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Default, Clone, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub struct LinuxPids {
+    /// Maximum number of processes, -1 for unlimited.
+    pub limit: i64,
+}
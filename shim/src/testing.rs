@@ -0,0 +1,403 @@
+//! Helpers for tests that run containers through a fake OCI runtime.
+
+use std::{
+    fs,
+    os::unix::fs::PermissionsExt,
+    path::PathBuf,
+    sync::{
+        atomic::{AtomicU32, Ordering},
+        mpsc as std_mpsc, Arc, Mutex, OnceLock, Weak,
+    },
+    thread,
+    time::Duration,
+};
+
+use anyhow::Result;
+use clap::Parser;
+use dashmap::DashMap;
+use nix::sys::{prctl::set_child_subreaper, signal::Signal};
+use tempfile::TempDir;
+use time::OffsetDateTime;
+use tokio::{
+    sync::mpsc,
+    time::{sleep, Instant},
+};
+
+use crate::{
+    config::Config,
+    container::{Container, CreateOptions},
+    events::Publisher,
+    metrics::Metrics,
+    process::{PidRegistry, Reaped},
+    runtime::Runtime,
+    seccomp::KeepOpen,
+    service::TaskService,
+    signal::{handle_signals, sigchld},
+    utils::ExitSignal,
+};
+
+/// A fake OCI runtime. A container runs `sh <bundle>/cmd` once it is started, with the stdio
+/// the runtime was given at create. Its state is kept in files in the runtime root: the pid of
+/// its process in `<id>.pid` and whether it was started in `<id>.started`. Every invocation is
+/// appended to `invocations`, and each line of `create-errors` fails one create or restore with
+/// that error in the runtime log. Create and restore wait while `hold-create` exists. An exec'd
+/// process runs the script of a process spec written by [`exec_spec`], its pid kept in
+/// `<id>-exec-<pid>.pid`.
+const FAKE_RUNTIME: &str = r#"#!/bin/sh
+root=. log=/dev/null
+while :; do
+    case $1 in
+    --root) root=$2; shift 2 ;;
+    --log) log=$2; shift 2 ;;
+    --log-format) shift 2 ;;
+    --systemd-cgroup) shift ;;
+    *) break ;;
+    esac
+done
+command=$1
+shift
+bundle= pid_file= image= force= leave_running= resources= process=
+while [ $# -gt 1 ]; do
+    case $1 in
+    --bundle) bundle=$2; shift 2 ;;
+    --pid-file) pid_file=$2; shift 2 ;;
+    --image-path) image=$2; shift 2 ;;
+    --force) force=1; shift ;;
+    --leave-running) leave_running=1; shift ;;
+    --resources) resources=$2; shift 2 ;;
+    --process) process=$2; shift 2 ;;
+    *) shift ;;
+    esac
+done
+id=$1
+state=$root/$id
+echo "$command $id" >> "$root/invocations"
+
+fail() {
+    echo "{\"level\":\"error\",\"msg\":\"$1\"}" >> "$log"
+    echo "$1" >&2
+    exit 1
+}
+
+running() {
+    [ -e "$state.pid" ] && kill -0 "$(cat "$state.pid")" 2>/dev/null
+}
+
+case $command in
+create|restore)
+    while [ -e "$root/hold-create" ]; do sleep 0.01; done
+    if [ -s "$root/create-errors" ]; then
+        error=$(head -n 1 "$root/create-errors")
+        sed -i 1d "$root/create-errors"
+        fail "$error"
+    fi
+    [ -e "$state.pid" ] && fail "container with id exists: $id"
+    if [ "$command" = restore ]; then
+        [ -e "$image/checkpoint" ] || fail "no checkpoint in $image"
+        touch "$state.started"
+    fi
+    # An asynchronous list gets /dev/null as stdin unless it is redirected explicitly.
+    exec 3<&0
+    (
+        while [ ! -e "$state.started" ]; do sleep 0.01; done
+        exec sh "$bundle/cmd" 3<&-
+    ) <&3 &
+    echo $! > "$state.pid"
+    echo $! > "$pid_file"
+    ;;
+start)
+    [ -e "$state.pid" ] || fail "container does not exist: $id"
+    touch "$state.started"
+    ;;
+state)
+    [ -e "$state.pid" ] || fail "container does not exist: $id"
+    if ! running; then
+        echo "{\"id\":\"$id\",\"status\":\"stopped\",\"pid\":0}"
+    elif [ -e "$state.started" ]; then
+        echo "{\"id\":\"$id\",\"status\":\"running\",\"pid\":$(cat "$state.pid")}"
+    else
+        echo "{\"id\":\"$id\",\"status\":\"created\",\"pid\":$(cat "$state.pid")}"
+    fi
+    ;;
+exec)
+    running || fail "container is not running: $id"
+    script=$(sed -n 's/^{"args":\["sh","-c","\(.*\)"\]}$/\1/p' "$process")
+    exec 3<&0
+    sh -c "$script" <&3 3<&- &
+    echo $! > "$state-exec-$!.pid"
+    echo $! > "$pid_file"
+    ;;
+update)
+    running || fail "container is not running: $id"
+    [ -e "$resources" ] || fail "no resources in $resources"
+    ;;
+checkpoint)
+    running || fail "container is not running: $id"
+    mkdir -p "$image" && touch "$image/checkpoint"
+    [ -n "$leave_running" ] || kill -9 "$(cat "$state.pid")"
+    ;;
+delete)
+    if running; then
+        [ -n "$force" ] || fail "cannot delete container $id that is not stopped"
+        kill -9 "$(cat "$state.pid")"
+        for exec in "$state"-exec-*.pid; do
+            [ -e "$exec" ] && kill -9 "$(cat "$exec")" 2>/dev/null
+        done
+    elif [ ! -e "$state.pid" ] && [ -z "$force" ]; then
+        fail "container does not exist: $id"
+    fi
+    rm -f "$state.pid" "$state.started" "$state"-exec-*.pid
+    ;;
+*)
+    fail "unsupported command: $command"
+    ;;
+esac
+"#;
+
+/// Returns a process spec for the fake runtime's exec that runs `script` with `sh -c`. The
+/// script can't contain anything JSON would escape.
+pub fn exec_spec(script: &str) -> Vec<u8> {
+    assert!(
+        !script.contains(['"', '\\']),
+        "unsupported script {:?}",
+        script
+    );
+    format!(r#"{{"args":["sh","-c","{}"]}}"#, script).into_bytes()
+}
+
+/// How long tests wait for a container's processes to do something.
+pub const TIMEOUT: Duration = Duration::from_secs(10);
+
+type Containers = DashMap<String, Arc<Container>>;
+
+static REAPER: OnceLock<PidRegistry> = OnceLock::new();
+
+/// The containers of the live [`Fixture`]s, which the reaper dispatches exits to.
+static FIXTURES: Mutex<Vec<Weak<Containers>>> = Mutex::new(Vec::new());
+
+/// Makes container IDs unique across the tests running in parallel, as they share the reaper.
+static NEXT_ID: AtomicU32 = AtomicU32::new(0);
+
+fn find_container(id: &str) -> Option<Arc<Container>> {
+    FIXTURES
+        .lock()
+        .unwrap()
+        .iter()
+        .filter_map(Weak::upgrade)
+        .find_map(|containers| containers.get(id).map(|container| container.clone()))
+}
+
+/// Returns the registry of the test process's reaper, starting it on first use. There can only
+/// be one, as it reaps any exited child of the process. It runs on a thread of its own, so that
+/// exits are reaped whichever test's runtime is running, and dispatches them to the containers
+/// created by [`Fixture`]s.
+pub fn pids() -> PidRegistry {
+    REAPER
+        .get_or_init(|| {
+            // The runtime leaves the containers' processes behind for us to reap.
+            set_child_subreaper(true).expect("Failed to set subreaper");
+            let pids = PidRegistry::default();
+            let registry = pids.clone();
+            let (ready, started) = std_mpsc::channel();
+            thread::spawn(move || {
+                let runtime = tokio::runtime::Builder::new_current_thread()
+                    .enable_all()
+                    .build()
+                    .expect("Failed to build reaper runtime");
+                runtime.block_on(async move {
+                    let (sender, mut receiver) = mpsc::unbounded_channel();
+                    let sigchld = sigchld().expect("Failed to listen for SIGCHLD");
+                    tokio::spawn(handle_signals(sigchld, sender, registry));
+                    ready.send(()).expect("the test waits for the reaper");
+                    while let Some(Reaped {
+                        pid,
+                        exit_status,
+                        owner,
+                    }) = receiver.recv().await
+                    {
+                        let Some((container_id, exec_id)) = owner else {
+                            continue;
+                        };
+                        let Some(container) = find_container(&container_id) else {
+                            continue;
+                        };
+                        tokio::spawn(async move {
+                            container.reap(exec_id.as_deref(), pid, exit_status).await;
+                        });
+                    }
+                });
+            });
+            started.recv().expect("Failed to start reaper");
+            pids
+        })
+        .clone()
+}
+
+#[derive(Parser)]
+struct Args {
+    #[command(flatten)]
+    config: Config,
+}
+
+/// A fake OCI runtime in a temporary directory, and the containers created with it. Their
+/// processes are killed when it is dropped.
+pub struct Fixture {
+    pub dir: TempDir,
+    pub config: Config,
+    pub runtime: Runtime,
+    pub metrics: Metrics,
+    pub containers: Arc<Containers>,
+}
+
+impl Fixture {
+    pub fn new() -> Self {
+        Self::with_args(&[])
+    }
+
+    /// Creates a fixture whose configuration also has the given command line arguments.
+    pub fn with_args(args: &[&str]) -> Self {
+        let dir = TempDir::new().expect("Failed to create temporary directory");
+        let runtime = dir.path().join("runtime");
+        fs::write(&runtime, FAKE_RUNTIME).expect("Failed to write fake runtime");
+        fs::set_permissions(&runtime, fs::Permissions::from_mode(0o755))
+            .expect("Failed to make fake runtime executable");
+        let root = dir.path().join("root");
+        fs::create_dir(&root).expect("Failed to create runtime root");
+        let mut argv = vec!["shim".into(), "--runtime".into(), runtime.into_os_string()];
+        argv.extend(["--runtime-root".into(), root.into_os_string()]);
+        argv.extend(args.iter().map(Into::into));
+        let config = Args::parse_from(argv).config;
+        let metrics = Metrics::default();
+        let containers = Arc::new(DashMap::new());
+        let mut fixtures = FIXTURES.lock().unwrap();
+        fixtures.retain(|containers| containers.strong_count() > 0);
+        fixtures.push(Arc::downgrade(&containers));
+        Self {
+            runtime: Runtime::new(&config, pids(), metrics.clone()),
+            dir,
+            config,
+            metrics,
+            containers,
+        }
+    }
+
+    /// Returns a task service using the fake runtime, which shares the fixture's containers.
+    pub fn service(&self) -> TaskService {
+        let mut service = TaskService::new(
+            &self.config,
+            Arc::new(ExitSignal::default()),
+            OffsetDateTime::now_utc(),
+            self.dir.path().join("state.json"),
+        );
+        service.pids = pids();
+        service.runtime = Runtime::new(&self.config, pids(), service.metrics.clone());
+        service.containers = self.containers.clone();
+        service
+    }
+
+    /// The fake runtime's root directory.
+    pub fn root(&self) -> PathBuf {
+        self.dir.path().join("root")
+    }
+
+    /// Returns a unique container ID starting with `name`, and a bundle for it whose container
+    /// runs the shell script `cmd`.
+    pub fn bundle(&self, name: &str, cmd: &str) -> (String, PathBuf) {
+        let id = format!("{}-{}", name, NEXT_ID.fetch_add(1, Ordering::Relaxed));
+        let bundle = self.dir.path().join(&id);
+        fs::create_dir(&bundle).expect("Failed to create bundle");
+        fs::write(bundle.join("config.json"), "{}").expect("Failed to write spec");
+        fs::write(bundle.join("cmd"), cmd).expect("Failed to write command");
+        (id, bundle)
+    }
+
+    /// Returns a container that runs the shell script `cmd`, with its stdout and stderr going to
+    /// the files `stdout` and `stderr` in its bundle. Its ID is `name` with a unique suffix.
+    pub fn container(&self, name: &str, cmd: &str, options: CreateOptions) -> Arc<Container> {
+        let (id, bundle) = self.bundle(name, cmd);
+        let container = Arc::new(Container::new(
+            &id,
+            &bundle,
+            &bundle.join("stdout"),
+            &bundle.join("stderr"),
+            options,
+            pids(),
+            Publisher::default(),
+        ));
+        self.containers.insert(id, container.clone());
+        container
+    }
+
+    pub async fn create(&self, container: &Container) -> Result<()> {
+        container
+            .create(
+                &self.runtime,
+                self.config.log_options(&self.metrics),
+                self.config.cgroup_options(),
+                &KeepOpen,
+            )
+            .await
+    }
+
+    /// Creates and starts a container.
+    pub async fn run(&self, container: &Container) -> Result<()> {
+        self.create(container).await?;
+        container
+            .start(&self.runtime, self.config.cgroup_options())
+            .await
+    }
+
+    /// Queues an error in the runtime log for the next create or restore to fail with.
+    pub fn fail_create(&self, error: &str) {
+        let path = self.root().join("create-errors");
+        let mut errors = fs::read_to_string(&path).unwrap_or_default();
+        errors.push_str(error);
+        errors.push('\n');
+        fs::write(path, errors).expect("Failed to queue create error");
+    }
+
+    /// Returns how often the runtime was invoked with `command` for the container.
+    pub fn invocations(&self, command: &str, container: &Container) -> usize {
+        let line = format!("{} {}", command, container.id);
+        fs::read_to_string(self.root().join("invocations"))
+            .unwrap_or_default()
+            .lines()
+            .filter(|invocation| *invocation == line)
+            .count()
+    }
+}
+
+impl Drop for Fixture {
+    fn drop(&mut self) {
+        let pids = pids();
+        let Ok(entries) = fs::read_dir(self.root()) else {
+            return;
+        };
+        for entry in entries.flatten() {
+            if entry
+                .path()
+                .extension()
+                .is_none_or(|extension| extension != "pid")
+            {
+                continue;
+            }
+            // Only still registered processes are signalled, so an exited one's reused pid isn't.
+            let pid = fs::read_to_string(entry.path()).unwrap_or_default();
+            if let Ok(pid) = pid.trim().parse::<i32>() {
+                let _ = pids.signal(pid, Signal::SIGKILL);
+            }
+        }
+    }
+}
+
+/// Waits until `condition` holds, for up to [`TIMEOUT`].
+pub async fn wait_until(mut condition: impl FnMut() -> bool) -> bool {
+    let start = Instant::now();
+    while !condition() {
+        if start.elapsed() > TIMEOUT {
+            return false;
+        }
+        sleep(Duration::from_millis(10)).await;
+    }
+    true
+}
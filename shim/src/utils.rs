@@ -1,5 +1,19 @@
-use std::sync::atomic::{AtomicBool, Ordering};
+use std::{
+    fs,
+    io::IoSliceMut,
+    os::fd::{AsRawFd, BorrowedFd, FromRawFd, OwnedFd, RawFd},
+    path::Path,
+    sync::atomic::{AtomicBool, Ordering},
+    time::Duration,
+};
 
+use anyhow::{bail, Context, Result};
+use nix::{
+    cmsg_space,
+    sys::socket::{recvmsg, ControlMessageOwned, MsgFlags},
+};
+use prost_types::Timestamp;
+use time::OffsetDateTime;
 use tokio::sync::Notify;
 
 pub struct ExitSignal {
@@ -32,3 +46,102 @@ impl Default for ExitSignal {
         }
     }
 }
+
+/// Parses a duration such as `500ms`, `30s` or `5m`. A bare number is taken as seconds.
+pub fn parse_duration(value: &str) -> Result<Duration, String> {
+    let value = value.trim();
+    let split = value
+        .find(|c: char| !c.is_ascii_digit())
+        .unwrap_or(value.len());
+    let (amount, unit) = value.split_at(split);
+    let amount: u64 = amount
+        .parse()
+        .map_err(|_| format!("invalid duration: {}", value))?;
+    match unit {
+        "ms" => Ok(Duration::from_millis(amount)),
+        "" | "s" => Ok(Duration::from_secs(amount)),
+        "m" => amount
+            .checked_mul(60)
+            .map(Duration::from_secs)
+            .ok_or_else(|| format!("duration too large: {}", value)),
+        _ => Err(format!("invalid duration unit: {}", unit)),
+    }
+}
+
+/// Formats a duration so that it can be parsed back by [`parse_duration`].
+pub fn format_duration(duration: Duration) -> String {
+    format!("{}ms", duration.as_millis())
+}
+
+/// Parses a size in bytes such as `512K`, `10M` or `1G`, in multiples of 1024. A bare number is
+/// taken as bytes.
+pub fn parse_size(value: &str) -> Result<u64, String> {
+    let value = value.trim();
+    let split = value
+        .find(|c: char| !c.is_ascii_digit())
+        .unwrap_or(value.len());
+    let (amount, unit) = value.split_at(split);
+    let amount: u64 = amount
+        .parse()
+        .map_err(|_| format!("invalid size: {}", value))?;
+    let shift = match unit {
+        "" => 0,
+        "K" => 10,
+        "M" => 20,
+        "G" => 30,
+        _ => return Err(format!("invalid size unit: {}", unit)),
+    };
+    amount
+        .checked_mul(1 << shift)
+        .ok_or_else(|| format!("size too large: {}", value))
+}
+
+/// Writes a file atomically, through a hidden temporary file next to it, so that readers never
+/// see a partial one.
+pub fn write_atomic(path: &Path, contents: &[u8]) -> Result<()> {
+    let name = path.file_name().context("Path has no file name")?;
+    let temp = path.with_file_name(format!(".{}", name.to_string_lossy()));
+    fs::write(&temp, contents).with_context(|| format!("Failed to write {:?}", temp))?;
+    fs::rename(&temp, path).with_context(|| format!("Failed to rename {:?}", temp))?;
+    Ok(())
+}
+
+/// Converts a point in time into its protobuf representation.
+pub fn timestamp(time: OffsetDateTime) -> Timestamp {
+    Timestamp {
+        seconds: time.unix_timestamp(),
+        nanos: time.nanosecond() as i32,
+    }
+}
+
+/// Receives a single file descriptor sent over a unix socket with `SCM_RIGHTS`.
+pub fn recv_fd(socket: BorrowedFd<'_>) -> Result<OwnedFd> {
+    let mut buf = [0u8; 4096];
+    let mut iov = [IoSliceMut::new(&mut buf)];
+    let mut cmsg = cmsg_space!([RawFd; 1]);
+    let msg = recvmsg::<()>(
+        socket.as_raw_fd(),
+        &mut iov,
+        Some(&mut cmsg),
+        MsgFlags::MSG_CMSG_CLOEXEC,
+    )?;
+    for cmsg in msg.cmsgs()? {
+        if let ControlMessageOwned::ScmRights(fds) = cmsg {
+            if let Some(&fd) = fds.first() {
+                return Ok(unsafe { OwnedFd::from_raw_fd(fd) });
+            }
+        }
+    }
+    bail!("No file descriptor received")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_duration_rejects_overflowing_minutes() {
+        assert_eq!(parse_duration("5m"), Ok(Duration::from_secs(300)));
+        assert!(parse_duration(&format!("{}m", u64::MAX)).is_err());
+    }
+}